@@ -0,0 +1,52 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use crate::{room::RoomHandle, server::MatrixServer, BufferOwner, Servers};
+
+pub(super) struct Encryption {
+    servers: Servers,
+}
+
+impl Encryption {
+    pub(super) fn create(servers: Servers) -> Result<BarItem, ()> {
+        let encryption = Encryption { servers };
+        BarItem::new("matrix_encryption", encryption)
+    }
+}
+
+/// The signs showing that the room is encrypted and, if so, whether it
+/// contains unverified devices.
+pub(super) fn encryption_signs(
+    server: &MatrixServer,
+    room: &RoomHandle,
+) -> Vec<String> {
+    let mut signs = Vec::new();
+
+    if room.is_encrypted() {
+        let config = server.config();
+        let config = config.borrow();
+
+        signs.push(config.look().encrypted_room_sign());
+
+        if !room.contains_only_verified_devices() {
+            signs.push(config.look().encryption_warning_sign());
+        }
+    }
+
+    signs
+}
+
+impl BarItemCallback for Encryption {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
+        if let BufferOwner::Room(server, room) =
+            self.servers.buffer_owner(buffer)
+        {
+            encryption_signs(&server, &room).join("")
+        } else {
+            String::new()
+        }
+    }
+}
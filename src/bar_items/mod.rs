@@ -1,13 +1,19 @@
 mod buffer_name;
 mod buffer_plugin;
+mod encryption;
 mod status;
+mod typing;
+mod unread;
 
 use weechat::hooks::BarItem;
 
 use crate::Servers;
 use buffer_name::BufferName;
 use buffer_plugin::BufferPlugin;
+use encryption::Encryption;
 use status::Status;
+use typing::Typing;
+use unread::Unread;
 
 pub struct BarItems {
     #[allow(dead_code)]
@@ -16,6 +22,12 @@ pub struct BarItems {
     buffer_name: BarItem,
     #[allow(dead_code)]
     buffer_plugin: BarItem,
+    #[allow(dead_code)]
+    typing: BarItem,
+    #[allow(dead_code)]
+    unread: BarItem,
+    #[allow(dead_code)]
+    encryption: BarItem,
 }
 
 impl BarItems {
@@ -23,7 +35,10 @@ impl BarItems {
         Ok(Self {
             status: Status::create(servers.clone())?,
             buffer_name: BufferName::create(servers.clone())?,
-            buffer_plugin: BufferPlugin::create(servers)?,
+            buffer_plugin: BufferPlugin::create(servers.clone())?,
+            typing: Typing::create(servers.clone())?,
+            unread: Unread::create(servers.clone())?,
+            encryption: Encryption::create(servers)?,
         })
     }
 }
@@ -4,6 +4,7 @@ use weechat::{
     Weechat,
 };
 
+use super::encryption::encryption_signs;
 use crate::{BufferOwner, Servers};
 
 pub(super) struct Status {
@@ -24,21 +25,7 @@ impl BarItemCallback for Status {
         if let BufferOwner::Room(server, room) =
             self.servers.buffer_owner(buffer)
         {
-            if room.is_encrypted() {
-                signs.push(
-                    server.config().borrow().look().encrypted_room_sign(),
-                );
-
-                if !room.contains_only_verified_devices() {
-                    signs.push(
-                        server
-                            .config()
-                            .borrow()
-                            .look()
-                            .encryption_warning_sign(),
-                    );
-                }
-            }
+            signs.extend(encryption_signs(&server, &room));
 
             if room.is_public() {
                 signs.push(server.config().borrow().look().public_room_sign());
@@ -47,6 +34,10 @@ impl BarItemCallback for Status {
             if room.is_busy() {
                 signs.push(server.config().borrow().look().busy_sign());
             }
+
+            if server.is_reconnecting() {
+                signs.push(server.config().borrow().look().reconnecting_sign());
+            }
         }
 
         signs.join("")
@@ -0,0 +1,34 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use crate::{BufferOwner, Servers};
+
+pub(super) struct Typing {
+    servers: Servers,
+}
+
+impl Typing {
+    pub(super) fn create(servers: Servers) -> Result<BarItem, ()> {
+        let typing = Typing { servers };
+        BarItem::new("matrix_typing", typing)
+    }
+}
+
+impl BarItemCallback for Typing {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer) -> String {
+        if let BufferOwner::Room(_, room) = self.servers.buffer_owner(buffer) {
+            let nicks = room.typing_nicks();
+
+            match nicks.len() {
+                0 => String::new(),
+                1 => format!("{} is typing…", nicks[0]),
+                _ => format!("{} are typing…", nicks.join(", ")),
+            }
+        } else {
+            String::new()
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{BarItem, BarItemCallback},
+    Weechat,
+};
+
+use crate::Servers;
+
+pub(super) struct Unread {
+    servers: Servers,
+}
+
+impl Unread {
+    pub(super) fn create(servers: Servers) -> Result<BarItem, ()> {
+        let unread = Unread { servers };
+        BarItem::new("matrix_unread", unread)
+    }
+}
+
+impl BarItemCallback for Unread {
+    fn callback(&mut self, _: &Weechat, _: &Buffer) -> String {
+        let servers = self.servers.borrow();
+
+        let counts: Vec<_> = servers
+            .values()
+            .flat_map(|server| server.rooms())
+            .map(|room| room.unread())
+            .filter(|unread| unread.messages > 0)
+            .collect();
+
+        if counts.is_empty() {
+            return String::new();
+        }
+
+        let rooms = counts.len();
+        let highlights: usize = counts.iter().map(|c| c.highlights).sum();
+
+        let rooms =
+            format!("{} room{}", rooms, if rooms == 1 { "" } else { "s" });
+
+        if highlights > 0 {
+            format!(
+                "Matrix: {} ({} highlight{})",
+                rooms,
+                highlights,
+                if highlights == 1 { "" } else { "s" }
+            )
+        } else {
+            format!("Matrix: {}", rooms)
+        }
+    }
+}
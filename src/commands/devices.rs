@@ -34,9 +34,10 @@ impl DevicesCommand {
             .description(Self::DESCRIPTION)
             .add_argument("list")
             .add_argument("delete <device-id>")
-            .add_argument("set-name <device-id> <name>")
+            .add_argument("set-name [<device-id>] <name>")
             .arguments_description(
-                "device-id: The unique id of the device that should be deleted.
+                "device-id: The unique id of the device that should be \
+                 deleted or renamed, defaults to our own device when renaming.
      name: The name that the device name should be set to.",
             )
             .add_completion("list %(matrix-users)")
@@ -65,6 +66,19 @@ impl DevicesCommand {
         }
     }
 
+    fn set_name(servers: &Servers, buffer: &Buffer, args: Vec<String>) {
+        let server = servers.find_server(buffer);
+
+        if let Some(s) = server {
+            let set_name = || async move {
+                s.set_device_name(args).await;
+            };
+            Weechat::spawn(set_name()).detach();
+        } else {
+            Weechat::print("Must be executed on Matrix buffer")
+        }
+    }
+
     fn list(servers: &Servers, buffer: &Buffer, user_id: Option<OwnedUserId>) {
         let server = servers.find_server(buffer);
 
@@ -103,6 +117,14 @@ impl DevicesCommand {
                     .collect();
                 Self::delete(servers, buffer, devices);
             }
+            ("set-name", args) => {
+                let args = args
+                    .and_then(|a| a.values_of("args"))
+                    .expect("Args didn't contain a device name")
+                    .map(|a| a.to_owned())
+                    .collect();
+                Self::set_name(servers, buffer, args);
+            }
             _ => Weechat::print(&format!(
                 "{}Subcommand isn't implemented",
                 Weechat::prefix(Prefix::Error)
@@ -130,9 +152,11 @@ impl DevicesCommand {
                         .required(true),
                 ),
             SubCommand::with_name("set-name")
-                .about("Set the human readable name of the given device")
-                .arg(Arg::with_name("device-id").required(true))
-                .arg(Arg::with_name("name").required(true)),
+                .about(
+                    "Set the human readable name of the given device, or of \
+                     our own device if no device id is given",
+                )
+                .arg(Arg::with_name("args").multiple(true).required(true)),
         ]
     }
 }
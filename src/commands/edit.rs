@@ -0,0 +1,97 @@
+use matrix_sdk::ruma::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct EditCommand {
+    servers: Servers,
+}
+
+impl EditCommand {
+    pub const DESCRIPTION: &'static str =
+        "Edit one of your messages in the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("edit")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id|offset> <message>")
+            .arguments_description(
+                "event-id: The ID of the event that should be edited
+  offset: The position of the message to edit, counting from the newest \
+                 message, starting at 1
+ message: The new content of the message",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+
+    fn print_error(message: &str) {
+        Weechat::print(&format!(
+            "{}{}: {}",
+            Weechat::prefix(Prefix::Error),
+            PLUGIN_NAME,
+            message
+        ));
+    }
+}
+
+impl CommandCallback for EditCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The edit command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let target = if let Some(t) = arguments.next() {
+            t
+        } else {
+            Self::print_error("Missing the message to edit");
+            return;
+        };
+
+        let event_id = if target.starts_with('$') {
+            EventId::parse(&target).ok()
+        } else {
+            target
+                .parse::<usize>()
+                .ok()
+                .and_then(|offset| room.event_id_at_offset(offset))
+        };
+
+        let event_id = if let Some(e) = event_id {
+            e
+        } else {
+            Self::print_error(&format!("No message found for {}", target));
+            return;
+        };
+
+        let message = arguments.collect::<Vec<String>>().join(" ");
+
+        if message.is_empty() {
+            Self::print_error("Missing the new content of the message");
+            return;
+        }
+        room.spawn_task(|room| async move {
+            room.edit_message(&event_id, message).await
+        });
+    }
+}
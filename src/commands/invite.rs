@@ -0,0 +1,60 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct InviteCommand {
+    servers: Servers,
+}
+
+impl InviteCommand {
+    pub const DESCRIPTION: &'static str = "Invite a user to the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("invite")
+            .description(Self::DESCRIPTION)
+            .add_argument("<user-id>")
+            .arguments_description("user-id: The Matrix user ID of the user")
+            .add_completion("%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for InviteCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The invite command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let user = if let Some(u) = arguments.next() {
+            u
+        } else {
+            Weechat::print(&format!(
+                "{}{}: Missing the user ID of the user to invite",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+        room.spawn_task(|room| async move { room.invite(&user).await });
+    }
+}
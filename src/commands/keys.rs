@@ -19,8 +19,9 @@ pub struct KeysCommand {
 }
 
 impl KeysCommand {
-    pub const DESCRIPTION: &'static str = "Import or export E2EE keys.";
-    pub const COMPLETION: &'static str = "import|export %(filename)";
+    pub const DESCRIPTION: &'static str =
+        "Import or export E2EE keys, or compact the store.";
+    pub const COMPLETION: &'static str = "import|export|compact %(filename)";
     pub const SETTINGS: &'static [ArgParseSettings] = &[
         ArgParseSettings::DisableHelpFlags,
         ArgParseSettings::DisableVersion,
@@ -33,11 +34,12 @@ impl KeysCommand {
             .description(Self::DESCRIPTION)
             .add_argument("import <file> <passphrase>")
             .add_argument("export <file> <passphrase>")
+            .add_argument("compact")
             .arguments_description(
                 "file: Path to a file that is or will contain the E2EE keys export",
             )
             .add_completion(Self::COMPLETION)
-            .add_completion("help import|export");
+            .add_completion("help import|export|compact");
 
         Command::new(
             settings,
@@ -81,6 +83,13 @@ impl KeysCommand {
         Weechat::spawn(export()).detach();
     }
 
+    fn compact(server: MatrixServer) {
+        let compact = || async move {
+            server.compact_store().await;
+        };
+        Weechat::spawn(compact()).detach();
+    }
+
     pub fn run(buffer: &Buffer, servers: &Servers, args: &ArgMatches) {
         if let Some(server) = servers.find_server(buffer) {
             match args.subcommand() {
@@ -96,6 +105,7 @@ impl KeysCommand {
                     );
                     Self::export(server, file, passphrase);
                 }
+                ("compact", _) => Self::compact(server),
                 _ => unreachable!(),
             }
         } else {
@@ -114,6 +124,8 @@ impl KeysCommand {
                 .about("Export your E2EE keys to the given file.")
                 .arg(Arg::with_name("file").required(true))
                 .arg(Arg::with_name("passphrase").required(true)),
+            SubCommand::with_name("compact")
+                .about("Compact the store and report its size."),
         ]
     }
 }
@@ -0,0 +1,72 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct KickCommand {
+    servers: Servers,
+}
+
+impl KickCommand {
+    pub const DESCRIPTION: &'static str =
+        "Kick a member from the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("kick")
+            .description(Self::DESCRIPTION)
+            .add_argument("<nick|user-id> [<reason>]")
+            .arguments_description(
+                "   nick: The nick of the member in the current room
+user-id: The Matrix user ID of the member
+ reason: The reason for the kick, shown to the kicked member",
+            )
+            .add_completion("%(nicks)|%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for KickCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The kick command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let user = if let Some(u) = arguments.next() {
+            u
+        } else {
+            Weechat::print(&format!(
+                "{}{}: Missing the nick or user ID of the member",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let reason = arguments.collect::<Vec<String>>().join(" ");
+        let reason = if reason.is_empty() {
+            None
+        } else {
+            Some(reason)
+        };
+        room.spawn_task(|room| async move { room.kick(&user, reason).await });
+    }
+}
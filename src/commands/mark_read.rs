@@ -0,0 +1,43 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct MarkReadCommand {
+    servers: Servers,
+}
+
+impl MarkReadCommand {
+    pub const DESCRIPTION: &'static str =
+        "Mark the current room as read up to the latest message.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings =
+            CommandSettings::new("markread").description(Self::DESCRIPTION);
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for MarkReadCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, _: Args) {
+        if let Some(room) = self.servers.find_room(buffer) {
+            room.mark_read();
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The markread command must be executed on a Matrix \
+                 room buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+        }
+    }
+}
@@ -2,6 +2,12 @@ use clap::{
     App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches,
     SubCommand,
 };
+use std::process::Stdio;
+
+use matrix_sdk::{
+    encryption::verification::VerificationRequest,
+    ruma::{OwnedUserId, RoomId, UserId},
+};
 use url::Url;
 
 use weechat::{
@@ -12,7 +18,7 @@ use weechat::{
 
 use super::parse_and_run;
 use crate::{
-    commands::{DevicesCommand, KeysCommand},
+    commands::{DevicesCommand, KeysCommand, RoomCommand},
     config::ConfigHandle,
     MatrixServer, Servers, PLUGIN_NAME,
 };
@@ -29,13 +35,38 @@ impl MatrixCommand {
     ) -> Result<Command, ()> {
         let matrix_settings = CommandSettings::new("matrix")
             .description("Matrix chat protocol command.")
-            .add_argument("server add <server-name> <hostname>[:<port>]")
+            .add_argument("server add [--force] <server-name> <hostname>[:<port>]")
             .add_argument("server delete|list|listfull <server-name>")
-            .add_argument("connect <server-name>")
+            .add_argument("server filter|capabilities|ping|config [<server-name>]")
+            .add_argument("connect [--sso|--sso-token <token>] <server-name>")
+            .add_argument("connect --all")
             .add_argument("devices delete|list|set-name")
             .add_argument("keys import|export <file> <passphrase>")
-            .add_argument("disconnect <server-name>")
-            .add_argument("reconnect <server-name>")
+            .add_argument("keys compact")
+            .add_argument("room queue [clear]")
+            .add_argument("room color <nick-or-user-id> [<color>]")
+            .add_argument("room receipts [on|off]")
+            .add_argument("room rotate-keys|encryption-info|who-can-see")
+            .add_argument("room accept-knock|reject-knock <user-id> [<reason>]")
+            .add_argument("room raw <offset>")
+            .add_argument("room history <count>")
+            .add_argument("room timeline [<count>]")
+            .add_argument("room via")
+            .add_argument(
+                "room joinrule public|invite|knock|restricted|knock-restricted \
+                 [<room-id>...]",
+            )
+            .add_argument("open <emxc-url>")
+            .add_argument("password <secured-data-name> [--logout-devices]")
+            .add_argument("react-toggle <offset> <emoji>")
+            .add_argument("goto-parent [<offset>]")
+            .add_argument("verifications [accept|cancel <number>]")
+            .add_argument("dm list|set|unset [<user-id> <room-id>]")
+            .add_argument("user-search <term>")
+            .add_argument("highlights")
+            .add_argument("safe-mode [on|off]")
+            .add_argument("disconnect <server-name>|--all")
+            .add_argument("reconnect [<server-name>...]")
             .add_argument("help <matrix-command> [<matrix-subcommand>]")
             .arguments_description(&format!(
                 "      server: List, add, or remove Matrix servers.
@@ -44,21 +75,57 @@ impl MatrixCommand {
    reconnect: Reconnect to server(s).
      devices: {}
         keys: {}
+        room: {}
+        open: Open encrypted media using the configured emxc plumber.
+    password: Change the password of the account, the new password is
+              read from the given secured data, e.g. after a
+              /secure set matrix_password <new-password>.
+react-toggle: Add or remove a reaction to the message at the given offset,
+              counting from the newest message.
+ goto-parent: Scroll to the message that the message at the given offset
+              is replying to, the offset defaults to the newest message.
+verifications: List the pending verification requests of all servers, or
+              accept or cancel the request with the given number.
+          dm: List the direct message rooms, or mark or unmark a room as
+              a direct message with the given user.
+ user-search: Search the user directory of the homeserver.
+  highlights: Show what causes messages to be highlighted.
+   safe-mode: Show or toggle the refusal to send messages to rooms that
+              aren't encrypted or contain unverified devices.
         help: Show detailed command help.\n
 Use /matrix [command] help to find out more.\n",
                 DevicesCommand::DESCRIPTION,
                 KeysCommand::DESCRIPTION,
+                RoomCommand::DESCRIPTION,
             ))
-            .add_completion("server add|delete|list|listfull")
+            .add_completion(
+                "server add|delete|list|listfull|filter|capabilities|ping|config %(matrix_servers)",
+            )
             .add_completion("devices list|delete|set-name %(matrix-users)")
             .add_completion(&format!("keys {}", KeysCommand::COMPLETION))
-            .add_completion("connect %(matrix_servers)")
-            .add_completion("disconnect %(matrix_servers)")
+            .add_completion("open")
+            .add_completion("password %(secured_data)")
+            .add_completion("react-toggle")
+            .add_completion("goto-parent")
+            .add_completion("verifications accept|cancel")
+            .add_completion("dm list|set|unset %(matrix-users)")
+            .add_completion("user-search")
+            .add_completion("highlights")
+            .add_completion("safe-mode on|off")
+            .add_completion("connect %(matrix_servers)|--all")
+            .add_completion("disconnect %(matrix_servers)|--all")
             .add_completion("reconnect %(matrix_servers)")
             .add_completion(
-                "help server|connect|disconnect|reconnect|keys|devices",
+                "help server|connect|disconnect|reconnect|keys|devices|room|open|password|react-toggle|goto-parent|verifications|dm|user-search|highlights|safe-mode",
             );
 
+        let matrix_settings = RoomCommand::COMPLETIONS.iter().fold(
+            matrix_settings,
+            |settings, completion| {
+                settings.add_completion(&format!("room {}", completion))
+            },
+        );
+
         Command::new(
             matrix_settings,
             MatrixCommand {
@@ -78,11 +145,81 @@ Use /matrix [command] help to find out more.\n",
         let homeserver = Url::parse(homeserver)
             .expect("Can't parse Homeserver even if validation passed");
 
+        if let Some(connected) =
+            self.servers.get(server_name).map(|s| s.connected())
+        {
+            if !args.is_present("force") {
+                Weechat::print(&format!(
+                    "{}{}: Server {}{}{} already exists, use --force to \
+                     replace it.",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME,
+                    Weechat::color("chat_server"),
+                    server_name,
+                    Weechat::color("reset")
+                ));
+                return;
+            } else if connected {
+                Weechat::print(&format!(
+                    "{}{}: Server {}{}{} is still connected, disconnect \
+                     it before replacing it.",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME,
+                    Weechat::color("chat_server"),
+                    server_name,
+                    Weechat::color("reset")
+                ));
+                return;
+            }
+
+            let server = self
+                .servers
+                .remove(server_name)
+                .expect("Server disappeared while replacing it");
+
+            // Dropping the last handle to the server frees its config
+            // options, so new ones can be created under the same name. If
+            // something else still holds on to the server, keep it around
+            // instead of ending up with options that have no server.
+            if !server.is_last_handle() {
+                self.servers.insert(server);
+
+                Weechat::print(&format!(
+                    "{}{}: The server {}{}{} is still in use, try again \
+                     later.",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME,
+                    Weechat::color("chat_server"),
+                    server_name,
+                    Weechat::color("reset")
+                ));
+                return;
+            }
+
+            drop(server);
+        }
+
         let mut config_borrow = self.config.borrow_mut();
         let mut section = config_borrow
             .search_section_mut("server")
             .expect("Can't get server section");
 
+        if section
+            .search_option(&format!("{}.homeserver", server_name))
+            .is_some()
+        {
+            Weechat::print(&format!(
+                "{}{}: The options of the server {}{}{} are still in use, \
+                 try again later.",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+                Weechat::color("chat_server"),
+                server_name,
+                Weechat::color("reset")
+            ));
+            return;
+        }
+
         let server = MatrixServer::new(
             server_name,
             &self.config,
@@ -157,16 +294,68 @@ Use /matrix [command] help to find out more.\n",
 
         Weechat::print("\nAll Matrix servers:");
 
-        // TODO print out some stats if the server is connected.
         for server in self.servers.borrow().values() {
             Weechat::print(&format!("    {}", server.get_info_str(details)));
         }
     }
 
-    fn server_command(&self, args: &ArgMatches) {
+    /// Get the server either from the given server name or from the buffer
+    /// the command was executed on.
+    fn server_from_args(
+        &self,
+        buffer: &Buffer,
+        args: &ArgMatches,
+    ) -> Option<MatrixServer> {
+        if let Some(server_name) = args.value_of("name") {
+            let server = self.servers.get(server_name);
+
+            if server.is_none() {
+                self.server_not_found(server_name);
+            }
+
+            server
+        } else if let Some(s) = self.servers.find_server(buffer) {
+            Some(s)
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            None
+        }
+    }
+
+    fn server_filter(&self, buffer: &Buffer, args: &ArgMatches) {
+        if let Some(server) = self.server_from_args(buffer, args) {
+            server.print_sync_filter();
+        }
+    }
+
+    fn server_config(&self, buffer: &Buffer, args: &ArgMatches) {
+        if let Some(server) = self.server_from_args(buffer, args) {
+            server.print_config();
+        }
+    }
+
+    fn server_capabilities(&self, buffer: &Buffer, args: &ArgMatches) {
+        if let Some(server) = self.server_from_args(buffer, args) {
+            Weechat::spawn(async move { server.capabilities().await }).detach();
+        }
+    }
+
+    fn server_ping(&self, buffer: &Buffer, args: &ArgMatches) {
+        if let Some(server) = self.server_from_args(buffer, args) {
+            Weechat::spawn(async move { server.ping().await }).detach();
+        }
+    }
+
+    fn server_command(&self, buffer: &Buffer, args: &ArgMatches) {
         match args.subcommand() {
             ("add", Some(subargs)) => self.add_server(subargs),
             ("delete", Some(subargs)) => self.delete_server(subargs),
+            ("filter", Some(subargs)) => self.server_filter(buffer, subargs),
+            ("capabilities", Some(subargs)) => {
+                self.server_capabilities(buffer, subargs)
+            }
+            ("ping", Some(subargs)) => self.server_ping(buffer, subargs),
+            ("config", Some(subargs)) => self.server_config(buffer, subargs),
             ("list", _) => self.list_servers(false),
             ("listfull", _) => self.list_servers(true),
             _ => self.list_servers(false),
@@ -185,15 +374,46 @@ Use /matrix [command] help to find out more.\n",
     }
 
     fn connect_command(&self, args: &ArgMatches) {
+        if args.is_present("all") {
+            let servers: Vec<MatrixServer> =
+                self.servers.borrow().values().cloned().collect();
+
+            // Unlike the autoconnect on load, every server gets connected,
+            // servers that are already connected are left alone.
+            for server in servers.iter().filter(|s| !s.connected()) {
+                if let Err(e) = server.connect() {
+                    Weechat::print(&format!("{:?}", e));
+                }
+            }
+
+            return;
+        }
+
         let server_names = args
             .values_of("name")
             .expect("Server names not set but were required");
 
+        let sso = args.is_present("sso");
+        let login_token = args.value_of("sso-token");
+
         for server_name in server_names {
             if let Some(s) = self.servers.get(server_name) {
-                match s.connect() {
-                    Ok(_) => (),
-                    Err(e) => Weechat::print(&format!("{:?}", e)),
+                if sso {
+                    Weechat::spawn(
+                        async move { s.print_sso_login_url().await },
+                    )
+                    .detach();
+                    continue;
+                }
+
+                let ret = if let Some(token) = login_token {
+                    s.connect_with_login_token(token.to_owned())
+                } else {
+                    s.connect()
+                };
+
+                if let Err(e) = ret {
+                    Weechat::print(&format!("{:?}", e));
                 }
             } else {
                 self.server_not_found(server_name)
@@ -202,6 +422,17 @@ Use /matrix [command] help to find out more.\n",
     }
 
     fn disconnect_command(&self, args: &ArgMatches) {
+        if args.is_present("all") {
+            let servers: Vec<MatrixServer> =
+                self.servers.borrow().values().cloned().collect();
+
+            for server in servers.iter().filter(|s| s.connected()) {
+                server.disconnect();
+            }
+
+            return;
+        }
+
         let server_name = args
             .value_of("name")
             .expect("Server name not set but was required");
@@ -213,17 +444,504 @@ Use /matrix [command] help to find out more.\n",
         }
     }
 
+    fn reconnect_command(&self, args: &ArgMatches) {
+        let servers: Vec<MatrixServer> =
+            if let Some(names) = args.values_of("name") {
+                names
+                    .filter_map(|name| {
+                        let server = self.servers.get(name);
+
+                        if server.is_none() {
+                            self.server_not_found(name);
+                        }
+
+                        server
+                    })
+                    .collect()
+            } else {
+                self.servers.borrow().values().cloned().collect()
+            };
+
+        for server in servers {
+            if server.connected() {
+                server.disconnect();
+            }
+
+            if let Err(e) = server.connect() {
+                Weechat::print(&format!("{:?}", e));
+            }
+        }
+    }
+
+    fn open_command(&self, args: &ArgMatches) {
+        let url = args.value_of("url").expect("URL not set but was required");
+
+        if !url.starts_with("emxc://") {
+            Weechat::print(&format!(
+                "{}{}: Only encrypted media (emxc URLs) needs to be opened \
+                 using the plumber, other URLs can be opened directly.",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+            ));
+            return;
+        }
+
+        let plumber = self.config.borrow().look().emxc_plumber().to_string();
+
+        if plumber.is_empty() {
+            Weechat::print(&format!(
+                "{}{}: No emxc plumber is configured, set the \
+                 matrix-rust.look.emxc_plumber option.",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+            ));
+            return;
+        }
+
+        let child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&plumber)
+            .env("url", url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        match child {
+            // Reap the plumber once it exits so it doesn't stay around as a
+            // zombie process.
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => Weechat::print(&format!(
+                "{}{}: Error running the emxc plumber: {}",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME,
+                e
+            )),
+        }
+    }
+
+    fn password_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let name = args
+            .value_of("secured-data")
+            .expect("Secured data name not set but was required");
+
+        // The password is read from the secured data, passing it as an
+        // argument would leave it in the input history and the logs.
+        let new_password =
+            Weechat::eval_string_expression(&format!("${{sec.data.{}}}", name))
+                .unwrap_or_default();
+
+        if new_password.is_empty() {
+            server.print_error(&format!(
+                "The secured data {} doesn't exist, set the new password \
+                 with /secure set {} <new-password>",
+                name, name
+            ));
+            return;
+        }
+
+        let logout_devices = args.is_present("logout-devices");
+
+        Weechat::spawn(async move {
+            server.change_password(new_password, logout_devices).await
+        })
+        .detach();
+    }
+
+    fn react_toggle_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let offset = args
+            .value_of("offset")
+            .expect("Offset not set but was required")
+            .parse::<usize>()
+            .expect("Can't parse the offset even if validation passed");
+        let emoji = args
+            .value_of("emoji")
+            .expect("Emoji not set but was required")
+            .to_owned();
+        room.spawn_task(|room| async move {
+            room.toggle_reaction(offset, &emoji).await
+        });
+    }
+
+    fn goto_parent_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        let offset = args
+            .value_of("offset")
+            .map(|o| {
+                o.parse::<usize>()
+                    .expect("Can't parse the offset even if validation passed")
+            })
+            .unwrap_or(1);
+        room.spawn_task(|room| async move { room.goto_parent(offset).await });
+    }
+
+    fn verifications_command(&self, args: &ArgMatches) {
+        let action = args.value_of("action").map(|a| a == "accept");
+        let number = args.value_of("number").map(|n| {
+            n.parse::<usize>()
+                .expect("Can't parse the number even if validation passed")
+        });
+
+        let mut servers: Vec<MatrixServer> =
+            self.servers.borrow().values().cloned().collect();
+        servers.sort_by(|a, b| a.name().cmp(b.name()));
+
+        Weechat::spawn(async move {
+            let mut pending = Vec::new();
+
+            for server in servers {
+                for (user_id, request) in server.pending_verifications().await {
+                    pending.push((server.clone(), user_id, request));
+                }
+            }
+
+            if let (Some(accept), Some(number)) = (action, number) {
+                MatrixCommand::answer_verification(pending, number, accept)
+                    .await
+            } else {
+                MatrixCommand::list_verifications(&pending)
+            }
+        })
+        .detach();
+    }
+
+    fn highlights_command(&self) {
+        let eval = |option: &str| {
+            let value =
+                Weechat::eval_string_expression(&format!("${{{}}}", option))
+                    .unwrap_or_default();
+
+            if value.is_empty() {
+                "none".to_owned()
+            } else {
+                value
+            }
+        };
+
+        let mut user_highlights: Vec<(String, &str)> = self
+            .config
+            .borrow()
+            .look()
+            .user_highlights()
+            .into_iter()
+            .map(|(user_id, h)| (user_id.to_string(), h.as_str()))
+            .collect();
+        user_highlights.sort();
+
+        let highlight_names = self.config.borrow().look().highlight_names();
+
+        let mut lines = vec![
+            format!(
+                "    Highlight names: {}",
+                if highlight_names.is_empty() {
+                    "none"
+                } else {
+                    highlight_names.as_str()
+                }
+            ),
+            format!("    Highlight words: {}", eval("weechat.look.highlight")),
+            format!(
+                "    Highlight regex: {}",
+                eval("weechat.look.highlight_regex")
+            ),
+        ];
+
+        for server in self.servers.borrow().values() {
+            let keywords = server.push_rule_keywords();
+
+            if !keywords.is_empty() {
+                lines.push(format!(
+                    "    Push rule keywords ({}): {}",
+                    server.name(),
+                    keywords.join(", ")
+                ));
+            }
+        }
+
+        if user_highlights.is_empty() {
+            lines.push("    User highlights: none".to_owned());
+        } else {
+            lines.push("    User highlights:".to_owned());
+            lines.extend(
+                user_highlights
+                    .iter()
+                    .map(|(user_id, h)| format!("        {}: {}", user_id, h)),
+            );
+        }
+
+        Weechat::print(&format!(
+            "{}{}: Messages get highlighted because of:\n{}",
+            Weechat::prefix(Prefix::Network),
+            PLUGIN_NAME,
+            lines.join("\n")
+        ));
+    }
+
+    fn safe_mode_command(&self, args: &ArgMatches) {
+        if let Some(state) = args.value_of("state") {
+            let mut config = self.config.borrow_mut();
+            let section = config
+                .search_section_mut("network")
+                .expect("Can't get network section");
+            let option = section
+                .search_option("safe_mode")
+                .expect("Safe mode option wasn't created");
+
+            option.set(state, true);
+        }
+
+        let enabled = self.config.borrow().network().safe_mode();
+
+        Weechat::print(&format!(
+            "{}{}: Safe mode is {}",
+            Weechat::prefix(Prefix::Network),
+            PLUGIN_NAME,
+            if enabled {
+                "on, messages are only sent to encrypted rooms without \
+                 unverified devices"
+            } else {
+                "off"
+            }
+        ));
+    }
+
+    fn user_search_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let term = args
+            .values_of("term")
+            .expect("Search term not set even if it's required")
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        Weechat::spawn(async move { server.search_users(&term).await })
+            .detach();
+    }
+
+    fn dm_command(&self, buffer: &Buffer, args: &ArgMatches) {
+        let server = if let Some(s) = self.servers.find_server(buffer) {
+            s
+        } else {
+            Weechat::print("Must be executed on Matrix buffer");
+            return;
+        };
+
+        let (direct, args) = match args.subcommand() {
+            ("set", Some(args)) => (true, args),
+            ("unset", Some(args)) => (false, args),
+            _ => {
+                Weechat::spawn(
+                    async move { server.print_direct_rooms().await },
+                )
+                .detach();
+                return;
+            }
+        };
+
+        let user_id = args
+            .value_of("user-id")
+            .and_then(|u| UserId::parse(u).ok())
+            .expect("User ID not set or invalid even if validation passed");
+        let room_id = args
+            .value_of("room-id")
+            .and_then(|r| RoomId::parse(r).ok())
+            .expect("Room ID not set or invalid even if validation passed");
+
+        Weechat::spawn(async move {
+            server.set_direct_room(user_id, room_id, direct).await
+        })
+        .detach();
+    }
+
+    fn verification_state(request: &VerificationRequest) -> &'static str {
+        if request.is_ready() {
+            "ready"
+        } else if request.we_started() {
+            "waiting for the other side to accept"
+        } else {
+            "waiting for us to accept"
+        }
+    }
+
+    fn list_verifications(
+        pending: &[(MatrixServer, OwnedUserId, VerificationRequest)],
+    ) {
+        if pending.is_empty() {
+            Weechat::print(&format!(
+                "{}{}: There are no pending verification requests",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME,
+            ));
+            return;
+        }
+
+        let lines: Vec<String> = pending
+            .iter()
+            .enumerate()
+            .map(|(i, (server, user_id, request))| {
+                format!(
+                    "    {}. {}{}{} {}: {}",
+                    i + 1,
+                    Weechat::color("chat_server"),
+                    server.name(),
+                    Weechat::color("reset"),
+                    user_id,
+                    MatrixCommand::verification_state(request),
+                )
+            })
+            .collect();
+
+        Weechat::print(&format!(
+            "{}{}: Pending verification requests:\n{}",
+            Weechat::prefix(Prefix::Network),
+            PLUGIN_NAME,
+            lines.join("\n"),
+        ));
+    }
+
+    async fn answer_verification(
+        pending: Vec<(MatrixServer, OwnedUserId, VerificationRequest)>,
+        number: usize,
+        accept: bool,
+    ) {
+        let (server, user_id, request) =
+            if let Some(p) = pending.into_iter().nth(number - 1) {
+                p
+            } else {
+                Weechat::print(&format!(
+                    "{}{}: No pending verification request with the number {}",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME,
+                    number,
+                ));
+                return;
+            };
+
+        let connection = if let Some(c) = server.connection() {
+            c
+        } else {
+            server.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        // The other side might have cancelled the request, or another one of
+        // our devices answered it, since the list was fetched.
+        if request.is_done() || request.is_cancelled() {
+            server.print_error(&format!(
+                "The verification request of {} has already been {}",
+                user_id,
+                if request.is_done() {
+                    "finished"
+                } else {
+                    "cancelled"
+                }
+            ));
+            return;
+        }
+
+        let (result, action, done) = if accept {
+            let result = connection.accept_verification(request).await;
+            (result, "accept", "Accepted")
+        } else {
+            let result = connection.cancel_verification(request).await;
+            (result, "cancel", "Cancelled")
+        };
+
+        match result {
+            Ok(()) => server.print_network(&format!(
+                "{} the verification request of {}",
+                done, user_id
+            )),
+            Err(e) => server.print_error(&format!(
+                "Failed to {} the verification request of {}: {:?}",
+                action, user_id, e
+            )),
+        }
+    }
+
+    pub(super) fn parse_offset(value: String) -> Result<(), String> {
+        match value.parse::<usize>() {
+            Ok(o) if o > 0 => Ok(()),
+            _ => Err("The offset needs to be a positive number".to_owned()),
+        }
+    }
+
+    fn dm_args() -> [Arg<'static, 'static>; 2] {
+        [
+            Arg::with_name("user-id").required(true).validator(|u| {
+                UserId::parse(u)
+                    .map(|_| ())
+                    .map_err(|_| "The given user isn't a valid user ID".into())
+            }),
+            Arg::with_name("room-id").required(true).validator(|r| {
+                RoomId::parse(r)
+                    .map(|_| ())
+                    .map_err(|_| "The given room isn't a valid room ID".into())
+            }),
+        ]
+    }
+
     fn run(&self, buffer: &Buffer, args: &ArgMatches) {
         match args.subcommand() {
             ("connect", Some(subargs)) => self.connect_command(subargs),
             ("disconnect", Some(subargs)) => self.disconnect_command(subargs),
-            ("server", Some(subargs)) => self.server_command(subargs),
+            ("reconnect", Some(subargs)) => self.reconnect_command(subargs),
+            ("server", Some(subargs)) => self.server_command(buffer, subargs),
+            ("open", Some(subargs)) => self.open_command(subargs),
+            ("react-toggle", Some(subargs)) => {
+                self.react_toggle_command(buffer, subargs)
+            }
+            ("goto-parent", Some(subargs)) => {
+                self.goto_parent_command(buffer, subargs)
+            }
+            ("verifications", Some(subargs)) => {
+                self.verifications_command(subargs)
+            }
+            ("dm", Some(subargs)) => self.dm_command(buffer, subargs),
+            ("highlights", _) => self.highlights_command(),
+            ("safe-mode", Some(subargs)) => self.safe_mode_command(subargs),
+            ("user-search", Some(subargs)) => {
+                self.user_search_command(buffer, subargs)
+            }
+            ("password", Some(subargs)) => {
+                self.password_command(buffer, subargs)
+            }
             ("devices", Some(subargs)) => {
                 DevicesCommand::run(buffer, &self.servers, subargs)
             }
             ("keys", Some(subargs)) => {
                 KeysCommand::run(buffer, &self.servers, subargs)
             }
+            ("room", Some(subargs)) => {
+                RoomCommand::run(buffer, &self.servers, subargs)
+            }
             _ => unreachable!(),
         }
     }
@@ -241,6 +959,9 @@ impl CommandCallback for MatrixCommand {
             .subcommand(
                 SubCommand::with_name("add")
                     .about("Add a new Matrix server.")
+                    .arg(Arg::with_name("force").long("force").help(
+                        "Replace an existing server with the same name.",
+                    ))
                     .arg(
                         Arg::with_name("name")
                             .value_name("server-name")
@@ -268,6 +989,42 @@ impl CommandCallback for MatrixCommand {
             .subcommand(
                 SubCommand::with_name("listfull")
                     .about("List detailed information about the configured Matrix servers."),
+            )
+            .subcommand(
+                SubCommand::with_name("filter")
+                    .about("Show the filter that is used to sync with the server.")
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("server-name")
+                            .required(false),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("capabilities")
+                    .about("Show the spec versions and capabilities of the server.")
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("server-name")
+                            .required(false),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("ping")
+                    .about("Measure the round-trip time to the homeserver.")
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("server-name")
+                            .required(false),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("config")
+                    .about("Show the evaluated values of all the options of the server.")
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("server-name")
+                            .required(false),
+                    ),
             );
 
         let argparse = Argparse::new("matrix")
@@ -291,14 +1048,154 @@ impl CommandCallback for MatrixCommand {
                     .settings(KeysCommand::SETTINGS)
                     .subcommands(KeysCommand::subcommands()),
             )
+            .subcommand(
+                SubCommand::with_name("room")
+                    .about(RoomCommand::DESCRIPTION)
+                    .settings(RoomCommand::SETTINGS)
+                    .subcommands(RoomCommand::subcommands()),
+            )
+            .subcommand(
+                SubCommand::with_name("open")
+                    .about(
+                        "Open encrypted media using the configured emxc plumber.",
+                    )
+                    .arg(
+                        Arg::with_name("url")
+                            .value_name("emxc-url")
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("password")
+                    .about(
+                        "Change the password of the account, the new password \
+                         is read from the secured data with the given name.",
+                    )
+                    .arg(
+                        Arg::with_name("secured-data")
+                            .value_name("secured-data-name")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("logout-devices")
+                            .long("logout-devices")
+                            .help("Log out all the other devices."),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("react-toggle")
+                    .about("Add or remove a reaction to a message.")
+                    .arg(
+                        Arg::with_name("offset")
+                            .required(true)
+                            .validator(MatrixCommand::parse_offset),
+                    )
+                    .arg(Arg::with_name("emoji").required(true)),
+            )
+            .subcommand(
+                SubCommand::with_name("goto-parent")
+                    .about("Scroll to the message a reply is replying to.")
+                    .arg(
+                        Arg::with_name("offset")
+                            .required(false)
+                            .validator(MatrixCommand::parse_offset),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("verifications")
+                    .about("List, accept or cancel verification requests.")
+                    .arg(
+                        Arg::with_name("action")
+                            .possible_values(&["accept", "cancel"])
+                            .requires("number"),
+                    )
+                    .arg(
+                        Arg::with_name("number")
+                            .validator(MatrixCommand::parse_offset),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("highlights")
+                    .about("Show what causes messages to be highlighted."),
+            )
+            .subcommand(
+                SubCommand::with_name("safe-mode")
+                    .about("Show or toggle the refusal to send messages to rooms that aren't encrypted or contain unverified devices.")
+                    .arg(
+                        Arg::with_name("state")
+                            .possible_values(&["on", "off"])
+                            .required(false),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("user-search")
+                    .about("Search the user directory of the homeserver.")
+                    .arg(
+                        Arg::with_name("term")
+                            .required(true)
+                            .multiple(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("dm")
+                    .about("List or edit the direct message rooms.")
+                    .subcommand(
+                        SubCommand::with_name("list")
+                            .about("List the direct message rooms."),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("set")
+                            .about(
+                                "Mark a room as a direct message with the \
+                                 given user.",
+                            )
+                            .args(&MatrixCommand::dm_args()),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("unset")
+                            .about(
+                                "Unmark a room as a direct message with the \
+                                 given user.",
+                            )
+                            .args(&MatrixCommand::dm_args()),
+                    ),
+            )
             .subcommand(
                 SubCommand::with_name("connect")
                     .about("Connect to Matrix servers.")
                     .arg(
                         Arg::with_name("name")
                             .value_name("server-name")
-                            .required(true)
+                            .required_unless("all")
                             .multiple(true),
+                    )
+                    .arg(
+                        Arg::with_name("all")
+                            .long("all")
+                            .conflicts_with_all(&["name", "sso", "sso-token"])
+                            .help(
+                                "Connect to all the configured servers, \
+                                 regardless of their autoconnect setting.",
+                            ),
+                    )
+                    .arg(
+                        Arg::with_name("sso")
+                            .long("sso")
+                            .conflicts_with("sso-token")
+                            .help(
+                                "Print the URL that needs to be opened to log \
+                                 in using single sign-on.",
+                            ),
+                    )
+                    .arg(
+                        Arg::with_name("sso-token")
+                            .long("sso-token")
+                            .value_name("token")
+                            .takes_value(true)
+                            .help(
+                                "Log in using the login token received after \
+                                 a single sign-on login.",
+                            ),
                     ),
             )
             .subcommand(
@@ -307,7 +1204,25 @@ impl CommandCallback for MatrixCommand {
                     .arg(
                         Arg::with_name("name")
                             .value_name("server-name")
-                            .required(true),
+                            .required_unless("all"),
+                    )
+                    .arg(
+                        Arg::with_name("all")
+                            .long("all")
+                            .conflicts_with("name")
+                            .help("Disconnect from all the connected servers."),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("reconnect")
+                    .about(
+                        "Reconnect to the given Matrix servers, or to all of \
+                         them if no server is given",
+                    )
+                    .arg(
+                        Arg::with_name("name")
+                            .value_name("server-name")
+                            .multiple(true),
                     ),
             );
 
@@ -0,0 +1,74 @@
+use matrix_sdk::ruma::events::room::message::{
+    EmoteMessageEventContent, MessageType, RoomMessageEventContent,
+};
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{config::ConfigHandle, Servers, PLUGIN_NAME};
+
+pub struct MeCommand {
+    servers: Servers,
+    config: ConfigHandle,
+}
+
+impl MeCommand {
+    pub const DESCRIPTION: &'static str = "Send an emote to the current room.";
+
+    pub fn create(
+        servers: &Servers,
+        config: &ConfigHandle,
+    ) -> Result<Command, ()> {
+        let settings = CommandSettings::new("me")
+            .description(Self::DESCRIPTION)
+            .add_argument("<message>")
+            .arguments_description("message: The emote that should be sent");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                config: config.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for MeCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The me command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let message = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if message.trim().is_empty() {
+            Weechat::print(&format!(
+                "{}{}: Missing the emote message",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        }
+
+        let emote = if self.config.borrow().input().markdown_input() {
+            EmoteMessageEventContent::markdown(message)
+        } else {
+            EmoteMessageEventContent::plain(message)
+        };
+
+        let content = RoomMessageEventContent::new(MessageType::Emote(emote));
+        room.spawn_task(|room| async move { room.send_message(content).await });
+    }
+}
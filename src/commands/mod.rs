@@ -8,20 +8,66 @@ use crate::{config::ConfigHandle, Servers};
 
 mod buffer_clear;
 mod devices;
+mod edit;
+mod invite;
 mod keys;
+mod kick;
+mod mark_read;
 mod matrix;
+mod me;
+mod names;
 mod page_up;
+mod part;
+mod power_level;
+mod query;
+mod redact;
+mod reply;
+mod room;
+mod thread;
+mod upload;
+mod whois;
 
 use buffer_clear::BufferClearCommand;
 use devices::DevicesCommand;
+use edit::EditCommand;
+use invite::InviteCommand;
 use keys::KeysCommand;
+use kick::KickCommand;
+use mark_read::MarkReadCommand;
 use matrix::MatrixCommand;
+use me::MeCommand;
+use names::NamesCommand;
 use page_up::PageUpCommand;
+use part::PartCommand;
+use power_level::PowerLevelCommand;
+use query::QueryCommand;
+use redact::RedactCommand;
+use reply::ReplyCommand;
+use room::RoomCommand;
+use thread::ThreadCommand;
+use upload::UploadCommand;
+use whois::WhoisCommand;
 
 pub struct Commands {
     _matrix: Command,
     _keys: Command,
+    _kick: Command,
+    _mark_read: Command,
     _devices: Command,
+    _edit: Command,
+    _invite: Command,
+    _me: Command,
+    _names: Command,
+    _part: Command,
+    _op: Command,
+    _deop: Command,
+    _voice: Command,
+    _query: Command,
+    _redact: Command,
+    _reply: Command,
+    _thread: Command,
+    _upload: Command,
+    _whois: Command,
     _page_up: CommandRun,
     _buffer_clear: CommandRun,
 }
@@ -34,7 +80,23 @@ impl Commands {
         Ok(Commands {
             _matrix: MatrixCommand::create(servers, config)?,
             _devices: DevicesCommand::create(servers)?,
+            _edit: EditCommand::create(servers)?,
+            _invite: InviteCommand::create(servers)?,
             _keys: KeysCommand::create(servers)?,
+            _kick: KickCommand::create(servers)?,
+            _mark_read: MarkReadCommand::create(servers)?,
+            _me: MeCommand::create(servers, config)?,
+            _names: NamesCommand::create(servers)?,
+            _part: PartCommand::create(servers)?,
+            _op: PowerLevelCommand::op(servers)?,
+            _deop: PowerLevelCommand::deop(servers)?,
+            _voice: PowerLevelCommand::voice(servers)?,
+            _query: QueryCommand::create(servers)?,
+            _redact: RedactCommand::create(servers)?,
+            _reply: ReplyCommand::create(servers, config)?,
+            _thread: ThreadCommand::create(servers)?,
+            _upload: UploadCommand::create(servers)?,
+            _whois: WhoisCommand::create(servers)?,
             _page_up: PageUpCommand::create(servers)?,
             _buffer_clear: BufferClearCommand::create(servers)?,
         })
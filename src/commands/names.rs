@@ -0,0 +1,45 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct NamesCommand {
+    servers: Servers,
+}
+
+impl NamesCommand {
+    pub const DESCRIPTION: &'static str =
+        "List the members of the current room grouped by their power level.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings =
+            CommandSettings::new("names").description(Self::DESCRIPTION);
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for NamesCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, _: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The names command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+        room.spawn_task(|room| async move { room.print_names().await });
+    }
+}
@@ -33,8 +33,9 @@ impl CommandRunCallback for PageUpCommand {
         if let Some(room) = self.servers.find_room(buffer) {
             if let Some(window) = buffer.window() {
                 if window.is_first_line_displayed() || buffer.num_lines() == 0 {
-                    Weechat::spawn(async move { room.get_messages().await })
-                        .detach();
+                    room.spawn_task(
+                        |room| async move { room.get_messages().await },
+                    );
                 }
             }
         }
@@ -0,0 +1,49 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{BufferOwner, Servers, PLUGIN_NAME};
+
+pub struct PartCommand {
+    servers: Servers,
+}
+
+impl PartCommand {
+    pub const DESCRIPTION: &'static str =
+        "Leave the current room and close its buffer.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings =
+            CommandSettings::new("part").description(Self::DESCRIPTION);
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for PartCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, _: Args) {
+        let (server, room) = if let BufferOwner::Room(s, r) =
+            self.servers.buffer_owner(buffer)
+        {
+            (s, r)
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The part command must be executed on a Matrix \
+                     room buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        Weechat::spawn(async move { server.leave_room(room.room_id()).await })
+            .detach();
+    }
+}
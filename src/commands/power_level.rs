@@ -0,0 +1,103 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+/// A command that sets the power level of a room member to a fixed value,
+/// used for /op, /deop and /voice.
+pub struct PowerLevelCommand {
+    servers: Servers,
+    name: &'static str,
+    power_level: i64,
+}
+
+impl PowerLevelCommand {
+    pub fn create(
+        servers: &Servers,
+        name: &'static str,
+        description: &str,
+        power_level: i64,
+    ) -> Result<Command, ()> {
+        let settings = CommandSettings::new(name)
+            .description(description)
+            .add_argument("<nick|user-id>")
+            .arguments_description(
+                "   nick: The nick of the member in the current room
+user-id: The Matrix user ID of the member",
+            )
+            .add_completion("%(nicks)|%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                name,
+                power_level,
+            },
+        )
+    }
+
+    pub fn op(servers: &Servers) -> Result<Command, ()> {
+        Self::create(
+            servers,
+            "op",
+            "Give a member of the current room administrator rights (power \
+             level 100).",
+            100,
+        )
+    }
+
+    pub fn deop(servers: &Servers) -> Result<Command, ()> {
+        Self::create(
+            servers,
+            "deop",
+            "Reset the power level of a member of the current room to 0.",
+            0,
+        )
+    }
+
+    pub fn voice(servers: &Servers) -> Result<Command, ()> {
+        Self::create(
+            servers,
+            "voice",
+            "Give a member of the current room voice (power level 1).",
+            1,
+        )
+    }
+}
+
+impl CommandCallback for PowerLevelCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The {} command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME,
+                self.name
+            ));
+            return;
+        };
+
+        let user = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if user.is_empty() {
+            Weechat::print(&format!(
+                "{}{}: Missing the nick or user ID of the member",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        }
+
+        let power_level = self.power_level;
+        room.spawn_task(|room| async move {
+            room.set_power_level(&user, power_level).await
+        });
+    }
+}
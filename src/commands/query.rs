@@ -0,0 +1,66 @@
+use matrix_sdk::ruma::UserId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct QueryCommand {
+    servers: Servers,
+}
+
+impl QueryCommand {
+    pub const DESCRIPTION: &'static str =
+        "Open a direct message with a user, the direct message room is \
+         created if there isn't one already.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("query")
+            .description(Self::DESCRIPTION)
+            .add_argument("<user-id>")
+            .arguments_description(
+                "user-id: The Matrix user ID of the user to talk to",
+            )
+            .add_completion("%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for QueryCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, mut arguments: Args) {
+        let server = if let Some(server) = self.servers.find_server(buffer) {
+            server
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The query command must be executed on a Matrix buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let user_id = match arguments.nth(1).map(|u| UserId::parse(u.as_str()))
+        {
+            Some(Ok(u)) => u,
+            _ => {
+                Weechat::print(&format!(
+                    "{}{}: Missing or invalid user ID",
+                    Weechat::prefix(Prefix::Error),
+                    PLUGIN_NAME
+                ));
+                return;
+            }
+        };
+
+        Weechat::spawn(async move { server.query(user_id).await }).detach();
+    }
+}
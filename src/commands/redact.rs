@@ -0,0 +1,73 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct RedactCommand {
+    servers: Servers,
+}
+
+impl RedactCommand {
+    pub const DESCRIPTION: &'static str =
+        "Redact a message in the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("redact")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id|text> [<reason>]")
+            .arguments_description(
+                "event-id: The ID of the event that should be redacted
+    text: Redact your latest message containing this text
+  reason: The reason for the redaction",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for RedactCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The redact command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let target = if let Some(t) = arguments.next() {
+            t
+        } else {
+            Weechat::print(&format!(
+                "{}{}: Missing the event ID or text of the message to redact",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let reason = arguments.collect::<Vec<String>>().join(" ");
+        let reason = if reason.is_empty() {
+            None
+        } else {
+            Some(reason)
+        };
+        room.spawn_task(
+            |room| async move { room.redact(&target, reason).await },
+        );
+    }
+}
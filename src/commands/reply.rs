@@ -0,0 +1,120 @@
+use matrix_sdk::ruma::{
+    events::{
+        relation::InReplyTo,
+        room::message::{
+            MessageType, Relation, RoomMessageEventContent,
+            TextMessageEventContent,
+        },
+    },
+    EventId,
+};
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{config::ConfigHandle, Servers, PLUGIN_NAME};
+
+pub struct ReplyCommand {
+    servers: Servers,
+    config: ConfigHandle,
+}
+
+impl ReplyCommand {
+    pub const DESCRIPTION: &'static str =
+        "Reply to a message in the current room.";
+
+    pub fn create(
+        servers: &Servers,
+        config: &ConfigHandle,
+    ) -> Result<Command, ()> {
+        let settings = CommandSettings::new("reply")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id|offset> <message>")
+            .arguments_description(
+                "event-id: The ID of the event that should be replied to
+  offset: The position of the message to reply to, counting from the \
+                 newest message, starting at 1
+ message: The reply that should be sent",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+                config: config.clone(),
+            },
+        )
+    }
+
+    fn print_error(message: &str) {
+        Weechat::print(&format!(
+            "{}{}: {}",
+            Weechat::prefix(Prefix::Error),
+            PLUGIN_NAME,
+            message
+        ));
+    }
+}
+
+impl CommandCallback for ReplyCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The reply command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let target = if let Some(t) = arguments.next() {
+            t
+        } else {
+            Self::print_error("Missing the message to reply to");
+            return;
+        };
+
+        let event_id = if target.starts_with('$') {
+            EventId::parse(&target).ok()
+        } else {
+            target
+                .parse::<usize>()
+                .ok()
+                .and_then(|offset| room.event_id_at_offset(offset))
+        };
+
+        let event_id = if let Some(e) = event_id {
+            e
+        } else {
+            Self::print_error(&format!("No message found for {}", target));
+            return;
+        };
+
+        let message = arguments.collect::<Vec<String>>().join(" ");
+
+        if message.is_empty() {
+            Self::print_error("Missing the reply message");
+            return;
+        }
+
+        let text = if self.config.borrow().input().markdown_input() {
+            TextMessageEventContent::markdown(message)
+        } else {
+            TextMessageEventContent::plain(message)
+        };
+
+        let mut content = RoomMessageEventContent::new(MessageType::Text(text));
+        content.relates_to = Some(Relation::Reply {
+            in_reply_to: InReplyTo::new(event_id),
+        });
+        room.spawn_task(|room| async move { room.send_message(content).await });
+    }
+}
@@ -0,0 +1,295 @@
+use clap::{
+    App as Argparse, AppSettings as ArgParseSettings, Arg, ArgMatches,
+    SubCommand,
+};
+
+use weechat::{buffer::Buffer, Weechat};
+
+use super::matrix::MatrixCommand;
+use crate::{room::RoomHandle, BufferOwner, MatrixServer, Servers};
+
+pub struct RoomCommand;
+
+impl RoomCommand {
+    pub const DESCRIPTION: &'static str =
+        "Inspect and manage the state of the current room";
+    /// The completion templates of the subcommands, one per subcommand so
+    /// every subcommand only completes its own arguments.
+    pub const COMPLETIONS: &'static [&'static str] = &[
+        "queue clear",
+        "color %(nicks) %(weechat_colors)",
+        "receipts on|off",
+        "rotate-keys",
+        "encryption-info",
+        "who-can-see",
+        "accept-knock",
+        "reject-knock",
+        "joinrule public|invite|knock|restricted|knock-restricted",
+        "raw",
+        "history",
+        "timeline",
+        "via",
+    ];
+    pub const SETTINGS: &'static [ArgParseSettings] = &[
+        ArgParseSettings::DisableHelpFlags,
+        ArgParseSettings::DisableVersion,
+        ArgParseSettings::VersionlessSubcommands,
+        ArgParseSettings::SubcommandRequiredElseHelp,
+    ];
+
+    fn queue(room: RoomHandle, args: &ArgMatches) {
+        match args.subcommand() {
+            ("clear", _) => room.clear_outgoing_queue(),
+            _ => room.print_outgoing_queue(),
+        }
+    }
+
+    fn color(room: RoomHandle, args: &ArgMatches) {
+        let user = args
+            .value_of("user")
+            .expect("User not set but was required")
+            .to_owned();
+        let color = args.value_of("color").map(|c| c.to_owned());
+        room.spawn_task(|room| async move {
+            room.set_nick_color(&user, color).await
+        });
+    }
+
+    fn receipts(room: RoomHandle, args: &ArgMatches) {
+        match args.value_of("state") {
+            Some("on") => room.set_read_receipts(true),
+            Some("off") => room.set_read_receipts(false),
+            _ => room.print_network(&format!(
+                "Read receipts are {} for this room",
+                if room.read_receipts_enabled() {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            )),
+        }
+    }
+
+    fn accept_knock(room: RoomHandle, args: &ArgMatches) {
+        let user = args
+            .value_of("user")
+            .expect("User not set but was required")
+            .to_owned();
+        room.spawn_task(|room| async move { room.accept_knock(&user).await });
+    }
+
+    fn reject_knock(room: RoomHandle, args: &ArgMatches) {
+        let user = args
+            .value_of("user")
+            .expect("User not set but was required")
+            .to_owned();
+        let reason = args
+            .values_of("reason")
+            .map(|r| r.collect::<Vec<&str>>().join(" "));
+        room.spawn_task(|room| async move {
+            room.reject_knock(&user, reason).await
+        });
+    }
+
+    fn joinrule(room: RoomHandle, args: &ArgMatches) {
+        let rule = args
+            .value_of("rule")
+            .expect("Join rule not set but was required")
+            .to_owned();
+        let allowed_rooms: Vec<String> = args
+            .values_of("allow")
+            .map(|r| r.map(|r| r.to_owned()).collect())
+            .unwrap_or_default();
+        room.spawn_task(|room| async move {
+            room.set_join_rule(&rule, &allowed_rooms).await
+        });
+    }
+
+    fn raw(room: RoomHandle, args: &ArgMatches) {
+        let offset = args
+            .value_of("offset")
+            .expect("Offset not set but was required")
+            .parse::<usize>()
+            .expect("Can't parse the offset even if validation passed");
+        room.spawn_task(|room| async move { room.toggle_raw(offset).await });
+    }
+
+    fn history(room: RoomHandle, args: &ArgMatches) {
+        let count = args
+            .value_of("count")
+            .expect("Count not set but was required")
+            .parse::<usize>()
+            .expect("Can't parse the count even if validation passed");
+        room.spawn_task(|room| async move { room.load_history(count).await });
+    }
+
+    fn timeline(server: MatrixServer, room: RoomHandle, args: &ArgMatches) {
+        let count = args
+            .value_of("count")
+            .unwrap_or("20")
+            .parse::<usize>()
+            .expect("Can't parse the count even if validation passed");
+        room.spawn_task(|room| async move {
+            server.print_room_timeline(&room, count).await
+        });
+    }
+
+    fn parse_count(value: String) -> Result<(), String> {
+        match value.parse::<usize>() {
+            Ok(c) if c > 0 => Ok(()),
+            _ => Err("The count needs to be a positive number".to_owned()),
+        }
+    }
+
+    pub fn run(buffer: &Buffer, servers: &Servers, args: &ArgMatches) {
+        let room = if let Some(room) = servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print("Must be executed on a Matrix room buffer");
+            return;
+        };
+
+        match args.subcommand() {
+            ("queue", Some(args)) => Self::queue(room, args),
+            ("color", Some(args)) => Self::color(room, args),
+            ("receipts", Some(args)) => Self::receipts(room, args),
+            ("rotate-keys", _) => {
+                room.spawn_task(|room| async move { room.rotate_keys().await });
+            }
+            ("encryption-info", _) => room.print_encryption_info(),
+            ("who-can-see", _) => room.print_visibility(),
+            ("accept-knock", Some(args)) => Self::accept_knock(room, args),
+            ("reject-knock", Some(args)) => Self::reject_knock(room, args),
+            ("joinrule", Some(args)) => Self::joinrule(room, args),
+            ("raw", Some(args)) => Self::raw(room, args),
+            ("history", Some(args)) => Self::history(room, args),
+            ("timeline", Some(args)) => {
+                if let BufferOwner::Room(server, _) =
+                    servers.buffer_owner(buffer)
+                {
+                    Self::timeline(server, room, args);
+                }
+            }
+            ("via", _) => {
+                room.spawn_task(|room| async move {
+                    room.print_via_servers().await
+                });
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn subcommands() -> Vec<Argparse<'static, 'static>> {
+        vec![
+            SubCommand::with_name("queue")
+                .about("List the messages that are waiting to be sent out.")
+                .subcommand(
+                    SubCommand::with_name("clear").about(
+                        "Drop all the messages from the outgoing queue.",
+                    ),
+                ),
+            SubCommand::with_name("color")
+                .about(
+                    "Set the nick color of a room member, the color is reset \
+                     if no color is given.",
+                )
+                .arg(
+                    Arg::with_name("user")
+                        .value_name("nick-or-user-id")
+                        .required(true),
+                )
+                .arg(Arg::with_name("color").required(false)),
+            SubCommand::with_name("receipts")
+                .about(
+                    "Enable or disable sending read receipts for the current \
+                     room, overriding the global setting.",
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .possible_values(&["on", "off"])
+                        .required(false),
+                ),
+            SubCommand::with_name("rotate-keys").about(
+                "Discard the current outbound session of an encrypted room, \
+                 the next message will be encrypted using a new session.",
+            ),
+            SubCommand::with_name("encryption-info").about(
+                "Show the encryption algorithm and the session rotation \
+                 settings of the current room.",
+            ),
+            SubCommand::with_name("who-can-see").about(
+                "Show who can join the current room and read its history.",
+            ),
+            SubCommand::with_name("accept-knock")
+                .about("Let a user that asked to join the room in.")
+                .arg(
+                    Arg::with_name("user").value_name("user-id").required(true),
+                ),
+            SubCommand::with_name("reject-knock")
+                .about("Deny a user that asked to join the room entry.")
+                .arg(
+                    Arg::with_name("user").value_name("user-id").required(true),
+                )
+                .arg(Arg::with_name("reason").multiple(true)),
+            SubCommand::with_name("joinrule")
+                .about(
+                    "Change who can join the current room, the restricted \
+                     rules need the rooms, usually spaces, whose members are \
+                     allowed to join.",
+                )
+                .arg(
+                    Arg::with_name("rule")
+                        .possible_values(&[
+                            "public",
+                            "invite",
+                            "knock",
+                            "restricted",
+                            "knock-restricted",
+                        ])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("allow")
+                        .value_name("room-id")
+                        .multiple(true),
+                ),
+            SubCommand::with_name("raw")
+                .about(
+                    "Toggle the message at the given offset, counting from the \
+                     newest message, between its rendered form and its raw \
+                     content.",
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .required(true)
+                        .validator(MatrixCommand::parse_offset),
+                ),
+            SubCommand::with_name("history")
+                .about(
+                    "Load older messages of the current room until the given \
+                     number of messages has been loaded or the start of the \
+                     room is reached.",
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .required(true)
+                        .validator(Self::parse_count),
+                ),
+            SubCommand::with_name("timeline")
+                .about(
+                    "Print the latest events of the current room to the \
+                     server buffer as the homeserver returns them, without \
+                     rendering them.",
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .default_value("20")
+                        .validator(Self::parse_count),
+                ),
+            SubCommand::with_name("via").about(
+                "Show the servers that should be used as via servers in \
+                 permalinks to the current room.",
+            ),
+        ]
+    }
+}
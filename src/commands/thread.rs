@@ -0,0 +1,85 @@
+use matrix_sdk::ruma::EventId;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct ThreadCommand {
+    servers: Servers,
+}
+
+impl ThreadCommand {
+    pub const DESCRIPTION: &'static str =
+        "Open the buffer of a thread in the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("thread")
+            .description(Self::DESCRIPTION)
+            .add_argument("<event-id|offset>")
+            .arguments_description(
+                "event-id: The ID of the thread root
+  offset: The position of the thread root, counting from the newest \
+                 message, starting at 1",
+            );
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+
+    fn print_error(message: &str) {
+        Weechat::print(&format!(
+            "{}{}: {}",
+            Weechat::prefix(Prefix::Error),
+            PLUGIN_NAME,
+            message
+        ));
+    }
+}
+
+impl CommandCallback for ThreadCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The thread command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let mut arguments = arguments.skip(1);
+
+        let target = if let Some(t) = arguments.next() {
+            t
+        } else {
+            Self::print_error("Missing the thread root");
+            return;
+        };
+
+        let event_id = if target.starts_with('$') {
+            EventId::parse(&target).ok()
+        } else {
+            target
+                .parse::<usize>()
+                .ok()
+                .and_then(|offset| room.event_id_at_offset(offset))
+        };
+
+        if let Some(event_id) = event_id {
+            room.open_thread(&self.servers, &event_id);
+        } else {
+            Self::print_error(&format!("No message found for {}", target));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct UploadCommand {
+    servers: Servers,
+}
+
+impl UploadCommand {
+    pub const DESCRIPTION: &'static str =
+        "Upload a file and send it to the current room.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("upload")
+            .description(Self::DESCRIPTION)
+            .add_argument("<file> [--caption <text>]")
+            .arguments_description(
+                "   file: Path to the file that should be sent
+caption: A caption that is sent alongside the file",
+            )
+            .add_completion("%(filename)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for UploadCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The upload command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let arguments: Vec<String> = arguments.skip(1).collect();
+
+        let (path, caption) =
+            match arguments.iter().position(|a| a == "--caption") {
+                Some(i) => (
+                    arguments[..i].join(" "),
+                    Some(arguments[i + 1..].join(" ")),
+                ),
+                None => (arguments.join(" "), None),
+            };
+
+        let caption = caption.filter(|c| !c.is_empty());
+
+        if path.is_empty() {
+            Weechat::print(&format!(
+                "{}{}: Missing the file that should be uploaded",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        }
+
+        let path = match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+            (Some(p), Some(home)) => PathBuf::from(home).join(p),
+            _ => PathBuf::from(path),
+        };
+        room.spawn_task(|room| async move { room.upload(path, caption).await });
+    }
+}
@@ -0,0 +1,62 @@
+use weechat::{
+    buffer::Buffer,
+    hooks::{Command, CommandCallback, CommandSettings},
+    Args, Prefix, Weechat,
+};
+
+use crate::{Servers, PLUGIN_NAME};
+
+pub struct WhoisCommand {
+    servers: Servers,
+}
+
+impl WhoisCommand {
+    pub const DESCRIPTION: &'static str =
+        "Show the profile, power level and devices of a room member.";
+
+    pub fn create(servers: &Servers) -> Result<Command, ()> {
+        let settings = CommandSettings::new("whois")
+            .description(Self::DESCRIPTION)
+            .add_argument("<nick|user-id>")
+            .arguments_description(
+                "   nick: The nick of the member in the current room
+user-id: The Matrix user ID of the member",
+            )
+            .add_completion("%(nicks)|%(matrix-users)");
+
+        Command::new(
+            settings,
+            Self {
+                servers: servers.clone(),
+            },
+        )
+    }
+}
+
+impl CommandCallback for WhoisCommand {
+    fn callback(&mut self, _: &Weechat, buffer: &Buffer, arguments: Args) {
+        let room = if let Some(room) = self.servers.find_room(buffer) {
+            room
+        } else {
+            Weechat::print(&format!(
+                "{}{}: The whois command must be executed on a Matrix room \
+                 buffer",
+                Weechat::prefix(Prefix::Network),
+                PLUGIN_NAME
+            ));
+            return;
+        };
+
+        let user = arguments.skip(1).collect::<Vec<String>>().join(" ");
+
+        if user.is_empty() {
+            Weechat::print(&format!(
+                "{}{}: Missing the nick or user ID of the member",
+                Weechat::prefix(Prefix::Error),
+                PLUGIN_NAME
+            ));
+            return;
+        }
+        room.spawn_task(|room| async move { room.whois(&user).await });
+    }
+}
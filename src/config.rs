@@ -17,20 +17,29 @@
 
 use std::{
     cell::{Ref, RefCell, RefMut},
+    collections::HashMap,
     rc::Rc,
 };
 
+use matrix_sdk::ruma::{api::client::filter::FilterDefinition, OwnedUserId};
 use strum::{EnumVariantNames, VariantNames};
 use weechat::{
     config,
     config::{
         Conf, ConfigOption, ConfigSection, ConfigSectionSettings,
         EnumOptionSettings, OptionChanged, SectionReadCallback,
+        StringOptionSettings,
     },
-    Weechat,
+    Prefix, Weechat,
 };
 
-use crate::{MatrixServer, Servers};
+use crate::{
+    render::{
+        expand_nick_format, expand_prefix_template, parse_power_prefixes,
+        parse_user_highlights, PowerPrefix, UserHighlight,
+    },
+    MatrixServer, Servers, PLUGIN_NAME,
+};
 
 #[derive(EnumVariantNames)]
 #[strum(serialize_all = "kebab_case")]
@@ -57,6 +66,31 @@ impl From<i32> for RedactionStyle {
     }
 }
 
+#[derive(EnumVariantNames)]
+#[strum(serialize_all = "kebab_case")]
+pub enum NewDirectMessage {
+    Ignore,
+    Notify,
+    Switch,
+}
+
+impl Default for NewDirectMessage {
+    fn default() -> Self {
+        NewDirectMessage::Ignore
+    }
+}
+
+impl From<i32> for NewDirectMessage {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => NewDirectMessage::Ignore,
+            1 => NewDirectMessage::Notify,
+            2 => NewDirectMessage::Switch,
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[derive(EnumVariantNames)]
 #[strum(serialize_all = "kebab_case")]
 pub enum ServerBuffer {
@@ -115,6 +149,14 @@ config!(
             "⏳",
         },
 
+        reconnecting_sign: String {
+            // Description.
+            "A sign that is used to show that the connection to the server \
+                was lost and the client is trying to reconnect",
+            // Default value.
+            "⚡",
+        },
+
         local_echo: bool {
             // Description
             "Should the sending message be printed out before the server \
@@ -128,6 +170,138 @@ config!(
             "The style that should be used when a message needs to be redacted",
             RedactionStyle,
         },
+
+        new_direct_message: Enum {
+            // Description
+            "What to do when a buffer gets created for a direct message room \
+             we didn't know about, e.g. because someone contacted us for the \
+             first time: ignore it, notify about it in the server buffer or \
+             switch to the new buffer",
+            NewDirectMessage,
+        },
+
+        emxc_plumber: String {
+            // Description.
+            "A shell command that /matrix open uses to open encrypted media, \
+             the emxc URL is passed in the $url environment variable, e.g. \
+             'emxc-plumber \"$url\"'",
+            // Default value.
+            "",
+        },
+
+        guest_access_warning: bool {
+            // Description
+            "Print a warning when a public room allows guests to join",
+            // Default value
+            true,
+        },
+
+        timestamp_format: String {
+            // Description.
+            "A strftime style format for a timestamp that is put in front of \
+             the prefix of messages, e.g. '%H:%M', Weechat's own time format \
+             is used alone if this is empty",
+            // Default value.
+            "",
+        },
+
+        timestamp_changes_only: bool {
+            // Description.
+            "Only show the timestamp of the timestamp_format option if it \
+             differs from the one of the previous message, e.g. with '%H:%M' \
+             consecutive messages within the same minute get blanks instead, \
+             Weechat's own time column isn't affected by this, set \
+             weechat.look.buffer_time_format to an empty string to hide it \
+             or use weechat.look.buffer_time_same to shorten it",
+            // Default value.
+            false,
+        },
+
+        auto_mark_read: bool {
+            // Description
+            "Mark a room as read, by sending a fully read marker together with \
+             the read receipt, when switching to its buffer",
+            // Default value
+            false,
+        },
+
+        show_power_in_prefix: bool {
+            // Description
+            "Show the power level sign of the sender, e.g. @ for moderators, \
+             in front of the nick in the prefix of messages and not only in \
+             the nicklist, the signs are set by the power_prefixes option",
+            // Default value
+            true,
+        },
+
+        always_show_user_id: bool {
+            // Description
+            "Always show the user ID of the sender next to the nick, not only \
+             when the display name is ambiguous",
+            // Default value
+            false,
+        },
+
+        thread_context: bool {
+            // Description
+            "Show the parent of a threaded message, if it's known, as an \
+             indented line above the message",
+            // Default value
+            false,
+        },
+
+        nicklist_presence: bool {
+            // Description
+            "Use the away color for the nicks of members that are away or \
+             offline in the nicklist",
+            // Default value
+            true,
+        },
+
+        read_marker: bool {
+            // Description
+            "Append a dimmed read marker to your last message once another \
+             member has read it",
+            // Default value
+            false,
+        },
+
+        merge_notices: bool {
+            // Description
+            "Print a notice that directly follows a message of the same \
+             sender, e.g. a link preview of a bot, as an indented \
+             continuation of the message instead of as a separate line",
+            // Default value
+            false,
+        },
+
+        restore_summary: bool {
+            // Description
+            "Print a one line summary of the room, e.g. the number of members, \
+             whether the room is encrypted and when it was last active, when \
+             its buffer is restored",
+            // Default value
+            false,
+        },
+
+        nicklist_member_cap: Integer {
+            // Description
+            "Rooms with more members than this only add members that speak to \
+             the nicklist, 0 disables the cap",
+            // Default value
+            0,
+            // The value can be between 0 and 1000000
+            0..1000000,
+        },
+    },
+
+    Section color {
+        quote_fg: Color {
+            // Description
+            "Foreground color for the quoted parent message of replies",
+            // Default value
+            "lightgreen",
+        },
     },
 
     Section network {
@@ -137,6 +311,63 @@ config!(
             // Default value.
             false,
         },
+
+        safe_mode: bool {
+            // Description
+            "Refuse to send messages to rooms that aren't encrypted or that \
+             contain unverified devices, in all rooms",
+            // Default value.
+            false,
+        },
+
+        typing_notice_delay: Integer {
+            // Description
+            "How long, in milliseconds, the input needs to be typed into \
+             before a typing notice is sent out",
+            // Default value
+            0,
+            // The value can be between 0 and 60000
+            0..60000,
+        },
+
+        sync_timeout_max: Integer {
+            // Description
+            "The maximal timeout, in seconds, the sync timeout is raised to if \
+             the server keeps rate limiting our sync requests",
+            // Default value
+            300,
+            // The value can be between 30 and 3600
+            30..3600,
+        },
+
+        sync_backoff_max: Integer {
+            // Description
+            "The maximal time, in seconds, to wait before syncing again if \
+             the server keeps rate limiting our sync requests",
+            // Default value
+            120,
+            // The value can be between 1 and 3600
+            1..3600,
+        },
+
+        read_receipts: bool {
+            // Description
+            "Send read receipts when switching to a room buffer, can be \
+             overridden per room using /matrix room receipts",
+            // Default value.
+            true,
+        },
+
+        utd_grace_period: Integer {
+            // Description
+            "How long, in seconds, to wait for the room key of a message that \
+             can't be decrypted before the message is printed as \
+             undecryptable, 0 prints it right away",
+            // Default value
+            0,
+            // The value can be between 0 and 60
+            0..60,
+        },
     },
 
     Section input {
@@ -211,6 +442,140 @@ impl ConfigHandle {
             look_section
                 .new_enum_option(settings)
                 .expect("Can't create server buffers option");
+
+            let settings = StringOptionSettings::new("user_highlights")
+                .description(
+                    "Comma separated list of user IDs that should cause \
+                     highlights, every user ID is followed by when to \
+                     highlight: message (they speak), mention (they are \
+                     mentioned) or all, e.g. @boss:example.org=message",
+                )
+                .set_check_callback(|_, _, value| {
+                    parse_user_highlights(&value).is_ok()
+                });
+
+            look_section
+                .new_string_option(settings)
+                .expect("Can't create user highlights option");
+
+            let settings = StringOptionSettings::new("highlight_names")
+                .description(
+                    "Comma separated list of names that highlight a message \
+                     when they are mentioned in it, ${displayname} is \
+                     replaced by your display name in the room and \
+                     ${localpart} by the local part of your user ID, matching \
+                     is case insensitive and only whole words count",
+                )
+                .default_value("${displayname},${localpart}");
+
+            look_section
+                .new_string_option(settings)
+                .expect("Can't create highlight names option");
+
+            let settings = StringOptionSettings::new("prefix_template")
+                .description(
+                    "The format of the prefix of messages, the placeholders \
+                     %nick% (the colored nick), %userid% (the user ID) and \
+                     %power% (the power level sign) get replaced, %% is a \
+                     literal percent sign",
+                )
+                .default_value("%nick%")
+                .set_check_callback(|_, _, value| {
+                    expand_prefix_template(&value, "", "", "").is_ok()
+                });
+
+            look_section
+                .new_string_option(settings)
+                .expect("Can't create prefix template option");
+
+            let settings = StringOptionSettings::new("nick_format")
+                .description(
+                    "The format of nicks, the placeholders ${prefix} (the \
+                     power level sign, empty if the nick is ambiguous), \
+                     ${nick} (the colored nick), ${mxid} (the user ID in \
+                     parentheses, if the nick is ambiguous or \
+                     always_show_user_id is enabled) and ${userid} (the user \
+                     ID) get replaced",
+                )
+                .default_value("${prefix}${nick}${mxid}")
+                .set_check_callback(|_, _, value| {
+                    let placeholders = ["prefix", "nick", "mxid", "userid"];
+                    let values: Vec<_> =
+                        placeholders.iter().map(|p| (*p, "")).collect();
+
+                    expand_nick_format(&value, &values).is_ok()
+                });
+
+            look_section
+                .new_string_option(settings)
+                .expect("Can't create nick format option");
+
+            let settings = StringOptionSettings::new("power_prefixes")
+                .description(
+                    "Comma separated list of power levels and the nicklist \
+                     prefix that members with at least that power level get, \
+                     e.g. 100=&,50=@,1=+, every prefix gets its own nicklist \
+                     group, the three highest ones are colored like the \
+                     default admin, moderator and voice prefixes",
+                )
+                .default_value("100=&,50=@,1=+")
+                .set_check_callback(|_, _, value| {
+                    parse_power_prefixes(&value).is_ok()
+                });
+
+            look_section
+                .new_string_option(settings)
+                .expect("Can't create power prefixes option");
+
+            drop(look_section);
+
+            let mut network_section = config_borrow.network_mut();
+
+            let settings = StringOptionSettings::new("sync_filter")
+                .description(
+                    "A custom filter definition, in JSON form, that should be \
+                     used for syncing instead of the default one, takes \
+                     effect on the next connect",
+                )
+                .set_check_callback(|_, _, value| {
+                    value.is_empty()
+                        || serde_json::from_str::<FilterDefinition>(&value)
+                            .is_ok()
+                });
+
+            network_section
+                .new_string_option(settings)
+                .expect("Can't create sync filter option");
+
+            let servers = servers.clone();
+            let settings = StringOptionSettings::new("data_dir")
+                .description(
+                    "The directory where the device IDs and crypto stores of \
+                     the servers are kept, defaults to the matrix-rust \
+                     directory in the Weechat home directory, takes effect on \
+                     the next connect",
+                )
+                .set_change_callback(move |_, _| {
+                    // The option is also set while the config is being read,
+                    // before any server is created, don't warn in that case.
+                    if servers.is_empty() {
+                        return;
+                    }
+
+                    Weechat::print(&format!(
+                        "{}{}: The data directory has been changed, existing \
+                         data isn't moved automatically. Move the server \
+                         directories from the old data directory to the new \
+                         one before connecting, otherwise new devices will be \
+                         created and encrypted history will be lost",
+                        Weechat::prefix(Prefix::Network),
+                        PLUGIN_NAME,
+                    ));
+                });
+
+            network_section
+                .new_string_option(settings)
+                .expect("Can't create data directory option");
         }
 
         config
@@ -235,6 +600,77 @@ impl<'a> LookSection<'a> {
             panic!("Server buffer option has the wrong type");
         }
     }
+
+    pub fn prefix_template(&self) -> String {
+        if let ConfigOption::String(o) =
+            self.search_option("prefix_template").unwrap()
+        {
+            o.value().to_string()
+        } else {
+            panic!("Prefix template option has the wrong type");
+        }
+    }
+
+    pub fn nick_format(&self) -> String {
+        if let ConfigOption::String(o) =
+            self.search_option("nick_format").unwrap()
+        {
+            o.value().to_string()
+        } else {
+            panic!("Nick format option has the wrong type");
+        }
+    }
+
+    pub fn power_prefixes(&self) -> Vec<PowerPrefix> {
+        if let ConfigOption::String(o) =
+            self.search_option("power_prefixes").unwrap()
+        {
+            parse_power_prefixes(&o.value()).unwrap_or_default()
+        } else {
+            panic!("Power prefixes option has the wrong type");
+        }
+    }
+
+    pub fn highlight_names(&self) -> String {
+        if let ConfigOption::String(o) =
+            self.search_option("highlight_names").unwrap()
+        {
+            o.value().to_string()
+        } else {
+            panic!("Highlight names option has the wrong type");
+        }
+    }
+
+    pub fn user_highlights(&self) -> HashMap<OwnedUserId, UserHighlight> {
+        if let ConfigOption::String(o) =
+            self.search_option("user_highlights").unwrap()
+        {
+            parse_user_highlights(&o.value()).unwrap_or_default()
+        } else {
+            panic!("User highlights option has the wrong type");
+        }
+    }
+}
+
+impl<'a> NetworkSection<'a> {
+    pub fn sync_filter(&self) -> String {
+        if let ConfigOption::String(o) =
+            self.search_option("sync_filter").unwrap()
+        {
+            o.value().to_string()
+        } else {
+            panic!("Sync filter option has the wrong type");
+        }
+    }
+
+    pub fn data_dir(&self) -> String {
+        if let ConfigOption::String(o) = self.search_option("data_dir").unwrap()
+        {
+            o.value().to_string()
+        } else {
+            panic!("Data directory option has the wrong type");
+        }
+    }
 }
 
 impl SectionReadCallback for ConfigHandle {
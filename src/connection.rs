@@ -1,43 +1,95 @@
 use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    convert::TryFrom,
     future::Future,
-    path::PathBuf,
+    hash::{Hash, Hasher},
+    io::{Read, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
     rc::{Rc, Weak},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+use backon::{BackoffBuilder, ExponentialBuilder};
 use tokio::{
     runtime::Runtime,
     sync::mpsc::{channel, Receiver, Sender},
 };
 
-use tracing::error;
+use tracing::{error, warn};
 
 use matrix_sdk::{
     self,
+    attachment::AttachmentConfig,
     config::SyncSettings,
-    deserialized_responses::AmbiguityChange,
-    room::{Messages, MessagesOptions, Room},
+    crypto::AttachmentEncryptor,
+    deserialized_responses::{AmbiguityChange, TimelineEvent},
+    encryption::verification::{SasVerification, VerificationRequest},
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    mime::{self, Mime},
+    room::{Messages, MessagesOptions, Receipts, Room, RoomMember},
     ruma::{
         api::client::{
+            account::change_password::v3::{
+                Request as ChangePasswordRequest,
+                Response as ChangePasswordResponse,
+            },
             device::{
                 delete_devices::v3::Response as DeleteDevicesResponse,
                 get_devices::v3::Response as DevicesResponse,
+                update_device::v3::{
+                    Request as UpdateDeviceRequest,
+                    Response as UpdateDeviceResponse,
+                },
+            },
+            discovery::{
+                get_capabilities::v3::{
+                    Request as CapabilitiesRequest,
+                    Response as CapabilitiesResponse,
+                },
+                get_supported_versions::{
+                    Request as VersionsRequest, Response as VersionsResponse,
+                },
             },
+            error::ErrorKind,
             filter::{
                 FilterDefinition, LazyLoadOptions, RoomEventFilter, RoomFilter,
             },
             message::send_message_event::v3::Response as RoomSendResponse,
+            receipt::create_receipt::v3::ReceiptType,
+            redact::redact_event::v3::Response as RedactEventResponse,
             session::login::v3::Response as LoginResponse,
             sync::sync_events::v3::Filter,
             uiaa::{AuthData, Password, UserIdentifier},
+            user_directory::search_users,
         },
         events::{
-            room::member::RoomMemberEventContent, AnyMessageLikeEventContent,
-            AnySyncStateEvent, AnySyncTimelineEvent, SyncStateEvent,
+            direct::DirectEventContent,
+            receipt::{ReceiptEventContent, ReceiptThread},
+            room::{
+                join_rules::{JoinRule, RoomJoinRulesEventContent},
+                member::{MembershipState, RoomMemberEventContent},
+                message::{
+                    AudioMessageEventContent, FileMessageEventContent,
+                    ImageMessageEventContent, MessageType,
+                    RoomMessageEventContent, VideoMessageEventContent,
+                },
+                EncryptedFileInit, MediaSource,
+            },
+            AnyGlobalAccountDataEvent, AnyMessageLikeEventContent,
+            AnySyncEphemeralRoomEvent, AnySyncStateEvent, AnySyncTimelineEvent,
+            AnyToDeviceEvent, SyncStateEvent,
         },
-        OwnedDeviceId, OwnedRoomId, OwnedTransactionId,
+        owned_user_id,
+        presence::PresenceState,
+        Int, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedTransactionId,
+        OwnedUserId, UInt,
     },
-    Client, LoopCtrl, Result as MatrixResult, RoomMemberships,
+    Client, LoopCtrl, Result as MatrixResult, RoomMemberships, SessionMeta,
 };
 
 use weechat::{Task, Weechat};
@@ -48,6 +100,15 @@ use crate::{
 };
 
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+const INITIAL_SYNC_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The bounds within which the sync loop adapts the sync timeout and the
+/// backoff between syncs if the server rate limits us.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncLimits {
+    pub max_timeout: Duration,
+    pub max_backoff: Duration,
+}
 
 pub struct InteractiveAuthInfo {
     pub user: String,
@@ -75,6 +136,36 @@ pub enum ClientMessage {
         Option<AmbiguityChange>,
     ),
     RestoredRoom(Room),
+    SessionRestored(OwnedUserId),
+    ConnectionLost(String, Duration),
+    ConnectionRestored,
+    Synced,
+    VerificationRequest(OwnedUserId, String),
+    Typing(OwnedRoomId, Vec<OwnedUserId>),
+    Receipts(OwnedRoomId, ReceiptEventContent),
+    Presence(OwnedUserId, PresenceState),
+    RoomKeysReceived(OwnedRoomId, String),
+    AccountData(AnyGlobalAccountDataEvent),
+}
+
+/// How long we wait for requests that are still in flight to finish when the
+/// plugin gets unloaded.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Keeps track of a request that is running on the runtime of a `Connection`.
+struct PendingRequest(Arc<AtomicUsize>);
+
+impl PendingRequest {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 /// Struct representing an active connection to the homeserver.
@@ -92,6 +183,7 @@ pub struct Connection {
     receiver_task: Rc<Task<()>>,
     client: Client,
     pub runtime: Rc<Runtime>,
+    pending_requests: Arc<AtomicUsize>,
 }
 
 impl Connection {
@@ -104,12 +196,32 @@ impl Connection {
         F: Future + Send + 'static,
         F::Output: Send + 'static,
     {
+        let pending = PendingRequest::new(&self.pending_requests);
+
         self.runtime
-            .spawn(future)
+            .spawn(async move {
+                let _pending = pending;
+                future.await
+            })
             .await
             .expect("Tokio error while sending a message")
     }
 
+    /// Get a future that resolves once all the requests that were spawned
+    /// on this connection have finished.
+    ///
+    /// The future doesn't borrow the connection, so it can be awaited on a
+    /// different runtime while the connection is being torn down.
+    pub fn flush(&self) -> impl Future<Output = ()> + Send + 'static {
+        let pending = self.pending_requests.clone();
+
+        async move {
+            while pending.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
     pub fn new(server: &MatrixServer, client: &Client) -> Self {
         let (tx, rx) = channel(10_000);
 
@@ -129,12 +241,17 @@ impl Connection {
             server.password(),
             server_name.to_string(),
             server.get_server_path(),
+            server.sync_filter(),
+            server.sync_limits(),
+            server.take_login_token(),
+            server.access_token(),
         ));
 
         Self {
             client: client.clone(),
             runtime: runtime.into(),
             receiver_task: receiver_task.into(),
+            pending_requests: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -166,6 +283,114 @@ impl Connection {
         .await
     }
 
+    /// Upload the given file and send it to the given room.
+    ///
+    /// The message type is picked based on the mime type of the file, the
+    /// file will be encrypted if the room is encrypted.
+    pub async fn send_attachment(
+        &self,
+        room: Room,
+        path: PathBuf,
+        content_type: Mime,
+        caption: Option<String>,
+    ) -> MatrixResult<RoomSendResponse> {
+        self.spawn(async move {
+            let data = std::fs::read(&path)?;
+            let filename = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            if let Some(caption) = caption {
+                Connection::send_captioned_attachment(
+                    room,
+                    filename,
+                    content_type,
+                    data,
+                    caption,
+                )
+                .await
+            } else {
+                room.send_attachment(
+                    &filename,
+                    &content_type,
+                    data,
+                    AttachmentConfig::new(),
+                )
+                .await
+            }
+        })
+        .await
+    }
+
+    /// Upload a file and send it with a caption.
+    ///
+    /// The SDK doesn't support captions for attachments, so the file is
+    /// uploaded, and encrypted if needed, by hand. The body of the message
+    /// contains the caption while the filename field contains the name of
+    /// the file.
+    async fn send_captioned_attachment(
+        room: Room,
+        filename: String,
+        content_type: Mime,
+        data: Vec<u8>,
+        caption: String,
+    ) -> MatrixResult<RoomSendResponse> {
+        let source = if room.is_encrypted().await? {
+            let mut reader = std::io::Cursor::new(data);
+            let mut encryptor = AttachmentEncryptor::new(&mut reader);
+            let mut encrypted = Vec::new();
+            encryptor.read_to_end(&mut encrypted)?;
+            let keys = encryptor.finish();
+
+            let response = room
+                .client()
+                .media()
+                .upload(&mime::APPLICATION_OCTET_STREAM, encrypted)
+                .await?;
+
+            MediaSource::Encrypted(Box::new(
+                EncryptedFileInit {
+                    url: response.content_uri,
+                    key: keys.web_key,
+                    iv: keys.iv,
+                    hashes: keys.hashes,
+                    v: keys.version,
+                }
+                .into(),
+            ))
+        } else {
+            let response =
+                room.client().media().upload(&content_type, data).await?;
+            MediaSource::Plain(response.content_uri)
+        };
+
+        let msgtype = match content_type.type_() {
+            mime::IMAGE => {
+                let mut c = ImageMessageEventContent::new(caption, source);
+                c.filename = Some(filename);
+                MessageType::Image(c)
+            }
+            mime::AUDIO => {
+                let mut c = AudioMessageEventContent::new(caption, source);
+                c.filename = Some(filename);
+                MessageType::Audio(c)
+            }
+            mime::VIDEO => {
+                let mut c = VideoMessageEventContent::new(caption, source);
+                c.filename = Some(filename);
+                MessageType::Video(c)
+            }
+            _ => {
+                let mut c = FileMessageEventContent::new(caption, source);
+                c.filename = Some(filename);
+                MessageType::File(c)
+            }
+        };
+
+        Ok(room.send(RoomMessageEventContent::new(msgtype)).await?)
+    }
+
     pub async fn delete_devices(
         &self,
         devices: Vec<OwnedDeviceId>,
@@ -184,6 +409,219 @@ impl Connection {
             .await?)
     }
 
+    /// Get a verification request using the user that is taking part in the
+    /// verification and the id of the verification flow.
+    pub async fn verification_request(
+        &self,
+        user_id: OwnedUserId,
+        flow_id: String,
+    ) -> Option<VerificationRequest> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            client
+                .encryption()
+                .get_verification_request(&user_id, flow_id)
+                .await
+        })
+        .await
+    }
+
+    /// Accept a verification request that the other side sent us.
+    pub async fn accept_verification(
+        &self,
+        request: VerificationRequest,
+    ) -> MatrixResult<()> {
+        self.spawn(async move { request.accept().await }).await
+    }
+
+    /// Cancel a verification request.
+    pub async fn cancel_verification(
+        &self,
+        request: VerificationRequest,
+    ) -> MatrixResult<()> {
+        self.spawn(async move { request.cancel().await }).await
+    }
+
+    /// Get the SAS verification that belongs to the given verification flow,
+    /// if one has been started by either side.
+    pub async fn sas_verification(
+        &self,
+        user_id: OwnedUserId,
+        flow_id: String,
+    ) -> Option<SasVerification> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            client
+                .encryption()
+                .get_verification(&user_id, &flow_id)
+                .await
+                .and_then(|v| v.sas())
+        })
+        .await
+    }
+
+    /// Start a SAS verification on a verification request that is ready.
+    pub async fn start_sas(
+        &self,
+        request: VerificationRequest,
+    ) -> MatrixResult<Option<SasVerification>> {
+        self.spawn(async move { request.start_sas().await }).await
+    }
+
+    /// Confirm that the short authentication strings of both sides match.
+    pub async fn confirm_sas(&self, sas: SasVerification) -> MatrixResult<()> {
+        self.spawn(async move { sas.confirm().await }).await
+    }
+
+    /// Cancel a SAS verification.
+    pub async fn cancel_sas(&self, sas: SasVerification) -> MatrixResult<()> {
+        self.spawn(async move { sas.cancel().await }).await
+    }
+
+    /// Set the human readable name of one of our devices.
+    ///
+    /// Unlike deleting devices, renaming them doesn't require interactive
+    /// authentication, the endpoint doesn't accept any auth data.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - The id of the device that should be renamed.
+    ///
+    /// * `name` - The new display name of the device.
+    pub async fn set_device_name(
+        &self,
+        device_id: OwnedDeviceId,
+        name: String,
+    ) -> MatrixResult<UpdateDeviceResponse> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let mut request = UpdateDeviceRequest::new(device_id);
+            request.display_name = Some(name);
+
+            Ok(client.send(request, None).await?)
+        })
+        .await
+    }
+
+    /// Change the password of our account.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_password` - The password that should replace the current one.
+    ///
+    /// * `logout_devices` - Should all our other devices be logged out.
+    ///
+    /// * `auth_info` - The authentication info, required if the server
+    /// responded with an interactive authentication request.
+    pub async fn change_password(
+        &self,
+        new_password: String,
+        logout_devices: bool,
+        auth_info: Option<InteractiveAuthInfo>,
+    ) -> MatrixResult<ChangePasswordResponse> {
+        let client = self.client.clone();
+        Ok(self
+            .spawn(async move {
+                let mut request = ChangePasswordRequest::new(new_password);
+                request.logout_devices = logout_devices;
+                request.auth = auth_info.map(|i| i.as_auth_data());
+
+                client.send(request, None).await
+            })
+            .await?)
+    }
+
+    /// Get the `m.direct` account data content, a map from users to the rooms
+    /// that are direct messages with them.
+    async fn direct_content(
+        client: &Client,
+    ) -> MatrixResult<DirectEventContent> {
+        Ok(client
+            .account()
+            .account_data::<DirectEventContent>()
+            .await?
+            .map(|c| c.deserialize())
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /// Get the mapping from users to the rooms that are direct messages with
+    /// them.
+    pub async fn direct_rooms(
+        &self,
+    ) -> MatrixResult<BTreeMap<OwnedUserId, Vec<OwnedRoomId>>> {
+        let client = self.client.clone();
+
+        self.spawn(
+            async move { Ok(Connection::direct_content(&client).await?.0) },
+        )
+        .await
+    }
+
+    /// Create a new direct message room with the given user, the user gets
+    /// invited and the room is marked as a direct message room.
+    pub async fn create_dm(&self, user_id: OwnedUserId) -> MatrixResult<Room> {
+        let client = self.client.clone();
+
+        self.spawn(async move { client.create_dm(&user_id).await })
+            .await
+    }
+
+    /// Search the user directory of the homeserver.
+    pub async fn search_users(
+        &self,
+        term: String,
+        limit: u64,
+    ) -> MatrixResult<search_users::v3::Response> {
+        let client = self.client.clone();
+
+        self.spawn(async move { Ok(client.search_users(&term, limit).await?) })
+            .await
+    }
+
+    /// Mark or unmark a room as a direct message with the given user.
+    pub async fn set_direct_room(
+        &self,
+        user_id: OwnedUserId,
+        room_id: OwnedRoomId,
+        direct: bool,
+    ) -> MatrixResult<()> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let mut content = Connection::direct_content(&client).await?;
+            let rooms = content.entry(user_id.clone()).or_default();
+
+            if !direct {
+                rooms.retain(|r| *r != room_id);
+            } else if !rooms.contains(&room_id) {
+                rooms.push(room_id);
+            }
+
+            if rooms.is_empty() {
+                content.remove(&user_id);
+            }
+
+            client.account().set_account_data(content).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Fetch a single event of the given room, the event gets decrypted if
+    /// we have the room key for it.
+    pub async fn room_event(
+        &self,
+        room: Room,
+        event_id: OwnedEventId,
+    ) -> MatrixResult<TimelineEvent> {
+        self.spawn(async move { room.event(&event_id).await }).await
+    }
+
     /// Fetch historical messages for the given room.
     pub async fn room_messages(
         &self,
@@ -205,12 +643,57 @@ impl Connection {
         .await
     }
 
+    /// Fetch the latest events of the given room, newest first.
+    pub async fn room_timeline(
+        &self,
+        room: Room,
+        count: usize,
+    ) -> MatrixResult<Messages> {
+        self.spawn(async move {
+            let mut request = MessagesOptions::backward();
+            request.limit = UInt::try_from(count).unwrap_or(UInt::MAX);
+
+            room.messages(request).await
+        })
+        .await
+    }
+
     /// Get the list of our own devices.
     pub async fn devices(&self) -> MatrixResult<DevicesResponse> {
         let client = self.client.clone();
         Ok(self.spawn(async move { client.devices().await }).await?)
     }
 
+    /// Get the spec versions and the capabilities of the homeserver.
+    pub async fn server_capabilities(
+        &self,
+    ) -> MatrixResult<(VersionsResponse, CapabilitiesResponse)> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let versions = client.send(VersionsRequest::new(), None).await?;
+            let capabilities =
+                client.send(CapabilitiesRequest::new(), None).await?;
+
+            Ok::<_, matrix_sdk::Error>((versions, capabilities))
+        })
+        .await
+    }
+
+    /// Measure the round-trip time of a lightweight request to the
+    /// homeserver.
+    pub async fn ping(&self) -> MatrixResult<Duration> {
+        let client = self.client.clone();
+
+        self.spawn(async move {
+            let start = Instant::now();
+            client.whoami().await?;
+
+            Ok::<_, matrix_sdk::Error>(start.elapsed())
+        })
+        .await
+    }
+
     /// Set or reset a typing notice.
     ///
     /// # Arguments
@@ -228,6 +711,197 @@ impl Connection {
             .await
     }
 
+    /// Redact the given event.
+    pub async fn redact(
+        &self,
+        room: Room,
+        event_id: OwnedEventId,
+        reason: Option<String>,
+    ) -> MatrixResult<RedactEventResponse> {
+        Ok(self
+            .spawn(async move {
+                room.redact(&event_id, reason.as_deref(), None).await
+            })
+            .await?)
+    }
+
+    /// Leave the given room.
+    pub async fn leave_room(&self, room: Room) -> MatrixResult<()> {
+        self.spawn(async move { room.leave().await }).await
+    }
+
+    /// Send out a read receipt for the given event.
+    pub async fn send_read_receipt(
+        &self,
+        room: Room,
+        event_id: OwnedEventId,
+    ) -> MatrixResult<()> {
+        self.spawn(async move {
+            room.send_single_receipt(
+                ReceiptType::Read,
+                ReceiptThread::Unthreaded,
+                event_id,
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Mark the room as read up to the given event, this moves the fully read
+    /// marker and sends out a read receipt.
+    pub async fn mark_read(
+        &self,
+        room: Room,
+        event_id: OwnedEventId,
+    ) -> MatrixResult<()> {
+        self.spawn(async move {
+            let receipts = Receipts::new()
+                .fully_read_marker(event_id.clone())
+                .public_read_receipt(event_id);
+
+            room.send_multiple_receipts(receipts).await
+        })
+        .await
+    }
+
+    /// Change the join rule of the given room.
+    pub async fn set_join_rule(
+        &self,
+        room: Room,
+        join_rule: JoinRule,
+    ) -> MatrixResult<()> {
+        self.spawn(async move {
+            room.send_state_event(RoomJoinRulesEventContent::new(join_rule))
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    /// Invite a user to the given room.
+    pub async fn invite(
+        &self,
+        room: Room,
+        user_id: OwnedUserId,
+    ) -> MatrixResult<()> {
+        self.spawn(async move { room.invite_user_by_id(&user_id).await })
+            .await
+    }
+
+    /// Invite a user to the given room, failures are described in a human
+    /// readable way.
+    pub async fn invite_user(
+        &self,
+        room: Room,
+        user_id: OwnedUserId,
+    ) -> Result<(), String> {
+        let member_room = room.clone();
+        let member_id = user_id.clone();
+
+        let (membership, can_invite) = self
+            .spawn(async move {
+                let membership = member_room
+                    .get_member_no_sync(&member_id)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|m| m.membership().clone());
+                let can_invite = member_room
+                    .can_user_invite(member_room.own_user_id())
+                    .await
+                    .unwrap_or(true);
+
+                (membership, can_invite)
+            })
+            .await;
+
+        match membership {
+            Some(MembershipState::Join) => {
+                return Err(format!("{} is already in the room", user_id))
+            }
+            Some(MembershipState::Invite) => {
+                return Err(format!("{} has already been invited", user_id))
+            }
+            Some(MembershipState::Ban) => {
+                return Err(format!(
+                    "{} is banned from the room, unban them first",
+                    user_id
+                ))
+            }
+            _ => (),
+        }
+
+        if !can_invite {
+            return Err("You don't have the permission to invite users to \
+                        this room"
+                .to_owned());
+        }
+
+        // Other reasons for a refused invite, e.g. server ACLs, are only
+        // described by the error message of the server.
+        self.invite(room, user_id.clone()).await.map_err(|e| {
+            match e.client_api_error_kind() {
+                Some(ErrorKind::NotFound) => {
+                    format!("The user {} doesn't exist", user_id)
+                }
+                Some(ErrorKind::InvalidParam) => {
+                    format!("{} isn't a valid user ID", user_id)
+                }
+                Some(ErrorKind::LimitExceeded { .. }) => {
+                    "Too many requests, try again later".to_owned()
+                }
+                _ => format!("Error inviting {}: {}", user_id, e),
+            }
+        })
+    }
+
+    /// Change the power level of a user in the given room.
+    pub async fn set_power_level(
+        &self,
+        room: Room,
+        user_id: OwnedUserId,
+        power_level: i64,
+    ) -> MatrixResult<()> {
+        let power_level = Int::new_saturating(power_level);
+
+        self.spawn(async move {
+            room.update_power_levels(vec![(&user_id, power_level)])
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    /// Kick a user from the given room.
+    pub async fn kick(
+        &self,
+        room: Room,
+        user_id: OwnedUserId,
+        reason: Option<String>,
+    ) -> MatrixResult<()> {
+        self.spawn(
+            async move { room.kick_user(&user_id, reason.as_deref()).await },
+        )
+        .await
+    }
+
+    /// Get the joined members of the given room, fetching them from the
+    /// server if they haven't been synced yet.
+    pub async fn joined_members(
+        &self,
+        room: Room,
+    ) -> MatrixResult<Vec<RoomMember>> {
+        self.spawn(async move { room.members(RoomMemberships::JOIN).await })
+            .await
+    }
+
+    /// Discard the current outbound group session of the given room, the next
+    /// message will be encrypted using a fresh session.
+    pub async fn discard_room_key(&self, room: Room) -> MatrixResult<()> {
+        self.spawn(async move { room.discard_room_key().await })
+            .await
+    }
+
     fn save_device_id(
         user_name: &str,
         mut server_path: PathBuf,
@@ -264,6 +938,39 @@ impl Connection {
         }
     }
 
+    fn session_path(server_path: &Path) -> PathBuf {
+        server_path.join("session.json")
+    }
+
+    /// Store the session in the server directory, the file is only readable
+    /// by us since the session contains our access token.
+    fn save_session(
+        server_path: &Path,
+        session: &MatrixSession,
+    ) -> std::io::Result<()> {
+        let session = serde_json::to_string(session)?;
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(Connection::session_path(server_path))?;
+
+        // The mode is only used if the file gets created, restrict the
+        // permissions of an already existing file as well.
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(session.as_bytes())
+    }
+
+    fn load_session(server_path: &Path) -> Option<MatrixSession> {
+        let session =
+            std::fs::read_to_string(Connection::session_path(server_path))
+                .ok()?;
+
+        serde_json::from_str(&session).ok()
+    }
+
     /// Response receiver loop.
     /// This runs on the main Weechat thread and listens for responses coming
     /// from the client running in the tokio executor.
@@ -281,6 +988,9 @@ impl Connection {
             match message {
                 Ok(message) => match message {
                     ClientMessage::LoginMessage(r) => server.receive_login(r),
+                    ClientMessage::SessionRestored(user_id) => {
+                        server.receive_session(user_id)
+                    }
                     ClientMessage::SyncEvent(r, e) => {
                         server.receive_joined_timeline_event(&r, e).await
                     }
@@ -290,6 +1000,31 @@ impl Connection {
                     ClientMessage::RestoredRoom(room) => {
                         server.restore_room(room).await
                     }
+                    ClientMessage::ConnectionLost(e, delay) => {
+                        server.connection_lost(&e, delay)
+                    }
+                    ClientMessage::ConnectionRestored => {
+                        server.connection_restored()
+                    }
+                    ClientMessage::Synced => server.receive_sync(),
+                    ClientMessage::VerificationRequest(user_id, flow_id) => {
+                        server.receive_verification_request(user_id, flow_id)
+                    }
+                    ClientMessage::Typing(room_id, user_ids) => {
+                        server.receive_typing(&room_id, user_ids).await
+                    }
+                    ClientMessage::Receipts(room_id, content) => {
+                        server.receive_receipts(&room_id, &content)
+                    }
+                    ClientMessage::Presence(user_id, presence) => {
+                        server.receive_presence(&user_id, presence).await
+                    }
+                    ClientMessage::RoomKeysReceived(room_id, session_id) => {
+                        server.receive_room_keys(&room_id, &session_id).await
+                    }
+                    ClientMessage::AccountData(e) => {
+                        server.receive_account_data(e)
+                    }
                     ClientMessage::MemberEvent(
                         room_id,
                         e,
@@ -306,8 +1041,9 @@ impl Connection {
         }
     }
 
+    /// The default filter definition that is used for syncing.
     #[allow(clippy::field_reassign_with_default)]
-    fn sync_filter() -> FilterDefinition {
+    pub fn sync_filter() -> FilterDefinition {
         let mut filter = FilterDefinition::default();
         let mut room_filter = RoomFilter::default();
         let mut event_filter = RoomEventFilter::default();
@@ -319,10 +1055,241 @@ impl Connection {
 
         room_filter.state = event_filter;
         filter.room = room_filter;
+        filter.presence.types = Some(vec!["m.presence".to_owned()]);
 
         filter
     }
 
+    /// Get an unique name for the given filter definition.
+    ///
+    /// The SDK remembers uploaded filters by their name, a changed filter
+    /// definition needs a new name otherwise the old filter would be reused.
+    fn sync_filter_name(filter: &FilterDefinition) -> String {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(filter)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+
+        format!("sync-{:x}", hasher.finish())
+    }
+
+    /// Restore a session that was created using SSO.
+    async fn restore_session(
+        client: &Client,
+        channel: &Sender<Result<ClientMessage, String>>,
+        session: MatrixSession,
+    ) -> Option<()> {
+        let user_id = session.meta.user_id.clone();
+
+        if let Err(e) = client.matrix_auth().restore_session(session).await {
+            let _ = channel
+                .send(Err(format!("Failed to restore the session: {:?}", e)))
+                .await;
+            return None;
+        }
+
+        channel
+            .send(Ok(ClientMessage::SessionRestored(user_id)))
+            .await
+            .ok()
+    }
+
+    /// Find out which user and device an access token belongs to.
+    ///
+    /// The server only answers this for authenticated requests, so a
+    /// temporary client without a persistent store is used, this way the
+    /// crypto store of our real client isn't created for a bogus device.
+    async fn whoami(
+        client: &Client,
+        tokens: MatrixSessionTokens,
+    ) -> Result<SessionMeta, String> {
+        let temporary_client = Client::builder()
+            .homeserver_url(client.homeserver())
+            .build()
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id: owned_user_id!("@whoami:localhost"),
+                device_id: "WHOAMI".into(),
+            },
+            tokens,
+        };
+
+        temporary_client
+            .matrix_auth()
+            .restore_session(session)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        let response = temporary_client
+            .whoami()
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        let device_id = response.device_id.ok_or_else(|| {
+            "The access token doesn't belong to a device".to_owned()
+        })?;
+
+        Ok(SessionMeta {
+            user_id: response.user_id,
+            device_id,
+        })
+    }
+
+    /// Log in using an access token that was obtained outside of the client,
+    /// the username and password are not used in this case.
+    async fn access_token_login(
+        client: &Client,
+        channel: &Sender<Result<ClientMessage, String>>,
+        access_token: &str,
+    ) -> Option<()> {
+        let tokens = MatrixSessionTokens {
+            access_token: access_token.to_owned(),
+            refresh_token: None,
+        };
+
+        let meta = match Connection::whoami(client, tokens.clone()).await {
+            Ok(m) => m,
+            Err(e) => {
+                let _ = channel
+                    .send(Err(format!(
+                        "Failed to look up the owner of the access token: {}",
+                        e
+                    )))
+                    .await;
+                return None;
+            }
+        };
+
+        Connection::restore_session(
+            client,
+            channel,
+            MatrixSession { meta, tokens },
+        )
+        .await
+    }
+
+    /// Log in using a login token we received from a SSO login, the session
+    /// is stored so it can be restored the next time we connect.
+    async fn token_login(
+        client: &Client,
+        channel: &Sender<Result<ClientMessage, String>>,
+        token: &str,
+        server_name: &str,
+        server_path: &Path,
+    ) -> Option<()> {
+        let response = match client
+            .matrix_auth()
+            .login_token(token)
+            .initial_device_display_name("WeeChat-Matrix-rs")
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = channel
+                    .send(Err(format!("Failed to log in: {:?}", e)))
+                    .await;
+                return None;
+            }
+        };
+
+        if let Some(session) = client.matrix_auth().session() {
+            if let Err(e) = Connection::save_session(server_path, &session) {
+                let _ = channel
+                    .send(Err(format!(
+                        "Error while writing the session for server {}: {:?}",
+                        server_name, e
+                    )))
+                    .await;
+                return None;
+            }
+        }
+
+        channel
+            .send(Ok(ClientMessage::LoginMessage(response)))
+            .await
+            .ok()
+    }
+
+    /// Log in using the configured username and password.
+    ///
+    /// Returns if this was the first login of this device, or None if logging
+    /// in failed.
+    async fn password_login(
+        client: &Client,
+        channel: &Sender<Result<ClientMessage, String>>,
+        username: &str,
+        password: &str,
+        server_name: &str,
+        server_path: &Path,
+    ) -> Option<bool> {
+        let device_id =
+            Connection::load_device_id(username, server_path.to_owned());
+
+        let device_id = match device_id {
+            Err(e) => {
+                // TODO: do we want to do something with channel.send()
+                // errors?
+                let _ = channel
+                    .send(Err(format!(
+                        "Error while reading the device id for server {}: {:?}",
+                        server_name, e
+                    )))
+                    .await;
+                return None;
+            }
+            Ok(d) => d,
+        };
+
+        let first_login = device_id.is_none();
+
+        let mut builder = client
+            .matrix_auth()
+            .login_username(username, password)
+            .initial_device_display_name("WeeChat-Matrix-rs");
+
+        if let Some(device_id) = device_id.as_ref() {
+            builder = builder.device_id(device_id);
+        };
+
+        match builder.send().await {
+            Ok(response) => {
+                if let Err(e) = Connection::save_device_id(
+                    username,
+                    server_path.to_owned(),
+                    &response,
+                ) {
+                    let _ = channel
+                        .send(Err(format!(
+                        "Error while writing the device id for server {}: {:?}",
+                        server_name, e
+                    )))
+                        .await;
+                    return None;
+                }
+
+                if channel
+                    .send(Ok(ClientMessage::LoginMessage(response)))
+                    .await
+                    .is_err()
+                {
+                    return None;
+                }
+            }
+            Err(e) => {
+                let _ = channel
+                    .send(Err(format!("Failed to log in: {:?}", e)))
+                    .await;
+                return None;
+            }
+        }
+
+        Some(first_login)
+    }
+
     /// Main client sync loop.
     /// This runs on the per server tokio executor.
     /// It communicates with the main Weechat thread using a async channel.
@@ -333,67 +1300,55 @@ impl Connection {
         password: String,
         server_name: String,
         server_path: PathBuf,
+        filter: FilterDefinition,
+        limits: SyncLimits,
+        login_token: Option<String>,
+        access_token: String,
     ) {
         if !client.logged_in() {
-            let device_id =
-                Connection::load_device_id(&username, server_path.clone());
-
-            let device_id = match device_id {
-                Err(e) => {
-                    // TODO: do we want to do something with channel.send()
-                    // errors?
-                    let _ = channel
-                        .send(Err(format!(
-                        "Error while reading the device id for server {}: {:?}",
-                        server_name, e
-                    )))
-                        .await;
-                    return;
-                }
-                Ok(d) => d,
+            let session = if login_token.is_none() {
+                Connection::load_session(&server_path)
+            } else {
+                None
             };
 
-            let first_login = device_id.is_none();
-
-            let mut builder = client
-                .matrix_auth()
-                .login_username(&username, &password)
-                .initial_device_display_name("WeeChat-Matrix-rs");
-
-            if let Some(device_id) = device_id.as_ref() {
-                builder = builder.device_id(device_id);
+            let first_login = if login_token.is_none()
+                && !access_token.is_empty()
+            {
+                Connection::access_token_login(&client, &channel, &access_token)
+                    .await
+                    .map(|_| false)
+            } else if let Some(session) = session {
+                Connection::restore_session(&client, &channel, session)
+                    .await
+                    .map(|_| false)
+            } else if let Some(token) = login_token {
+                Connection::token_login(
+                    &client,
+                    &channel,
+                    &token,
+                    &server_name,
+                    &server_path,
+                )
+                .await
+                .map(|_| true)
+            } else {
+                Connection::password_login(
+                    &client,
+                    &channel,
+                    &username,
+                    &password,
+                    &server_name,
+                    &server_path,
+                )
+                .await
             };
 
-            match builder.send().await {
-                Ok(response) => {
-                    if let Err(e) = Connection::save_device_id(
-                        &username,
-                        server_path.clone(),
-                        &response,
-                    ) {
-                        let _ = channel
-                            .send(Err(format!(
-                            "Error while writing the device id for server {}: {:?}",
-                            server_name, e
-                        ))).await;
-                        return;
-                    }
-
-                    if channel
-                        .send(Ok(ClientMessage::LoginMessage(response)))
-                        .await
-                        .is_err()
-                    {
-                        return;
-                    }
-                }
-                Err(e) => {
-                    let _ = channel
-                        .send(Err(format!("Failed to log in: {:?}", e)))
-                        .await;
-                    return;
-                }
-            }
+            let first_login = if let Some(f) = first_login {
+                f
+            } else {
+                return;
+            };
 
             if !first_login {
                 for room in client.joined_rooms() {
@@ -408,28 +1363,216 @@ impl Connection {
             }
         }
 
+        let filter_name = Connection::sync_filter_name(&filter);
         let filter = client
-            .get_or_upload_filter("sync", Connection::sync_filter())
+            .get_or_upload_filter(&filter_name, filter)
             .await
             .unwrap();
 
-        let sync_token: Option<String> = None;
-        let sync_settings = SyncSettings::new()
-            .timeout(DEFAULT_SYNC_TIMEOUT)
-            .filter(Filter::FilterId(filter));
-
-        let sync_settings = if let Some(t) = sync_token {
-            sync_settings.token(t)
-        } else {
-            sync_settings
-        };
-
         let sync_channel = &channel;
 
         let client_ref = &client;
 
-        let _ret = client
+        let mut timeout = DEFAULT_SYNC_TIMEOUT;
+        let backoff_builder = ExponentialBuilder::default()
+            .with_min_delay(INITIAL_SYNC_BACKOFF)
+            .with_max_delay(limits.max_backoff)
+            .without_max_times();
+        let mut backoff = backoff_builder.build();
+        let reconnecting = AtomicBool::new(false);
+        let rate_limited = AtomicBool::new(false);
+
+        loop {
+            // The SDK persists the sync token in its state store and restores
+            // it together with the session, so syncing continues where we
+            // left off the last time we were connected.
+            let sync_token = client.sync_token().await;
+            let sync_settings = SyncSettings::new()
+                .timeout(timeout)
+                .filter(Filter::FilterId(filter.clone()));
+
+            let ret = Connection::sync(
+                client_ref,
+                sync_channel,
+                sync_settings,
+                &reconnecting,
+                &rate_limited,
+            )
+            .await;
+
+            let synced = client.sync_token().await != sync_token;
+
+            // The sync only returns successfully if the Weechat side went
+            // away, in that case we're done, or if it stopped to reset the
+            // sync timeout after being rate limited.
+            let (retry_after, error) = match ret {
+                Err(e) => match e.client_api_error_kind() {
+                    Some(ErrorKind::LimitExceeded { retry_after_ms }) => {
+                        (*retry_after_ms, None)
+                    }
+                    // Our access token was rejected, retrying won't help, the
+                    // stored session is useless as well so we need to log in
+                    // again.
+                    Some(ErrorKind::UnknownToken { .. }) => {
+                        let _ = std::fs::remove_file(Connection::session_path(
+                            &server_path,
+                        ));
+                        let _ = channel
+                            .send(Err(format!(
+                                "Failed to log in, the access token was \
+                                 rejected: {}",
+                                e
+                            )))
+                            .await;
+                        return;
+                    }
+                    _ => (None, Some(e)),
+                },
+                Ok(()) if !channel.is_closed() => {
+                    timeout = DEFAULT_SYNC_TIMEOUT;
+                    backoff = backoff_builder.build();
+                    continue;
+                }
+                Ok(()) => break,
+            };
+
+            // If we managed to sync in between the failures, this isn't a
+            // repeated failure, start over with the initial backoff and the
+            // default sync timeout.
+            if synced {
+                backoff = backoff_builder.build();
+                timeout = DEFAULT_SYNC_TIMEOUT;
+            }
+
+            // Wait longer every time the failures keep on coming.
+            let delay = retry_after
+                .unwrap_or_default()
+                .max(backoff.next().unwrap_or(limits.max_backoff));
+
+            if let Some(e) = error {
+                error!(
+                    "Matrix sync for server {} failed, retrying in {:?}: {}",
+                    server_name, delay, e
+                );
+
+                reconnecting.store(true, Ordering::SeqCst);
+
+                if channel
+                    .send(Ok(ClientMessage::ConnectionLost(
+                        e.to_string(),
+                        delay,
+                    )))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            } else {
+                timeout = (timeout * 2).min(limits.max_timeout);
+                rate_limited.store(true, Ordering::SeqCst);
+
+                warn!(
+                    "Server {} is rate limiting our syncs, retrying in {:?} \
+                     with a sync timeout of {:?}",
+                    server_name, delay, timeout
+                );
+            }
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Sync with the server and forward the responses to the Weechat thread,
+    /// until either an error occurs or the Weechat side goes away.
+    ///
+    /// If we were reconnecting, the Weechat side is notified that the
+    /// connection has been restored after the first successful sync.
+    ///
+    /// If we were rate limited, the sync stops after the first successful
+    /// sync, so it can be restarted with the default sync timeout.
+    async fn sync(
+        client: &Client,
+        sync_channel: &Sender<Result<ClientMessage, String>>,
+        sync_settings: SyncSettings,
+        reconnecting: &AtomicBool,
+        rate_limited: &AtomicBool,
+    ) -> MatrixResult<()> {
+        let client_ref = client;
+
+        client
             .sync_with_callback(sync_settings, |response| async move {
+                if reconnecting.swap(false, Ordering::SeqCst)
+                    && sync_channel
+                        .send(Ok(ClientMessage::ConnectionRestored))
+                        .await
+                        .is_err()
+                {
+                    return LoopCtrl::Break;
+                }
+
+                for event in response
+                    .to_device
+                    .iter()
+                    .filter_map(|e| e.deserialize().ok())
+                {
+                    let message = match event {
+                        AnyToDeviceEvent::KeyVerificationRequest(e) => {
+                            ClientMessage::VerificationRequest(
+                                e.sender,
+                                e.content.transaction_id.to_string(),
+                            )
+                        }
+                        // New room keys might allow us to decrypt messages
+                        // that we previously failed to decrypt.
+                        AnyToDeviceEvent::RoomKey(e) => {
+                            ClientMessage::RoomKeysReceived(
+                                e.content.room_id,
+                                e.content.session_id,
+                            )
+                        }
+                        AnyToDeviceEvent::ForwardedRoomKey(e) => {
+                            ClientMessage::RoomKeysReceived(
+                                e.content.room_id,
+                                e.content.session_id,
+                            )
+                        }
+                        _ => continue,
+                    };
+
+                    if sync_channel.send(Ok(message)).await.is_err() {
+                        return LoopCtrl::Break;
+                    }
+                }
+
+                for event in response
+                    .account_data
+                    .iter()
+                    .filter_map(|e| e.deserialize().ok())
+                {
+                    if sync_channel
+                        .send(Ok(ClientMessage::AccountData(event)))
+                        .await
+                        .is_err()
+                    {
+                        return LoopCtrl::Break;
+                    }
+                }
+
+                for event in
+                    response.presence.iter().filter_map(|e| e.deserialize().ok())
+                {
+                    if sync_channel
+                        .send(Ok(ClientMessage::Presence(
+                            event.sender,
+                            event.content.presence,
+                        )))
+                        .await
+                        .is_err()
+                    {
+                        return LoopCtrl::Break;
+                    }
+                }
+
                 for (room_id, room) in response.rooms.join {
                     for event in
                         room.state.iter().filter_map(|e| e.deserialize().ok())
@@ -507,6 +1650,32 @@ impl Connection {
                         }
                     }
 
+                    for event in room
+                        .ephemeral
+                        .iter()
+                        .filter_map(|e| e.deserialize().ok())
+                    {
+                        let message = match event {
+                            AnySyncEphemeralRoomEvent::Typing(e) => {
+                                ClientMessage::Typing(
+                                    room_id.clone(),
+                                    e.content.user_ids,
+                                )
+                            }
+                            AnySyncEphemeralRoomEvent::Receipt(e) => {
+                                ClientMessage::Receipts(
+                                    room_id.clone(),
+                                    e.content,
+                                )
+                            }
+                            _ => continue,
+                        };
+
+                        if sync_channel.send(Ok(message)).await.is_err() {
+                            return LoopCtrl::Break;
+                        }
+                    }
+
                     if let Some(r) = client_ref.get_room(&room_id) {
                         if !r.are_members_synced() {
                             let room_id = room_id.clone();
@@ -540,8 +1709,21 @@ impl Connection {
                     }
                 }
 
+                // Only announce the sync once all of its rooms were forwarded,
+                // this way rooms that were created during the initial sync
+                // aren't mistaken for new ones.
+                if sync_channel.send(Ok(ClientMessage::Synced)).await.is_err() {
+                    return LoopCtrl::Break;
+                }
+
+                // Start over with the default sync timeout once a sync
+                // succeeded after we were rate limited.
+                if rate_limited.swap(false, Ordering::SeqCst) {
+                    return LoopCtrl::Break;
+                }
+
                 LoopCtrl::Continue
             })
-            .await;
+            .await
     }
 }
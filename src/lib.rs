@@ -8,6 +8,7 @@ mod render;
 mod room;
 mod server;
 mod utils;
+mod verification;
 
 use std::{
     cell::{Ref, RefCell},
@@ -15,6 +16,7 @@ use std::{
     rc::Rc,
 };
 
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
 use tokio::runtime::{Handle, Runtime};
 use tracing_subscriber::layer::SubscriberExt;
 
@@ -26,7 +28,8 @@ use weechat::{
 
 use crate::{
     bar_items::BarItems, commands::Commands, completions::Completions,
-    config::ConfigHandle, room::RoomHandle, server::MatrixServer,
+    config::ConfigHandle, connection::FLUSH_TIMEOUT, room::RoomHandle,
+    server::MatrixServer,
 };
 
 const PLUGIN_NAME: &str = "matrix";
@@ -35,6 +38,10 @@ const PLUGIN_NAME: &str = "matrix";
 pub struct Servers {
     inner: Rc<RefCell<HashMap<String, MatrixServer>>>,
     runtime: Handle,
+    unloading: Rc<RefCell<bool>>,
+    /// An index from the name of a room or thread buffer to the server name
+    /// and room ID the buffer belongs to.
+    room_buffers: Rc<RefCell<HashMap<String, (String, OwnedRoomId)>>>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -52,14 +59,6 @@ impl BufferOwner {
             BufferOwner::None => None,
         }
     }
-
-    fn into_room(self) -> Option<RoomHandle> {
-        if let BufferOwner::Room(_, r) = self {
-            Some(r)
-        } else {
-            None
-        }
-    }
 }
 
 impl Servers {
@@ -67,6 +66,8 @@ impl Servers {
         Servers {
             inner: Rc::new(RefCell::new(HashMap::new())),
             runtime: handle,
+            unloading: Rc::new(RefCell::new(false)),
+            room_buffers: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
@@ -78,6 +79,16 @@ impl Servers {
         &self.runtime
     }
 
+    /// Is the plugin being unloaded, closing buffers shouldn't have any side
+    /// effects on the server if so.
+    pub fn is_unloading(&self) -> bool {
+        *self.unloading.borrow()
+    }
+
+    fn set_unloading(&self) {
+        *self.unloading.borrow_mut() = true;
+    }
+
     pub fn is_empty(&self) -> bool {
         self.inner.borrow().is_empty()
     }
@@ -87,7 +98,11 @@ impl Servers {
     }
 
     pub fn clear(&self) {
-        self.inner.borrow_mut().clear();
+        // Dropping the servers closes their buffers, which may trigger
+        // callbacks that look at our servers, so don't hold the borrow while
+        // the servers get dropped.
+        let servers = std::mem::take(&mut *self.inner.borrow_mut());
+        drop(servers);
     }
 
     pub fn insert(&self, server: MatrixServer) {
@@ -104,7 +119,66 @@ impl Servers {
         self.inner.borrow_mut().remove(server_name)
     }
 
+    /// Remember which server and room the room or thread buffer with the given
+    /// name belongs to.
+    pub fn add_room_buffer(
+        &self,
+        buffer_name: &str,
+        server_name: &str,
+        room_id: &RoomId,
+    ) {
+        self.room_buffers.borrow_mut().insert(
+            buffer_name.to_owned(),
+            (server_name.to_owned(), room_id.to_owned()),
+        );
+    }
+
+    /// Forget about a room or thread buffer, should be called when the buffer
+    /// gets closed.
+    pub fn remove_room_buffer(&self, buffer_name: &str) {
+        self.room_buffers.borrow_mut().remove(buffer_name);
+    }
+
+    /// Find the room a buffer belongs to.
+    ///
+    /// Buffers that don't belong to us are rejected after a single local
+    /// variable lookup, room buffers are looked up in the room buffer index
+    /// first. If the index misses we fall back to the server and room ID local
+    /// variables of the buffer.
+    fn room_of_buffer(
+        &self,
+        buffer: &Buffer,
+    ) -> Option<(MatrixServer, RoomHandle)> {
+        let room_id = buffer.get_localvar("room_id")?;
+
+        let indexed = self.room_buffers.borrow().get(&*buffer.name()).cloned();
+
+        let (server_name, room_id) = if let Some(indexed) = indexed {
+            indexed
+        } else {
+            let server_name = buffer.get_localvar("server")?;
+            (server_name.to_string(), RoomId::parse(&*room_id).ok()?)
+        };
+
+        let server = self.get(&server_name)?;
+        let room = server.room(&room_id)?;
+
+        // Thread buffers belong to the room as well, so make sure that this is
+        // the room buffer itself.
+        let room_buffer = room.buffer_handle().upgrade().ok()?;
+
+        if &room_buffer == buffer {
+            Some((server, room))
+        } else {
+            None
+        }
+    }
+
     pub fn buffer_owner(&self, buffer: &Buffer) -> BufferOwner {
+        if let Some((server, room)) = self.room_of_buffer(buffer) {
+            return BufferOwner::Room(server, room);
+        }
+
         let servers = self.borrow();
 
         for server in servers.values() {
@@ -113,16 +187,6 @@ impl Servers {
                     return BufferOwner::Server(server.clone());
                 }
             }
-
-            for room in server.rooms() {
-                let buffer_handle = room.buffer_handle();
-
-                if let Ok(b) = buffer_handle.upgrade() {
-                    if buffer == &b {
-                        return BufferOwner::Room(server.clone(), room);
-                    }
-                }
-            }
         }
 
         BufferOwner::None
@@ -136,25 +200,46 @@ impl Servers {
         self.buffer_owner(buffer).into_server()
     }
 
+    /// Get all the rooms whose buffer is displayed in a window.
+    fn displayed_rooms(&self) -> Vec<RoomHandle> {
+        self.borrow()
+            .values()
+            .flat_map(|server| server.rooms())
+            .filter(|room| room.is_displayed())
+            .collect()
+    }
+
     /// Find a `RoomHandle` that the given buffer belongs to.
     ///
     /// Returns None if the buffer doesn't belong to any of our servers of
     /// rooms.
     pub fn find_room(&self, buffer: &Buffer) -> Option<RoomHandle> {
-        self.buffer_owner(buffer).into_room()
+        self.room_of_buffer(buffer).map(|(_, room)| room)
     }
 }
 
 impl SignalCallback for Servers {
     fn callback(
         &mut self,
-        _: &Weechat,
-        _signal_name: &str,
+        _weechat: &Weechat,
+        signal_name: &str,
         data: Option<SignalData>,
     ) -> ReturnCode {
-        if let Some(SignalData::Buffer(buffer)) = data {
+        // The window scrolled signal carries the scrolled window, but we
+        // aren't handed windows of signals. The scrolled window isn't
+        // necessarily the one of the current buffer, so every room that is
+        // displayed in a window checks the scroll position of its window.
+        if signal_name == "window_scrolled" {
+            for room in self.displayed_rooms() {
+                room.schedule_read_receipt();
+            }
+        } else if let Some(SignalData::Buffer(buffer)) = data {
             if let Some(room) = self.find_room(&buffer) {
-                room.update_typing_notice();
+                if signal_name == "buffer_switch" {
+                    room.buffer_switched();
+                } else {
+                    room.update_typing_notice();
+                }
             }
         }
         ReturnCode::Ok
@@ -162,7 +247,6 @@ impl SignalCallback for Servers {
 }
 
 struct Matrix {
-    #[allow(dead_code)]
     global_runtime: Runtime,
     servers: Servers,
     #[allow(dead_code)]
@@ -173,6 +257,10 @@ struct Matrix {
     #[allow(dead_code)]
     typing_notice_signal: SignalHook,
     #[allow(dead_code)]
+    read_receipt_signal: SignalHook,
+    #[allow(dead_code)]
+    window_scrolled_signal: SignalHook,
+    #[allow(dead_code)]
     completions: Completions,
     debug_buffer: RefCell<Option<BufferHandle>>,
 }
@@ -248,6 +336,11 @@ impl Plugin for Matrix {
 
         let typing = SignalHook::new("input_text_changed", servers.clone())
             .expect("Can't create signal hook for the typing notice cb");
+        let read_receipt = SignalHook::new("buffer_switch", servers.clone())
+            .expect("Can't create signal hook for the read receipt cb");
+        let window_scrolled =
+            SignalHook::new("window_scrolled", servers.clone())
+                .expect("Can't create signal hook for the window scrolled cb");
 
         let plugin = Matrix {
             global_runtime,
@@ -258,6 +351,8 @@ impl Plugin for Matrix {
             completions,
             debug_buffer: RefCell::new(None),
             typing_notice_signal: typing,
+            read_receipt_signal: read_receipt,
+            window_scrolled_signal: window_scrolled,
         };
 
         Weechat::spawn(async move {
@@ -272,14 +367,33 @@ impl Plugin for Matrix {
 
 impl Drop for Matrix {
     fn drop(&mut self) {
+        self.servers.set_unloading();
+
         let servers = self.servers.borrow();
 
-        // Buffer close callbacks get called after this, so disconnect here so
-        // we don't leave all our rooms.
-        //
-        // TODO set a flag on the server as well so we don't even try to leave
-        // the rooms, once leaving the rooms is implemented when the buffer gets
-        // closed.
+        // Give the requests that are still in flight, e.g. messages that are
+        // being sent out, a bounded amount of time to finish before we drop
+        // the connections and with them their runtimes.
+        let flushes: Vec<_> = servers
+            .values()
+            .filter_map(|s| s.connection())
+            .map(|c| c.flush())
+            .collect();
+
+        if !flushes.is_empty() {
+            let _ = self.global_runtime.block_on(tokio::time::timeout(
+                FLUSH_TIMEOUT,
+                async move {
+                    for flush in flushes {
+                        flush.await;
+                    }
+                },
+            ));
+        }
+
+        // Buffer close callbacks get called after this, the unloading flag
+        // makes sure they don't leave the rooms, disconnecting makes sure that
+        // no request goes out either way.
         for server in servers.values() {
             server.disconnect();
         }
@@ -1,28 +1,295 @@
+use std::collections::HashMap;
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local, TimeZone,
+};
+
+use unicode_segmentation::UnicodeSegmentation;
 use url::Url;
 
 use matrix_sdk::ruma::{
     events::{
+        poll::{
+            start::PollStartEventContent,
+            unstable_start::NewUnstablePollStartEventContent,
+        },
         room::{
-            encrypted::RoomEncryptedEventContent,
+            encrypted::{EncryptedEventScheme, RoomEncryptedEventContent},
             member::{MembershipChange, RoomMemberEventContent},
             message::{
                 AudioMessageEventContent, EmoteMessageEventContent,
                 FileMessageEventContent, ImageMessageEventContent,
-                LocationMessageEventContent, NoticeMessageEventContent,
-                RedactedRoomMessageEventContent,
+                LocationMessageEventContent, MessageFormat,
+                NoticeMessageEventContent, RedactedRoomMessageEventContent,
                 ServerNoticeMessageEventContent, TextMessageEventContent,
                 VideoMessageEventContent,
             },
             EncryptedFile, MediaSource,
         },
-        OriginalSyncStateEvent, RedactedSyncMessageLikeEvent,
+        sticker::StickerEventContent,
+        AnySyncStateEvent, OriginalSyncStateEvent,
+        RedactedSyncMessageLikeEvent, SyncStateEvent,
     },
-    uint, EventId, MilliSecondsSinceUnixEpoch, MxcUri, TransactionId, UserId,
+    uint, EventId, MilliSecondsSinceUnixEpoch, MxcUri, OwnedUserId,
+    TransactionId, UserId,
 };
 
 use weechat::{Prefix, Weechat};
 
-use crate::{room::WeechatRoomMember, utils::ToTag};
+use crate::{
+    room::{PollAnswerCount, ReactionCount, WeechatRoomMember},
+    utils::ToTag,
+};
+
+/// When a message should be highlighted because of a specific user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserHighlight {
+    /// Highlight the messages the user sends.
+    Message,
+    /// Highlight the messages that mention the user.
+    Mention,
+    /// Highlight both, the messages of the user and the ones mentioning them.
+    All,
+}
+
+impl UserHighlight {
+    pub fn on_message(self) -> bool {
+        matches!(self, UserHighlight::Message | UserHighlight::All)
+    }
+
+    pub fn on_mention(self) -> bool {
+        matches!(self, UserHighlight::Mention | UserHighlight::All)
+    }
+
+    /// The name of the highlight behavior, as it's used in the config.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UserHighlight::Message => "message",
+            UserHighlight::Mention => "mention",
+            UserHighlight::All => "all",
+        }
+    }
+}
+
+/// Parse a comma separated list of user highlights, every element has the
+/// form `user-id=message|mention|all`.
+pub fn parse_user_highlights(
+    value: &str,
+) -> Result<HashMap<OwnedUserId, UserHighlight>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|element| {
+            let (user_id, highlight) =
+                element.rsplit_once('=').ok_or_else(|| {
+                    format!("Missing the highlight behavior for {}", element)
+                })?;
+
+            let user_id = UserId::parse(user_id.trim())
+                .map_err(|e| format!("Invalid user ID {}: {}", user_id, e))?;
+
+            let highlight = match highlight.trim() {
+                "message" => UserHighlight::Message,
+                "mention" => UserHighlight::Mention,
+                "all" => UserHighlight::All,
+                h => return Err(format!("Invalid highlight behavior {}", h)),
+            };
+
+            Ok((user_id, highlight))
+        })
+        .collect()
+}
+
+/// A power level band of the nicklist, members with at least the given power
+/// level get the prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerPrefix {
+    pub level: i64,
+    pub prefix: char,
+}
+
+impl PowerPrefix {
+    /// The name of the nicklist group of the band, the index makes sure that
+    /// the groups sort from the highest power level to the lowest.
+    pub fn group_name(&self, index: usize) -> String {
+        format!("{:03}|{}", index, self.prefix)
+    }
+}
+
+/// Parse the power prefixes option, a comma separated list of power levels
+/// and the prefix members with at least that power level get, e.g.
+/// `100=&,50=@,1=+`.
+///
+/// The bands are sorted from the highest power level to the lowest.
+pub fn parse_power_prefixes(value: &str) -> Result<Vec<PowerPrefix>, String> {
+    let mut bands = value
+        .split(',')
+        .map(|e| e.trim())
+        .filter(|e| !e.is_empty())
+        .map(|element| {
+            let (level, prefix) = element
+                .split_once('=')
+                .ok_or_else(|| format!("Missing the prefix for {}", element))?;
+
+            let level = level
+                .trim()
+                .parse::<i64>()
+                .map_err(|e| format!("Invalid power level {}: {}", level, e))?;
+
+            let mut chars = prefix.trim().chars();
+
+            let prefix = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => {
+                    return Err(format!(
+                        "The prefix {} isn't a single character",
+                        prefix
+                    ))
+                }
+            };
+
+            Ok(PowerPrefix { level, prefix })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    bands.sort_by(|a, b| b.level.cmp(&a.level));
+
+    if bands.windows(2).any(|w| w[0].level == w[1].level) {
+        return Err("Every power level can only have one prefix".to_owned());
+    }
+
+    // The last group is reserved for the members without a prefix.
+    if bands.len() >= 999 {
+        return Err("Too many power prefixes".to_owned());
+    }
+
+    Ok(bands)
+}
+
+/// Expand the highlight names option into the list of names that should
+/// highlight a message.
+///
+/// The `${displayname}` and `${localpart}` elements get replaced by the given
+/// display name and local part, the display name is skipped if there is none.
+pub fn expand_highlight_names(
+    value: &str,
+    display_name: Option<&str>,
+    localpart: &str,
+) -> Vec<String> {
+    value
+        .split(',')
+        .map(|e| e.trim())
+        .filter_map(|element| match element {
+            "${displayname}" => display_name.map(|n| n.to_owned()),
+            "${localpart}" => Some(localpart.to_owned()),
+            e => Some(e.to_owned()),
+        })
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+/// Does the text mention the given name.
+///
+/// The comparison is case insensitive and the name needs to be a whole word,
+/// e.g. `bob` is mentioned in `hi bob!` but not in `bobcat`.
+pub fn mentions_name(text: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+
+    let text = text.to_lowercase();
+    let name = name.to_lowercase();
+
+    text.match_indices(name.as_str()).any(|(i, m)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + m.len()..].chars().next();
+
+        !before.map_or(false, char::is_alphanumeric)
+            && !after.map_or(false, char::is_alphanumeric)
+    })
+}
+
+/// Expand the placeholders of a message prefix template.
+///
+/// The supported placeholders are `%nick%`, `%userid%` and `%power%`, `%%`
+/// stands for a literal percent sign.
+pub fn expand_prefix_template(
+    template: &str,
+    nick: &str,
+    user_id: &str,
+    power: &str,
+) -> Result<String, String> {
+    if template.contains(|c| c == '\t' || c == '\n') {
+        return Err("The template can't contain tabs or newlines".to_owned());
+    }
+
+    let mut expanded = String::new();
+    let mut placeholder = false;
+
+    // The parts alternate between literal text and placeholder names.
+    for part in template.split('%') {
+        if placeholder {
+            match part {
+                "nick" => expanded.push_str(nick),
+                "userid" => expanded.push_str(user_id),
+                "power" => expanded.push_str(power),
+                "" => expanded.push('%'),
+                p => return Err(format!("Unknown placeholder %{}%", p)),
+            }
+        } else {
+            expanded.push_str(part);
+        }
+
+        placeholder = !placeholder;
+    }
+
+    if placeholder {
+        Ok(expanded)
+    } else {
+        Err("Unterminated placeholder".to_owned())
+    }
+}
+
+/// Expand the placeholders of a nick format.
+///
+/// The supported placeholders are `${prefix}`, `${nick}`, `${mxid}` and
+/// `${userid}`, the values are given as name/value pairs.
+pub fn expand_nick_format(
+    format: &str,
+    values: &[(&str, &str)],
+) -> Result<String, String> {
+    if format.contains(|c| c == '\t' || c == '\n') {
+        return Err("The format can't contain tabs or newlines".to_owned());
+    }
+
+    let mut expanded = String::new();
+    let mut rest = format;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+
+        let placeholder = &rest[start + 2..];
+        let end = placeholder
+            .find('}')
+            .ok_or_else(|| "Unterminated placeholder".to_owned())?;
+        let name = &placeholder[..end];
+
+        let value = values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+            .ok_or_else(|| format!("Unknown placeholder ${{{}}}", name))?;
+
+        expanded.push_str(value);
+        rest = &placeholder[end + 1..];
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
 
 /// The rendered version of an event.
 pub struct RenderedEvent {
@@ -36,6 +303,11 @@ impl RenderedEvent {
     const MSG_TAGS: &'static [&'static str] = &["notify_message"];
     const SELF_TAGS: &'static [&'static str] =
         &["notify_none", "no_highlight", "self_msg"];
+    const THREAD_CONTEXT_TAGS: &'static [&'static str] =
+        &["matrix_thread_context", "notify_none", "no_highlight"];
+    const REPLY_QUOTE_TAGS: &'static [&'static str] =
+        &["matrix_reply_quote", "notify_none", "no_highlight"];
+    const HIGHLIGHT_TAGS: &'static [&'static str] = &["notify_highlight"];
 
     pub fn add_self_tags(self) -> Self {
         self.add_tags(Self::SELF_TAGS)
@@ -45,6 +317,161 @@ impl RenderedEvent {
         self.add_tags(Self::MSG_TAGS)
     }
 
+    /// Put the given, already formatted, time of the event in front of the
+    /// prefix.
+    pub fn add_timestamp(mut self, time: &str) -> Self {
+        self.prefix = format!(
+            "{}{}{} {}",
+            Weechat::color("chat_time"),
+            time,
+            Weechat::color("reset"),
+            self.prefix
+        );
+
+        self
+    }
+
+    /// Turn the event into a continuation of the line above it, the prefix
+    /// gets dropped and the lines get indented.
+    pub fn merge_into_previous(mut self) -> Self {
+        self.prefix = "\t".to_owned();
+
+        for line in &mut self.content.lines {
+            line.message = format!("  {}", line.message);
+            line.tags.push("matrix_merged".to_owned());
+        }
+
+        self
+    }
+
+    /// Does the event notify the user, i.e. is it a message of someone else
+    /// that is new, not one that was fetched from the history.
+    pub fn is_notifying(&self) -> bool {
+        self.content.lines.iter().any(|l| {
+            l.tags.iter().any(|t| {
+                Self::MSG_TAGS.contains(&t.as_str())
+                    || Self::HIGHLIGHT_TAGS.contains(&t.as_str())
+            })
+        })
+    }
+
+    /// Has the event been marked as a highlight.
+    pub fn is_highlighted(&self) -> bool {
+        self.content.lines.iter().any(|l| {
+            l.tags
+                .iter()
+                .any(|t| Self::HIGHLIGHT_TAGS.contains(&t.as_str()))
+        })
+    }
+
+    /// Mark the event as a highlight, the prefix gets the highlight color the
+    /// same way Weechat colors the prefix of highlighted lines.
+    pub fn add_highlight(mut self) -> Self {
+        self.prefix = format!(
+            "{}{}",
+            Weechat::color("chat_highlight"),
+            Weechat::remove_color(&self.prefix)
+        );
+
+        for line in &mut self.content.lines {
+            line.tags.retain(|t| t != "notify_message");
+        }
+
+        self.add_tags(Self::HIGHLIGHT_TAGS)
+    }
+
+    /// Prepend a line showing the parent of a threaded message and indent the
+    /// lines of the message below it.
+    pub fn add_thread_context(mut self, nick: &str, excerpt: &str) -> Self {
+        for (i, line) in self.content.lines.iter_mut().enumerate() {
+            let indent = if i == 0 { "╰ " } else { "  " };
+            line.message = format!("{}{}", indent, line.message);
+        }
+
+        let context = RenderedLine {
+            message: format!(
+                "{}╭ {}: {}{}",
+                Weechat::color("darkgray"),
+                nick,
+                excerpt,
+                Weechat::color("reset"),
+            ),
+            tags: Self::THREAD_CONTEXT_TAGS
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        };
+
+        self.content.lines.insert(0, context);
+
+        self
+    }
+
+    /// Replace the plain text reply fallback of a reply with a single line
+    /// quoting the parent message.
+    pub fn add_reply_quote(
+        mut self,
+        nick: &str,
+        excerpt: &str,
+        color: &str,
+    ) -> Self {
+        let fallback = self
+            .content
+            .lines
+            .iter()
+            .take_while(|l| l.message.starts_with('>') || l.message.is_empty())
+            .count();
+
+        if self
+            .content
+            .lines
+            .first()
+            .map_or(false, |l| l.message.starts_with('>'))
+            && fallback < self.content.lines.len()
+        {
+            self.content.lines.drain(..fallback);
+        }
+
+        // The quote belongs to the message, give it the event and sender tags
+        // of the message so it gets redacted together with it.
+        let message_tags = self
+            .content
+            .lines
+            .first()
+            .map(|l| {
+                l.tags
+                    .iter()
+                    .filter(|t| {
+                        t.starts_with("matrix_id_")
+                            || t.starts_with("matrix_sender_")
+                            || t.starts_with("nick_")
+                            || t.starts_with("prefix_nick_")
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        let quote = RenderedLine {
+            message: format!(
+                "{}> <{}> {}{}",
+                Weechat::color(color),
+                nick,
+                excerpt,
+                Weechat::color("reset"),
+            ),
+            tags: Self::REPLY_QUOTE_TAGS
+                .iter()
+                .map(|t| t.to_string())
+                .chain(message_tags)
+                .collect(),
+        };
+
+        self.content.lines.insert(0, quote);
+
+        self
+    }
+
     fn add_tags(mut self, tags: &[&str]) -> Self {
         for line in &mut self.content.lines {
             line.tags.extend(tags.iter().map(|tag| tag.to_string()))
@@ -106,7 +533,7 @@ pub trait Render {
     }
 
     fn prefix(&self, sender: &WeechatRoomMember) -> String {
-        format!("{}\t", sender.nick_colored())
+        format!("{}\t", sender.prefix_formatted())
     }
 
     /// Render the event.
@@ -186,18 +613,87 @@ impl Render for TextMessageEventContent {
 
     fn render(&self, _: &Self::RenderContext) -> RenderedContent {
         let lines = self
-            .body
+            .render_body()
             .lines()
             .map(|l| RenderedLine {
                 message: l.to_owned(),
                 tags: self.tags(),
             })
             .collect();
-        // TODO: parse and render using the formatted body.
+
         RenderedContent { lines }
     }
 }
 
+/// Render a poll, the question followed by one line for every answer.
+fn render_poll(
+    question: &str,
+    answers: Vec<String>,
+    tags: Vec<String>,
+) -> RenderedContent {
+    let answers: Vec<PollAnswerCount> = answers
+        .into_iter()
+        .map(|text| PollAnswerCount { text, count: 0 })
+        .collect();
+
+    let question = RenderedLine {
+        message: format!(
+            "{}Poll:{} {}",
+            Weechat::color("bold"),
+            Weechat::color("-bold"),
+            question
+        ),
+        tags: tags.clone(),
+    };
+
+    let lines = std::iter::once(question)
+        .chain(render_poll_answers(&answers).into_iter().map(|message| {
+            RenderedLine {
+                message,
+                tags: tags.clone(),
+            }
+        }))
+        .collect();
+
+    RenderedContent { lines }
+}
+
+impl Render for PollStartEventContent {
+    const TAGS: &'static [&'static str] = &["matrix_poll"];
+    type RenderContext = ();
+
+    fn render(&self, _: &Self::RenderContext) -> RenderedContent {
+        let answers = self
+            .poll
+            .answers
+            .iter()
+            .map(|a| a.text.find_plain().unwrap_or_default().to_owned())
+            .collect();
+
+        render_poll(
+            self.poll.question.text.find_plain().unwrap_or_default(),
+            answers,
+            self.tags(),
+        )
+    }
+}
+
+impl Render for NewUnstablePollStartEventContent {
+    const TAGS: &'static [&'static str] = &["matrix_poll"];
+    type RenderContext = ();
+
+    fn render(&self, _: &Self::RenderContext) -> RenderedContent {
+        let answers = self
+            .poll_start
+            .answers
+            .iter()
+            .map(|a| a.text.to_owned())
+            .collect();
+
+        render_poll(&self.poll_start.question.text, answers, self.tags())
+    }
+}
+
 impl Render for EmoteMessageEventContent {
     const TAGS: &'static [&'static str] = &["matrix_emote"];
     type RenderContext = WeechatRoomMember;
@@ -207,16 +703,26 @@ impl Render for EmoteMessageEventContent {
     }
 
     fn render(&self, sender: &Self::RenderContext) -> RenderedContent {
-        // TODO: parse and render using the formatted body.
-        // TODO: handle multiple lines in the body.
-        let message = format!("{} {}", sender.nick(), self.body);
+        let nick = sender.nick();
+        // Indent the continuation lines so they line up with the first line
+        // after the nick.
+        let indent = " ".repeat(nick.graphemes(true).count() + 1);
 
-        let line = RenderedLine {
-            message,
-            tags: self.tags(),
-        };
+        let lines = self
+            .render_body()
+            .split('\n')
+            .enumerate()
+            .map(|(i, l)| RenderedLine {
+                message: if i == 0 {
+                    format!("{} {}", nick, l)
+                } else {
+                    format!("{}{}", indent, l)
+                },
+                tags: self.tags(),
+            })
+            .collect();
 
-        RenderedContent { lines: vec![line] }
+        RenderedContent { lines }
     }
 }
 
@@ -257,12 +763,11 @@ impl Render for NoticeMessageEventContent {
     }
 
     fn render(&self, sender: &Self::RenderContext) -> RenderedContent {
-        // TODO: parse and render using the formatted body.
         let message = format!(
             "{color_notice}Notice\
             {color_delim}({color_reset}{}{color_delim}){color_reset}: {}",
             sender.nick(),
-            self.body,
+            self.render_body(),
             color_notice = Weechat::color("irc.color.notice"),
             color_delim = Weechat::color("chat_delimiters"),
             color_reset = Weechat::color("reset"),
@@ -286,6 +791,8 @@ impl Render for ServerNoticeMessageEventContent {
     }
 
     fn render(&self, sender: &Self::RenderContext) -> RenderedContent {
+        // Server notices don't support a formatted body, so the plain body is
+        // all we have.
         let message = format!(
             "{color_notice}Server notice\
             {color_delim}({color_reset}{}{color_delim}){color_reset}: {}",
@@ -393,35 +900,97 @@ fn mxc_to_emxc(
     Ok(emxc_url.to_string())
 }
 
+/// Render media as a clickable `<body>[url]` line, followed by the caption
+/// if there is one.
+fn render_media(
+    media: &impl HasUrlOrFile,
+    homeserver: &Url,
+    tags: Vec<String>,
+) -> RenderedContent {
+    // Convert MXC to HTTP(s) or EMXC, but fallback to MXC if unable to.
+    let mxc_url = match media.encrypted_file() {
+        Some(encrypted_file) => {
+            mxc_to_emxc(media.resolve_url(), homeserver, &encrypted_file)
+        }
+        None => mxc_to_http(media.resolve_url(), homeserver),
+    }
+    .unwrap_or_else(|_| media.resolve_url().to_string());
+
+    let caption = media.caption();
+    let name = match caption {
+        Some(_) => media.filename().unwrap_or_default(),
+        None => media.body(),
+    };
+
+    let message = format!(
+        "{color_delimiter}<{color_reset}{}{color_delimiter}>\
+            [{color_reset}{}{color_delimiter}]{color_reset}",
+        name,
+        mxc_url,
+        color_delimiter = Weechat::color("color_delimiter"),
+        color_reset = Weechat::color("reset")
+    );
+
+    let mut lines = vec![RenderedLine {
+        message,
+        tags: tags.clone(),
+    }];
+
+    if let Some(caption) = caption {
+        lines.extend(caption.lines().map(|l| RenderedLine {
+            message: l.to_owned(),
+            tags: tags.clone(),
+        }));
+    }
+
+    RenderedContent { lines }
+}
+
 impl<C: HasUrlOrFile> Render for C {
     type RenderContext = Url;
     const TAGS: &'static [&'static str] = &["matrix_media"];
 
     fn render(&self, homeserver: &Self::RenderContext) -> RenderedContent {
-        // Convert MXC to HTTP(s) or EMXC, but fallback to MXC if unable to.
-        let mxc_url = match self.encrypted_file() {
-            Some(encrypted_file) => {
-                mxc_to_emxc(self.resolve_url(), homeserver, &encrypted_file)
-            }
-            None => mxc_to_http(self.resolve_url(), homeserver),
-        }
-        .unwrap_or_else(|_| self.resolve_url().to_string());
+        render_media(self, homeserver, self.tags())
+    }
+}
 
-        let message = format!(
-            "{color_delimiter}<{color_reset}{}{color_delimiter}>\
-                [{color_reset}{}{color_delimiter}]{color_reset}",
-            self.body(),
-            mxc_url,
-            color_delimiter = Weechat::color("color_delimiter"),
-            color_reset = Weechat::color("reset")
-        );
+/// Stickers only carry a plain MXC URL in the spec version we support, wrap
+/// them so they can be rendered like the rest of the media.
+struct Sticker<'a> {
+    content: &'a StickerEventContent,
+}
 
-        let line = RenderedLine {
-            message,
-            tags: self.tags(),
-        };
+impl<'a> From<&'a StickerEventContent> for Sticker<'a> {
+    fn from(content: &'a StickerEventContent) -> Self {
+        Self { content }
+    }
+}
 
-        RenderedContent { lines: vec![line] }
+impl HasUrlOrFile for Sticker<'_> {
+    fn url(&self) -> Option<&MxcUri> {
+        Some(&self.content.url)
+    }
+
+    fn body(&self) -> &str {
+        &self.content.body
+    }
+
+    fn filename(&self) -> Option<&str> {
+        None
+    }
+
+    fn encrypted_file(&self) -> Option<&EncryptedFile> {
+        None
+    }
+}
+
+impl Render for StickerEventContent {
+    type RenderContext = Url;
+    const TAGS: &'static [&'static str] = &["matrix_sticker"];
+
+    fn render(&self, homeserver: &Self::RenderContext) -> RenderedContent {
+        render_media(&Sticker::from(self), homeserver, self.tags())
     }
 }
 
@@ -438,12 +1007,16 @@ impl Render for RoomEncryptedEventContent {
             Weechat::color("reset"),
         );
 
-        let line = RenderedLine {
-            message,
-            // TODO: add tags that allow us decrypt the event at a later point in
-            // time, sender key, algorithm, session id.
-            tags: self.tags(),
-        };
+        // Remember the session the message was encrypted with, so the message
+        // can be found and decrypted once we receive the room key for it.
+        let mut tags = self.tags();
+
+        if let EncryptedEventScheme::MegolmV1AesSha2(c) = &self.scheme {
+            tags.push("matrix_megolm_v1".to_owned());
+            tags.push(session_tag(&c.session_id));
+        }
+
+        let line = RenderedLine { message, tags };
 
         RenderedContent { lines: vec![line] }
     }
@@ -479,10 +1052,20 @@ impl Render for RedactedSyncMessageLikeEvent<RedactedRoomMessageEventContent> {
 trait HasFormattedBody {
     fn body(&self) -> &str;
     fn formatted_body(&self) -> Option<&str>;
+    fn html_body(&self) -> Option<&str>;
     #[inline]
     fn resolve_body(&self) -> &str {
         self.formatted_body().unwrap_or_else(|| self.body())
     }
+
+    /// Render the HTML formatted body if present, else fallback to the
+    /// regular body.
+    fn render_body(&self) -> String {
+        self.html_body()
+            .map(render_html)
+            .filter(|rendered| !rendered.trim().is_empty())
+            .unwrap_or_else(|| self.body().to_owned())
+    }
 }
 
 // Repeating this for each event type would get boring fast so lets use a simple
@@ -500,6 +1083,14 @@ macro_rules! has_formatted_body {
             fn formatted_body(&self) -> Option<&str> {
                 self.formatted.as_ref().map(|f| f.body.as_ref())
             }
+
+            #[inline]
+            fn html_body(&self) -> Option<&str> {
+                self.formatted
+                    .as_ref()
+                    .filter(|f| matches!(f.format, MessageFormat::Html))
+                    .map(|f| f.body.as_ref())
+            }
         }
     };
 }
@@ -511,17 +1102,26 @@ pub trait HasUrlOrFile {
 
     fn body(&self) -> &str;
 
+    fn filename(&self) -> Option<&str>;
+
+    /// The caption of the media, if there is one.
+    ///
+    /// If the filename is set and differs from the body, the body is a
+    /// caption, otherwise the body is the name of the file.
+    fn caption(&self) -> Option<&str> {
+        self.filename()
+            .filter(|f| *f != self.body())
+            .map(|_| self.body())
+    }
+
     #[inline]
     fn resolve_url(&self) -> &MxcUri {
-        match self.source() {
-            MediaSource::Plain(s) => &s,
-            MediaSource::Encrypted(e) => &e.url,
-        }
+        self.url()
+            .or_else(|| self.encrypted_file().map(|e| &*e.url))
+            .expect("Media has neither an URL nor an encrypted file")
     }
 
     fn encrypted_file(&self) -> Option<&EncryptedFile>;
-
-    fn source(&self) -> &MediaSource;
 }
 
 // Same as above: a simple macro to implement the trait for structs with `url`
@@ -533,6 +1133,10 @@ macro_rules! has_url_or_file {
                 &self.body
             }
 
+            fn filename(&self) -> Option<&str> {
+                self.filename.as_deref()
+            }
+
             #[inline]
             fn url(&self) -> Option<&MxcUri> {
                 match &self.source {
@@ -541,10 +1145,6 @@ macro_rules! has_url_or_file {
                 }
             }
 
-            fn source(&self) -> &MediaSource {
-                &self.source
-            }
-
             fn encrypted_file(&self) -> Option<&EncryptedFile> {
                 match &self.source {
                     MediaSource::Encrypted(e) => Some(&e),
@@ -565,61 +1165,713 @@ has_url_or_file!(FileMessageEventContent);
 has_url_or_file!(ImageMessageEventContent);
 has_url_or_file!(VideoMessageEventContent);
 
-/// Rendering implementation for membership events (joins, leaves, bans, profile
-/// changes, etc).
-pub fn render_membership(
-    event: &OriginalSyncStateEvent<RoomMemberEventContent>,
-    sender: &WeechatRoomMember,
-    target: &WeechatRoomMember,
-) -> String {
-    const _TAGS: &[&str] = &["matrix_membership"];
-    use MembershipChange::*;
-    let change_op = event.membership_change();
-
-    let operation = match change_op {
-        None => "did nothing",
-        Error => "caused an error", // must never happen
-        Joined => "has joined the room",
-        Left => "has left the room",
-        Banned => "was banned by",
-        Unbanned => "was unbanned by",
-        Kicked => "was kicked from the room by",
-        Invited => "was invited to the room by",
-        KickedAndBanned => "was kicked and banned by",
-        InvitationRejected => "rejected the invitation",
-        InvitationRevoked => "had the invitation revoked by",
-        ProfileChanged { .. } => "_",
-        _ => "performed an unimplemented operation",
-    };
+/// A token of a HTML formatted body.
+#[derive(Debug, PartialEq)]
+enum HtmlToken {
+    Text(String),
+    Start {
+        name: String,
+        attributes: Vec<(String, String)>,
+    },
+    End(String),
+}
 
-    fn formatted_name(member: &WeechatRoomMember) -> String {
-        match member.display_name() {
-            Some(display_name) => {
-                format!(
-                    "{name} {color_delim}({color_reset}{user_id}{color_delim}){color_reset}",
-                    name = display_name,
-                    user_id = member.user_id(),
-                    color_delim = Weechat::color("chat_delimiters"),
-                    color_reset = Weechat::color("reset"))
-            }
+impl HtmlToken {
+    fn attribute(
+        attributes: &[(String, String)],
+        name: &str,
+    ) -> Option<String> {
+        attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.to_owned())
+    }
+}
 
-            Option::None => member.user_id().to_string(),
+/// Decode a single HTML character reference, without the `&` and `;`.
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let number = entity.strip_prefix('#')?;
+
+            let code = if let Some(hex) = number
+                .strip_prefix('x')
+                .or_else(|| number.strip_prefix('X'))
+            {
+                u32::from_str_radix(hex, 16).ok()?
+            } else {
+                number.parse().ok()?
+            };
+
+            std::char::from_u32(code)
         }
     }
+}
 
-    let (prefix, color_action) = match change_op {
-        Joined => (Prefix::Join, "green"),
-        Banned | ProfileChanged { .. } | Invited => {
-            (Prefix::Network, "magenta")
+/// Replace the HTML character references in the given text.
+///
+/// Unknown references are left as they are.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest
+            .find(';')
+            .and_then(|end| decode_entity(&rest[1..end]).map(|c| (c, end)));
+
+        if let Some((character, end)) = entity {
+            decoded.push(character);
+            rest = &rest[end + 1..];
+        } else {
+            decoded.push('&');
+            rest = &rest[1..];
         }
-        _ => (Prefix::Quit, "red"),
-    };
+    }
 
-    let color_action = Weechat::color(color_action);
-    let color_reset = Weechat::color("reset");
+    decoded.push_str(rest);
 
-    let operation = format!(
-        "{color_action}{op}{color_reset}",
+    decoded
+}
+
+/// Find the end of a tag, ignoring any `>` characters in quoted attributes.
+fn find_tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Parse the inside of a tag, e.g. `a href="https://matrix.org"`.
+fn parse_tag(tag: &str) -> Option<HtmlToken> {
+    let tag = tag.trim();
+
+    if let Some(name) = tag.strip_prefix('/') {
+        let name = name.trim().to_lowercase();
+
+        return if name.is_empty() {
+            None
+        } else {
+            Some(HtmlToken::End(name))
+        };
+    }
+
+    let tag = tag.trim_end_matches('/');
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let name = tag[..name_end].to_lowercase();
+
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+
+    let mut attributes = Vec::new();
+    let mut rest = tag[name_end..].trim_start();
+
+    while !rest.is_empty() {
+        let attribute_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let attribute = rest[..attribute_end].to_lowercase();
+        rest = rest[attribute_end..].trim_start();
+
+        let value = if let Some(value) = rest.strip_prefix('=') {
+            let value = value.trim_start();
+
+            match value.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => {
+                    let value = &value[1..];
+                    let end = value.find(quote).unwrap_or(value.len());
+                    rest = value.get(end + 1..).unwrap_or("");
+                    &value[..end]
+                }
+                _ => {
+                    let end =
+                        value.find(char::is_whitespace).unwrap_or(value.len());
+                    rest = &value[end..];
+                    &value[..end]
+                }
+            }
+        } else {
+            ""
+        };
+
+        if !attribute.is_empty() {
+            attributes.push((attribute, decode_entities(value)));
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Some(HtmlToken::Start { name, attributes })
+}
+
+/// Split a HTML formatted body into a list of tokens.
+///
+/// This is a forgiving tokenizer for the small HTML subset the Matrix spec
+/// allows in formatted bodies, it doesn't try to validate the document.
+fn tokenize_html(html: &str) -> Vec<HtmlToken> {
+    let mut tokens = Vec::new();
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+        } else if let Some(tag) = rest.strip_prefix('<') {
+            if let Some(end) = find_tag_end(tag) {
+                if let Some(token) = parse_tag(&tag[..end]) {
+                    tokens.push(token);
+                }
+
+                rest = &tag[end + 1..];
+            } else {
+                tokens.push(HtmlToken::Text(decode_entities(rest)));
+                rest = "";
+            }
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            tokens.push(HtmlToken::Text(decode_entities(&rest[..end])));
+            rest = &rest[end..];
+        }
+    }
+
+    tokens
+}
+
+/// Strip the reply fallback, the quoted lines of the parent message, from the
+/// plain text body of a reply.
+///
+/// Replies to replies may contain multiple nested quote blocks, possibly
+/// separated by blank lines, all of them are stripped.
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with('>') {
+        return body;
+    }
+
+    let mut rest = body;
+
+    while rest.starts_with('>') || rest.starts_with('\n') {
+        rest = rest.find('\n').map_or("", |end| &rest[end + 1..]);
+    }
+
+    rest
+}
+
+/// Get the `:shortcode:` of a custom emoji from the attributes of its `img`
+/// tag.
+///
+/// Returns `None` if the image isn't marked as an emoticon.
+fn emoticon_shortcode(attributes: &[(String, String)]) -> Option<String> {
+    HtmlToken::attribute(attributes, "data-mx-emoticon")?;
+
+    let name = HtmlToken::attribute(attributes, "alt")
+        .or_else(|| HtmlToken::attribute(attributes, "title"))
+        .map(|n| n.trim().trim_matches(':').to_owned())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "emoticon".to_owned());
+
+    Some(format!(":{}:", name))
+}
+
+/// The state of the HTML renderer, the renderer keeps track of the block
+/// elements that we're in so it can prefix the lines it produces accordingly.
+struct HtmlRenderer {
+    /// Looks up the Weechat color codes of color names.
+    color: fn(&str) -> String,
+    rendered: String,
+    /// Is the current line still empty, the line prefix is only added once
+    /// the line gets some content.
+    line_empty: bool,
+    /// How many `blockquote` tags deep we are.
+    quote_depth: usize,
+    /// The lists we're in, `None` for unordered lists, the number of the next
+    /// list item for ordered ones.
+    lists: Vec<Option<u64>>,
+    /// How many `pre` tags deep we are, whitespace is preserved inside them.
+    preformatted: usize,
+    /// The links we're in, the link target and the position of the link
+    /// text.
+    links: Vec<(Option<String>, usize)>,
+}
+
+impl HtmlRenderer {
+    fn new(color: fn(&str) -> String) -> Self {
+        Self {
+            color,
+            rendered: String::new(),
+            line_empty: true,
+            quote_depth: 0,
+            lists: Vec::new(),
+            preformatted: 0,
+            links: Vec::new(),
+        }
+    }
+
+    fn quote_prefix(&self) -> String {
+        format!(
+            "{}>{} ",
+            (self.color)("matrix-rust.color.quote_fg"),
+            (self.color)("resetcolor")
+        )
+        .repeat(self.quote_depth)
+    }
+
+    /// Add the line prefix if the current line is still empty.
+    fn start_line(&mut self) {
+        if self.line_empty {
+            let prefix = format!(
+                "{}{}",
+                self.quote_prefix(),
+                "  ".repeat(self.lists.len())
+            );
+
+            self.rendered.push_str(&prefix);
+            self.line_empty = false;
+        }
+    }
+
+    fn newline(&mut self) {
+        self.rendered.push('\n');
+        self.line_empty = true;
+    }
+
+    /// Make sure that the next content starts on a new line.
+    fn block(&mut self) {
+        if !self.line_empty {
+            self.newline();
+        }
+    }
+
+    fn push_color(&mut self, color: &str) {
+        let color = (self.color)(color);
+        self.rendered.push_str(&color);
+    }
+
+    fn push_str(&mut self, string: &str) {
+        self.start_line();
+        self.rendered.push_str(string);
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if self.preformatted > 0 {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.newline();
+                }
+
+                if !line.is_empty() {
+                    self.push_str(line);
+                }
+            }
+        } else {
+            let text = text.replace('\n', " ");
+            let text = if self.line_empty {
+                text.trim_start()
+            } else {
+                &text
+            };
+
+            if !text.is_empty() {
+                self.push_str(text);
+            }
+        }
+    }
+
+    fn start_list_item(&mut self) {
+        self.block();
+
+        let marker = match self.lists.last_mut() {
+            Some(Some(number)) => {
+                *number += 1;
+                format!("{}. ", *number - 1)
+            }
+            _ => "• ".to_owned(),
+        };
+
+        let prefix = format!(
+            "{}{}{}",
+            self.quote_prefix(),
+            "  ".repeat(self.lists.len().saturating_sub(1)),
+            marker
+        );
+
+        self.rendered.push_str(&prefix);
+        self.line_empty = false;
+    }
+
+    fn end_link(&mut self) {
+        if let Some((Some(href), start)) = self.links.pop() {
+            let text = Weechat::remove_color(&self.rendered[start..]);
+
+            if text.trim() != href {
+                self.rendered.push_str(&format!(
+                    " {color_delimiter}[{color_reset}{}\
+                     {color_delimiter}]{color_reset}",
+                    href,
+                    color_delimiter = (self.color)("chat_delimiters"),
+                    color_reset = (self.color)("reset")
+                ));
+            }
+        }
+    }
+
+    fn start_tag(&mut self, name: &str, attributes: &[(String, String)]) {
+        match name {
+            "b" | "strong" => self.push_color("bold"),
+            "i" | "em" => self.push_color("italic"),
+            "u" => self.push_color("underline"),
+            "code" => self.push_color("cyan"),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.block();
+                self.push_color("bold");
+            }
+            "pre" => {
+                self.block();
+                self.preformatted += 1;
+                self.push_color("cyan");
+            }
+            "blockquote" => {
+                self.block();
+                self.quote_depth += 1;
+            }
+            "ul" => {
+                self.block();
+                self.lists.push(None);
+            }
+            "ol" => {
+                self.block();
+                let start = HtmlToken::attribute(attributes, "start")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(1);
+                self.lists.push(Some(start));
+            }
+            "li" => self.start_list_item(),
+            "p" | "div" => self.block(),
+            "br" => self.newline(),
+            "img" => {
+                if let Some(shortcode) = emoticon_shortcode(attributes) {
+                    self.push_str(&shortcode);
+                }
+            }
+            "a" => {
+                self.start_line();
+                self.links.push((
+                    HtmlToken::attribute(attributes, "href"),
+                    self.rendered.len(),
+                ))
+            }
+            _ => (),
+        }
+    }
+
+    fn end_tag(&mut self, name: &str) {
+        match name {
+            "b" | "strong" => self.push_color("-bold"),
+            "i" | "em" => self.push_color("-italic"),
+            "u" => self.push_color("-underline"),
+            "code" => self.push_color("resetcolor"),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.push_color("-bold");
+                self.block();
+            }
+            "pre" => {
+                self.push_color("resetcolor");
+                self.preformatted = self.preformatted.saturating_sub(1);
+                self.block();
+            }
+            "blockquote" => {
+                self.block();
+                self.quote_depth = self.quote_depth.saturating_sub(1);
+            }
+            "ul" | "ol" => {
+                self.block();
+                self.lists.pop();
+            }
+            "li" | "p" | "div" => self.block(),
+            "a" => self.end_link(),
+            _ => (),
+        }
+    }
+}
+
+/// Render a HTML formatted body into a string containing Weechat colors.
+///
+/// Tags that we don't know how to render are stripped, their content is kept.
+/// The reply fallback, contained in the `mx-reply` tag, is removed completely.
+fn render_html(html: &str) -> String {
+    render_html_with_colors(html, |color| Weechat::color(color).to_owned())
+}
+
+/// Render a HTML formatted body, the Weechat color codes are looked up using
+/// the given function.
+fn render_html_with_colors(html: &str, color: fn(&str) -> String) -> String {
+    let mut renderer = HtmlRenderer::new(color);
+    let mut reply_depth = 0;
+
+    for token in tokenize_html(html) {
+        match token {
+            HtmlToken::Start { name, .. } if name == "mx-reply" => {
+                reply_depth += 1
+            }
+            HtmlToken::End(name) if name == "mx-reply" => reply_depth -= 1,
+            _ if reply_depth > 0 => (),
+            HtmlToken::Text(text) => renderer.push_text(&text),
+            HtmlToken::Start { name, attributes } => {
+                renderer.start_tag(&name, &attributes)
+            }
+            HtmlToken::End(name) => renderer.end_tag(&name),
+        }
+    }
+
+    renderer.rendered.trim_end_matches('\n').to_owned()
+}
+
+/// Render the answers of a poll together with their votes, one line per
+/// answer, e.g. `  1. Yes [3]`.
+pub fn render_poll_answers(answers: &[PollAnswerCount]) -> Vec<String> {
+    answers
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            format!(
+                "  {}. {} {color_delimiter}[{color_reset}{}{color_delimiter}]{color_reset}",
+                i + 1,
+                a.text,
+                a.count,
+                color_delimiter = Weechat::color("chat_delimiters"),
+                color_reset = Weechat::color("reset")
+            )
+        })
+        .collect()
+}
+
+/// Render the final results of a poll, e.g. `Yes (3), No (1)`.
+///
+/// The answers with the most votes are highlighted.
+pub fn render_poll_results(answers: &[PollAnswerCount]) -> String {
+    let most_votes = answers.iter().map(|a| a.count).max().unwrap_or_default();
+
+    answers
+        .iter()
+        .map(|a| {
+            if a.count == most_votes && most_votes > 0 {
+                format!(
+                    "{}{} ({}){}",
+                    Weechat::color("bold"),
+                    a.text,
+                    a.count,
+                    Weechat::color("-bold")
+                )
+            } else {
+                format!("{} ({})", a.text, a.count)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Format an UNIX timestamp in the local timezone using a strftime style
+/// format, a timestamp of 0 stands for the current time.
+///
+/// Returns None if the format is invalid.
+pub fn format_timestamp(timestamp: i64, format: &str) -> Option<String> {
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+
+    if items.iter().any(|i| matches!(i, Item::Error)) {
+        return None;
+    }
+
+    let time = if timestamp == 0 {
+        Local::now()
+    } else {
+        Local.timestamp_opt(timestamp, 0).single()?
+    };
+
+    Some(time.format_with_items(items.into_iter()).to_string())
+}
+
+/// The tag of the lines of messages that were encrypted with the given Megolm
+/// session and couldn't be decrypted.
+pub fn session_tag(session_id: &str) -> String {
+    format!("matrix_session_id_{}", session_id)
+}
+
+/// The marker that is appended to our own last message once it has been read
+/// by another member.
+pub fn render_read_marker() -> String {
+    format!(
+        "{}✓ read{}",
+        Weechat::color_pair("darkgray", "default"),
+        Weechat::color("reset")
+    )
+}
+
+/// Render a summary of the reactions to an event, e.g. `[👍 3] [❤ 1]`.
+///
+/// The reactions we contributed to are highlighted.
+pub fn render_reactions(reactions: &[ReactionCount]) -> String {
+    reactions
+        .iter()
+        .map(|r| {
+            let color = if r.own {
+                format!(
+                    "{}{}",
+                    Weechat::color("bold"),
+                    Weechat::color("weechat.color.chat_nick_self")
+                )
+            } else {
+                Weechat::color("reset")
+            };
+
+            format!(
+                "{color_delimiter}[{color}{} {}{color_delimiter}]{color_reset}",
+                r.key,
+                r.count,
+                color = color,
+                color_delimiter = Weechat::color("chat_delimiters"),
+                color_reset = Weechat::color("reset")
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn formatted_name(member: &WeechatRoomMember) -> String {
+    match member.display_name() {
+        Some(display_name) => {
+            format!(
+                "{name} {color_delim}({color_reset}{user_id}{color_delim}){color_reset}",
+                name = display_name,
+                user_id = member.user_id(),
+                color_delim = Weechat::color("chat_delimiters"),
+                color_reset = Weechat::color("reset"))
+        }
+
+        Option::None => member.user_id().to_string(),
+    }
+}
+
+/// Rendering implementation for room state changes, e.g. name, topic and
+/// avatar changes or the creation of the room.
+///
+/// Returns `None` if the state event isn't one we know how to render.
+pub fn render_state_change(
+    event: &AnySyncStateEvent,
+    sender: &WeechatRoomMember,
+) -> Option<String> {
+    let operation = match event {
+        AnySyncStateEvent::RoomName(SyncStateEvent::Original(e)) => {
+            if e.content.name.is_empty() {
+                "removed the room name".to_owned()
+            } else {
+                format!("changed the room name to: {}", e.content.name)
+            }
+        }
+        AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(e)) => {
+            if e.content.topic.is_empty() {
+                "removed the topic".to_owned()
+            } else {
+                format!("changed the topic to: {}", e.content.topic)
+            }
+        }
+        AnySyncStateEvent::RoomCreate(SyncStateEvent::Original(e)) => {
+            format!(
+                "created the room (room version {}, {})",
+                e.content.room_version,
+                if e.content.federate {
+                    "federated"
+                } else {
+                    "not federated"
+                }
+            )
+        }
+        AnySyncStateEvent::RoomJoinRules(SyncStateEvent::Original(e)) => {
+            format!(
+                "changed the join rule of the room to: {}",
+                e.content.join_rule.as_str()
+            )
+        }
+        AnySyncStateEvent::RoomAvatar(SyncStateEvent::Original(e)) => {
+            if e.content.url.is_some() {
+                "changed the room avatar".to_owned()
+            } else {
+                "removed the room avatar".to_owned()
+            }
+        }
+        _ => return None,
+    };
+
+    Some(format!(
+        "{prefix}{sender} {color_action}{operation}{color_reset}",
+        prefix = Weechat::prefix(Prefix::Network),
+        sender = formatted_name(sender),
+        color_action = Weechat::color("magenta"),
+        operation = operation,
+        color_reset = Weechat::color("reset")
+    ))
+}
+
+/// Rendering implementation for membership events (joins, leaves, bans, profile
+/// changes, etc).
+pub fn render_membership(
+    event: &OriginalSyncStateEvent<RoomMemberEventContent>,
+    sender: &WeechatRoomMember,
+    target: &WeechatRoomMember,
+) -> String {
+    const _TAGS: &[&str] = &["matrix_membership"];
+    use MembershipChange::*;
+    let change_op = event.membership_change();
+
+    let operation = match change_op {
+        None => "did nothing",
+        Error => "caused an error", // must never happen
+        Joined => "has joined the room",
+        Left => "has left the room",
+        Banned => "was banned by",
+        Unbanned => "was unbanned by",
+        Kicked => "was kicked from the room by",
+        Invited => "was invited to the room by",
+        KickedAndBanned => "was kicked and banned by",
+        InvitationRejected => "rejected the invitation",
+        InvitationRevoked => "had the invitation revoked by",
+        Knocked => "is asking to join the room",
+        KnockAccepted => "was let into the room by",
+        KnockRetracted => "no longer wants to join the room",
+        KnockDenied => "was denied entry into the room by",
+        ProfileChanged { .. } => "_",
+        _ => "performed an unimplemented operation",
+    };
+
+    let (prefix, color_action) = match change_op {
+        Joined => (Prefix::Join, "green"),
+        Banned | ProfileChanged { .. } | Invited | Knocked | KnockAccepted => {
+            (Prefix::Network, "magenta")
+        }
+        _ => (Prefix::Quit, "red"),
+    };
+
+    let color_action = Weechat::color(color_action);
+    let color_reset = Weechat::color("reset");
+
+    let operation = format!(
+        "{color_action}{op}{color_reset}",
         color_action = color_action,
         op = operation,
         color_reset = color_reset
@@ -702,8 +1954,20 @@ pub fn render_membership(
                     "Cannot happen: got profile changed but nothing really changed".to_string()
             }
         }
+        Knocked => format!(
+            "{prefix}{target} {op}{reason}",
+            prefix = Weechat::prefix(prefix),
+            target = target_name,
+            op = operation,
+            reason = event
+                .content
+                .reason
+                .as_ref()
+                .map(|r| format!(": {}", r))
+                .unwrap_or_default()
+        ),
         Banned | Unbanned | Kicked | Invited | InvitationRevoked
-        | KickedAndBanned => format!(
+        | KickedAndBanned | KnockAccepted | KnockDenied => format!(
             "{prefix}{target} {op} {sender}",
             prefix = Weechat::prefix(prefix),
             target = target_name,
@@ -731,6 +1995,347 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(
+            "<b> & \"quoted\" \u{e9}",
+            decode_entities("&lt;b&gt; &amp; &quot;quoted&quot; &#xe9;")
+        );
+        assert_eq!(
+            "Tom & Jerry &unknown;",
+            decode_entities("Tom & Jerry &unknown;")
+        );
+    }
+
+    #[test]
+    fn test_tokenize_html() {
+        let tokens = tokenize_html(
+            "<b>Hello</b><!-- comment --> <a href=\"https://matrix.org/?a=1&amp;b=2\">world</a><br/>",
+        );
+
+        assert_eq!(
+            vec![
+                HtmlToken::Start {
+                    name: "b".to_owned(),
+                    attributes: vec![]
+                },
+                HtmlToken::Text("Hello".to_owned()),
+                HtmlToken::End("b".to_owned()),
+                HtmlToken::Text(" ".to_owned()),
+                HtmlToken::Start {
+                    name: "a".to_owned(),
+                    attributes: vec![(
+                        "href".to_owned(),
+                        "https://matrix.org/?a=1&b=2".to_owned()
+                    )]
+                },
+                HtmlToken::Text("world".to_owned()),
+                HtmlToken::End("a".to_owned()),
+                HtmlToken::Start {
+                    name: "br".to_owned(),
+                    attributes: vec![]
+                },
+            ],
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_tokenize_broken_html() {
+        assert_eq!(
+            vec![HtmlToken::Text("1 < 2".to_owned())],
+            tokenize_html("1 &lt; 2")
+        );
+        assert_eq!(
+            vec![
+                HtmlToken::Text("a ".to_owned()),
+                HtmlToken::Text("<b".to_owned())
+            ],
+            tokenize_html("a <b")
+        );
+    }
+
+    #[test]
+    fn test_strip_reply_fallback() {
+        assert_eq!(
+            strip_reply_fallback(
+                "> <@alice:example.org> Hello\n> there\n\nGeneral Kenobi"
+            ),
+            "General Kenobi"
+        );
+        assert_eq!(strip_reply_fallback("No reply"), "No reply");
+        assert_eq!(
+            strip_reply_fallback("Not a > fallback\n> quote"),
+            "Not a > fallback\n> quote"
+        );
+        assert_eq!(strip_reply_fallback("> Only a quote"), "");
+    }
+
+    #[test]
+    fn test_strip_nested_reply_fallback() {
+        let body = "> <@carol:example.org> > <@bob:example.org> > \
+                    <@alice:example.org> Hi\n\
+                    > > \n\
+                    > > Hello Alice\n\
+                    >\n\
+                    > Hello Bob\n\
+                    \n\
+                    Hello Carol\n\
+                    > not a fallback";
+
+        assert_eq!(strip_reply_fallback(body), "Hello Carol\n> not a fallback");
+        assert_eq!(
+            strip_reply_fallback(
+                "> > > Level 3\n\n> > Level 2\n\n> Level 1\n\nNew"
+            ),
+            "New"
+        );
+    }
+
+    fn render(html: &str) -> String {
+        render_html_with_colors(html, |color| format!("[{}]", color))
+    }
+
+    #[test]
+    fn test_render_html_lists() {
+        assert_eq!(
+            render(
+                "<ul><li>one</li><li>two</li></ul>\
+                 <ol start=\"3\"><li>three</li><li>four</li></ol>"
+            ),
+            "• one\n• two\n3. three\n4. four"
+        );
+        assert_eq!(
+            render("<ul><li>a<ul><li>b</li></ul></li><li>c</li></ul>"),
+            "• a\n  • b\n• c"
+        );
+    }
+
+    #[test]
+    fn test_render_html_blocks() {
+        assert_eq!(
+            render("<blockquote><p>quoted</p></blockquote><p>after</p>"),
+            "[matrix-rust.color.quote_fg]>[resetcolor] quoted\nafter"
+        );
+        assert_eq!(
+            render("<pre><code>fn main() {\n    x\n}</code></pre>"),
+            "[cyan][cyan]fn main() {\n    x\n}[resetcolor][resetcolor]"
+        );
+        assert_eq!(render("<p>Hello\nworld</p>"), "Hello world");
+        assert_eq!(
+            render("<b>bold</b> and <em>italic</em>"),
+            "[bold]bold[-bold] and [italic]italic[-italic]"
+        );
+    }
+
+    #[test]
+    fn test_render_html_strips_reply_fallback() {
+        assert_eq!(
+            render(
+                "<mx-reply><blockquote>In reply to <a \
+                 href=\"https://matrix.to/#/@alice:example.org\">Alice</a>\
+                 </blockquote></mx-reply>General Kenobi"
+            ),
+            "General Kenobi"
+        );
+    }
+
+    #[test]
+    fn test_emoticon_shortcode() {
+        let attributes =
+            |attributes: &[(&str, &str)]| -> Vec<(String, String)> {
+                attributes
+                    .iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect()
+            };
+
+        assert_eq!(
+            emoticon_shortcode(&attributes(&[
+                ("data-mx-emoticon", ""),
+                ("src", "mxc://example.org/abc"),
+                ("alt", ":partyparrot:"),
+            ])),
+            Some(":partyparrot:".to_owned())
+        );
+        assert_eq!(
+            emoticon_shortcode(&attributes(&[
+                ("data-mx-emoticon", ""),
+                ("title", "blobcat"),
+            ])),
+            Some(":blobcat:".to_owned())
+        );
+        assert_eq!(
+            emoticon_shortcode(&attributes(&[("data-mx-emoticon", "")])),
+            Some(":emoticon:".to_owned())
+        );
+        assert_eq!(emoticon_shortcode(&attributes(&[("alt", "A cat")])), None);
+    }
+
+    #[test]
+    fn test_media_caption() {
+        let url = OwnedMxcUri::from("mxc://example.org/some-media-id");
+
+        let mut content = ImageMessageEventContent::new(
+            "cat.png".to_owned(),
+            MediaSource::Plain(url.clone()),
+        );
+        assert_eq!(content.caption(), None);
+
+        content.filename = Some("cat.png".to_owned());
+        assert_eq!(content.caption(), None);
+
+        let mut content = ImageMessageEventContent::new(
+            "Look at my cat".to_owned(),
+            MediaSource::Plain(url),
+        );
+        content.filename = Some("cat.png".to_owned());
+        assert_eq!(content.caption(), Some("Look at my cat"));
+        assert_eq!(content.filename(), Some("cat.png"));
+    }
+
+    #[test]
+    fn test_parse_user_highlights() {
+        let highlights = parse_user_highlights(
+            "@boss:example.org=message, @bot:example.org=mention,\
+             @friend:example.org=all",
+        )
+        .unwrap();
+
+        assert_eq!(highlights.len(), 3);
+        assert_eq!(
+            highlights[&UserId::parse("@boss:example.org").unwrap()],
+            UserHighlight::Message
+        );
+        assert_eq!(
+            highlights[&UserId::parse("@bot:example.org").unwrap()],
+            UserHighlight::Mention
+        );
+        assert_eq!(
+            highlights[&UserId::parse("@friend:example.org").unwrap()],
+            UserHighlight::All
+        );
+
+        assert!(parse_user_highlights("").unwrap().is_empty());
+        assert!(parse_user_highlights("@boss:example.org").is_err());
+        assert!(parse_user_highlights("boss=message").is_err());
+        assert!(parse_user_highlights("@boss:example.org=always").is_err());
+    }
+
+    #[test]
+    fn test_power_prefixes() {
+        let bands = parse_power_prefixes("1=+, 100=&, 50=@").unwrap();
+
+        assert_eq!(
+            bands,
+            vec![
+                PowerPrefix {
+                    level: 100,
+                    prefix: '&'
+                },
+                PowerPrefix {
+                    level: 50,
+                    prefix: '@'
+                },
+                PowerPrefix {
+                    level: 1,
+                    prefix: '+'
+                },
+            ]
+        );
+        assert_eq!(bands[0].group_name(0), "000|&");
+        assert_eq!(bands[2].group_name(2), "002|+");
+
+        assert_eq!(parse_power_prefixes("").unwrap(), vec![]);
+        assert!(parse_power_prefixes("100").is_err());
+        assert!(parse_power_prefixes("admin=&").is_err());
+        assert!(parse_power_prefixes("100=&&").is_err());
+        assert!(parse_power_prefixes("100=&,100=@").is_err());
+    }
+
+    #[test]
+    fn test_highlight_names() {
+        assert_eq!(
+            expand_highlight_names(
+                "${displayname}, ${localpart}, bobby,",
+                Some("Bob Smith"),
+                "bob"
+            ),
+            vec!["Bob Smith", "bob", "bobby"]
+        );
+        assert_eq!(
+            expand_highlight_names("${displayname},${localpart}", None, "bob"),
+            vec!["bob"]
+        );
+
+        assert!(mentions_name("hi bob!", "bob"));
+        assert!(mentions_name("Bob: ping", "bob"));
+        assert!(mentions_name("thanks Bob Smith", "bob smith"));
+        assert!(!mentions_name("a bobcat", "bob"));
+        assert!(!mentions_name("kabob", "bob"));
+        assert!(!mentions_name("hi bob", ""));
+    }
+
+    #[test]
+    fn test_prefix_template() {
+        let expand = |template| {
+            expand_prefix_template(template, "alice", "@alice:example.org", "@")
+        };
+
+        assert_eq!(expand("%nick%").unwrap(), "alice");
+        assert_eq!(expand("%power%%nick%").unwrap(), "@alice");
+        assert_eq!(
+            expand("%nick% (%userid%)").unwrap(),
+            "alice (@alice:example.org)"
+        );
+        assert_eq!(expand("100%% %nick%").unwrap(), "100% alice");
+
+        assert!(expand("%nick").is_err());
+        assert!(expand("%color%").is_err());
+        assert!(expand("%nick%\t").is_err());
+    }
+
+    #[test]
+    fn test_nick_format() {
+        let expand = |format| {
+            expand_nick_format(
+                format,
+                &[
+                    ("prefix", "@"),
+                    ("nick", "alice"),
+                    ("mxid", " (@alice:example.org)"),
+                    ("userid", "@alice:example.org"),
+                ],
+            )
+        };
+
+        assert_eq!(
+            expand("${prefix}${nick}${mxid}").unwrap(),
+            "@alice (@alice:example.org)"
+        );
+        assert_eq!(expand("${nick}").unwrap(), "alice");
+        assert_eq!(
+            expand("${nick} <${userid}>").unwrap(),
+            "alice <@alice:example.org>"
+        );
+        assert_eq!(expand("$nick ${nick}").unwrap(), "$nick alice");
+
+        assert!(expand("${nick").is_err());
+        assert!(expand("${color}").is_err());
+        assert!(expand("${nick}\t").is_err());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        // 2020-09-13, the year is the same in every timezone.
+        assert_eq!(
+            format_timestamp(1_600_000_000, "%Y").as_deref(),
+            Some("2020")
+        );
+        assert!(format_timestamp(0, "%H:%M").is_some());
+        assert!(format_timestamp(1_600_000_000, "%Q").is_none());
+    }
+
     #[test]
     fn test_mxc_to_http() {
         let homeserver = url::Url::parse("https://matrix.org").unwrap();
@@ -1,17 +1,20 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
 
 use dashmap::DashMap;
 use tokio::runtime::Handle;
-use tracing::{error, info};
+use tracing::{error, info, trace};
 
 use matrix_sdk::{
     deserialized_responses::AmbiguityChange,
     room::{Room, RoomMember},
     ruma::{
         events::{
-            room::member::{MembershipState, RoomMemberEventContent},
-            SyncStateEvent,
+            room::member::{
+                MembershipChange, MembershipState, RoomMemberEventContent,
+            },
+            StateEventType, SyncStateEvent,
         },
+        presence::PresenceState,
         uint, OwnedUserId, UserId,
     },
     StoreError,
@@ -22,7 +25,13 @@ use weechat::{
     Prefix, Weechat,
 };
 
-use crate::render::render_membership;
+use crate::{
+    config::Config,
+    render::{
+        expand_nick_format, expand_prefix_template, render_membership,
+        PowerPrefix,
+    },
+};
 
 #[derive(Clone)]
 pub struct Members {
@@ -30,27 +39,188 @@ pub struct Members {
     pub(super) runtime: Handle,
     ambiguity_map: Rc<DashMap<OwnedUserId, bool>>,
     nicks: Rc<DashMap<OwnedUserId, String>>,
+    nick_colors: Rc<DashMap<OwnedUserId, String>>,
+    presence: Rc<DashMap<OwnedUserId, PresenceState>>,
+    nicklist_capped: bool,
+    config: Rc<RefCell<Config>>,
     pub(super) buffer: Rc<RefCell<Option<BufferHandle>>>,
 }
 
+/// The nicklist group of the members that don't have a power prefix.
+pub(super) const NICKLIST_DEFAULT_GROUP: &str = "999|...";
+
+/// The colors of the power prefixes, from the highest band to the lowest.
+const PREFIX_COLORS: &[&str] = &["lightgreen", "lightmagenta", "yellow"];
+
 #[derive(Clone, Debug)]
 pub struct WeechatRoomMember {
     inner: RoomMember,
     color: Rc<String>,
     ambiguous_nick: Rc<bool>,
+    show_user_id: Rc<bool>,
+    show_power: Rc<bool>,
+    prefix_template: Rc<String>,
+    nick_format: Rc<String>,
+    power_prefixes: Rc<Vec<PowerPrefix>>,
 }
 
 impl Members {
-    pub fn new(room: Room, runtime: Handle) -> Self {
+    pub fn new(
+        room: Room,
+        runtime: Handle,
+        config: Rc<RefCell<Config>>,
+        nicklist_capped: bool,
+    ) -> Self {
+        let nick_colors = Self::load_nick_colors(&room, &runtime);
+
         Self {
             room,
             runtime,
             nicks: DashMap::new().into(),
+            nick_colors: nick_colors.into(),
+            presence: DashMap::new().into(),
             ambiguity_map: DashMap::new().into(),
+            nicklist_capped,
+            config,
             buffer: RefCell::new(None).into(),
         }
     }
 
+    /// Does the nicklist only contain members that participate in the room.
+    ///
+    /// This is the case for rooms with more members than the configured
+    /// nicklist member cap.
+    pub fn is_nicklist_capped(&self) -> bool {
+        self.nicklist_capped
+    }
+
+    fn nick_colors_key(room: &Room) -> Vec<u8> {
+        format!("weechat-matrix.nick_colors.{}", room.room_id()).into_bytes()
+    }
+
+    /// Load the nick color overrides of the room from the store.
+    fn load_nick_colors(
+        room: &Room,
+        runtime: &Handle,
+    ) -> DashMap<OwnedUserId, String> {
+        let client = room.client();
+        let key = Self::nick_colors_key(room);
+
+        let colors = runtime.block_on(async move {
+            client.store().get_custom_value(&key).await
+        });
+
+        match colors {
+            Ok(Some(colors)) => {
+                serde_json::from_slice::<BTreeMap<OwnedUserId, String>>(&colors)
+                    .map(|c| c.into_iter().collect())
+                    .unwrap_or_default()
+            }
+            Ok(None) => DashMap::new(),
+            Err(e) => {
+                error!("Error loading the nick colors: {}", e);
+                DashMap::new()
+            }
+        }
+    }
+
+    /// Override the automatically computed nick color of a member.
+    ///
+    /// The override is persisted in the store, passing `None` as the color
+    /// removes the override.
+    pub async fn set_nick_color(
+        &self,
+        user_id: &UserId,
+        color: Option<String>,
+    ) -> Result<(), StoreError> {
+        if let Some(color) = color {
+            self.nick_colors.insert(user_id.to_owned(), color);
+        } else {
+            self.nick_colors.remove(user_id);
+        }
+
+        let colors: BTreeMap<OwnedUserId, String> = self
+            .nick_colors
+            .iter()
+            .map(|c| (c.key().clone(), c.value().clone()))
+            .collect();
+        let colors =
+            serde_json::to_vec(&colors).expect("Can't serialize nick colors");
+
+        let client = self.room.client();
+        let key = Self::nick_colors_key(&self.room);
+
+        self.runtime
+            .spawn(async move {
+                client.store().set_custom_value(&key, colors).await
+            })
+            .await
+            .expect("Storing the nick colors panicked")?;
+
+        if self.nicks.contains_key(user_id) {
+            self.update_member(user_id).await;
+        }
+
+        Ok(())
+    }
+
+    /// Are we allowed to invite users to the room.
+    pub async fn can_invite(&self) -> bool {
+        let room = self.room.clone();
+
+        self.runtime
+            .spawn(async move {
+                room.can_user_invite(room.own_user_id())
+                    .await
+                    .unwrap_or(false)
+            })
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Are we allowed to kick members from the room.
+    pub async fn can_kick(&self) -> bool {
+        let room = self.room.clone();
+
+        self.runtime
+            .spawn(async move {
+                room.can_user_kick(room.own_user_id())
+                    .await
+                    .unwrap_or(false)
+            })
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Are we allowed to change the power levels of the room.
+    pub async fn can_change_power_levels(&self) -> bool {
+        let room = self.room.clone();
+
+        self.runtime
+            .spawn(async move {
+                room.can_user_send_state(
+                    room.own_user_id(),
+                    StateEventType::RoomPowerLevels,
+                )
+                .await
+                .unwrap_or(false)
+            })
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Find the user ID of a member using either their nick or user ID.
+    pub fn resolve_user(&self, user: &str) -> Option<OwnedUserId> {
+        if let Ok(user_id) = UserId::parse(user) {
+            Some(user_id)
+        } else {
+            self.nicks
+                .iter()
+                .find(|n| n.value() == user)
+                .map(|n| n.key().clone())
+        }
+    }
+
     fn buffer(&self) -> BufferHandle {
         self.buffer
             .borrow()
@@ -58,16 +228,62 @@ impl Members {
             .expect("Members struct wasn't initialized properly")
     }
 
+    /// Update the presence of a member, members that are away or offline get
+    /// the away color in the nicklist.
+    pub async fn update_presence(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+    ) {
+        let changed = self
+            .presence
+            .insert(user_id.to_owned(), presence.clone())
+            .map_or(true, |p| p != presence);
+
+        if changed && self.nicks.contains_key(user_id) {
+            self.update_member(user_id).await;
+        }
+    }
+
+    /// Is the member with the given user ID known to be away or offline.
+    fn is_away(&self, user_id: &UserId) -> bool {
+        self.presence.get(user_id).map_or(false, |p| {
+            matches!(*p, PresenceState::Offline | PresenceState::Unavailable)
+        })
+    }
+
     fn add_nick(&self, buffer: &Buffer, member: &WeechatRoomMember) {
         let nick = member.nick();
 
+        let group_name = member.nicklist_group_name();
+
+        // The power prefixes might have changed since the buffer was created,
+        // so the group might need to be created first.
         let group = buffer
-            .search_nicklist_group(member.nicklist_group_name())
+            .search_nicklist_group(&group_name)
+            .or_else(|| {
+                buffer
+                    .add_nicklist_group(
+                        &group_name,
+                        "weechat.color.nicklist_group",
+                        true,
+                        None,
+                    )
+                    .ok()
+            })
             .expect("No group found when adding member");
 
+        let color = if self.config.borrow().look().nicklist_presence()
+            && self.is_away(member.user_id())
+        {
+            "weechat.color.nicklist_away"
+        } else {
+            member.color()
+        };
+
         let nick_settings = NickSettings::new(&nick)
-            .set_color(member.color())
-            .set_prefix(member.nicklist_prefix())
+            .set_color(color)
+            .set_prefix(&member.nicklist_prefix())
             .set_prefix_color(member.prefix_color());
 
         info!("Inserting nick {} for room {}", nick, buffer.short_name());
@@ -84,7 +300,13 @@ impl Members {
         self.nicks.insert(member.user_id().to_owned(), nick);
     }
 
-    pub async fn restore_member(&self, user_id: OwnedUserId) {
+    /// Restore a batch of members from the store and add them to the
+    /// nicklist.
+    ///
+    /// The members of the whole batch are fetched from the store in a single
+    /// go, so restoring a huge room doesn't need a round trip to the runtime
+    /// for every member.
+    pub async fn restore_members(&self, user_ids: Vec<OwnedUserId>) {
         let buffer = self.buffer();
 
         let buffer = if let Ok(b) = buffer.upgrade() {
@@ -94,32 +316,44 @@ impl Members {
         };
 
         let room = self.room.clone();
-        let user = user_id.to_owned();
 
-        match self
+        let members = self
             .runtime
-            .spawn(async move { room.get_member_no_sync(&user).await })
+            .spawn(async move {
+                let mut members = Vec::with_capacity(user_ids.len());
+
+                for user_id in user_ids {
+                    let member = room.get_member_no_sync(&user_id).await;
+                    members.push((user_id, member));
+                }
+
+                members
+            })
             .await
-            .expect("Fetching the room member from the store panicked")
-        {
-            Ok(Some(member)) => {
-                self.ambiguity_map
-                    .insert(user_id.to_owned(), member.name_ambiguous());
-                self.update_member(&user_id).await;
-            }
-            Ok(None) => {
-                panic!(
-                    "Couldn't find member {} in {}",
-                    user_id,
-                    buffer.short_name()
-                )
-            }
-            Err(e) => {
-                Weechat::print(&format!(
-                    "{}: Error fetching a room member from the store: {}",
-                    Weechat::prefix(Prefix::Error),
-                    e.to_string(),
-                ));
+            .expect("Fetching the room members from the store panicked");
+
+        for (user_id, member) in members {
+            match member {
+                Ok(Some(member)) => {
+                    trace!("Restoring member {}", &user_id);
+                    self.ambiguity_map
+                        .insert(user_id.to_owned(), member.name_ambiguous());
+                    self.update_member(&user_id).await;
+                }
+                Ok(None) => {
+                    panic!(
+                        "Couldn't find member {} in {}",
+                        user_id,
+                        buffer.short_name()
+                    )
+                }
+                Err(e) => {
+                    Weechat::print(&format!(
+                        "{}: Error fetching a room member from the store: {}",
+                        Weechat::prefix(Prefix::Error),
+                        e.to_string(),
+                    ));
+                }
             }
         }
     }
@@ -135,6 +369,9 @@ impl Members {
 
         if let Some(nick) = self.nicks.get(user_id) {
             buffer.remove_nick(&nick);
+        } else if self.nicklist_capped && user_id != self.room.own_user_id() {
+            // Members of capped rooms only get added once they speak.
+            return;
         }
 
         let member = self.get(user_id).await.unwrap_or_else(|| {
@@ -172,6 +409,23 @@ impl Members {
         self.update_member(user_id).await;
     }
 
+    /// Add a member that sent a message to the nicklist of a capped room.
+    pub async fn add_speaker(&self, user_id: &UserId) {
+        if !self.nicklist_capped || self.nicks.contains_key(user_id) {
+            return;
+        }
+
+        let buffer = if let Ok(b) = self.buffer().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        if let Some(member) = self.get(user_id).await {
+            self.add_nick(&buffer, &member);
+        }
+    }
+
     /// Remove a Weechat room member by user ID.
     ///
     /// Returns either the removed Weechat room member, or an error if the
@@ -208,15 +462,42 @@ impl Members {
         }
     }
 
-    /// Retrieve a reference to a Weechat room member by user ID.
-    pub async fn get(&self, user_id: &UserId) -> Option<WeechatRoomMember> {
-        let color = if self.room.own_user_id() == user_id {
+    /// Wrap a room member coming from the SDK, so it can be displayed in
+    /// Weechat.
+    pub fn wrap(&self, member: RoomMember) -> WeechatRoomMember {
+        let user_id = member.user_id();
+
+        let color = if let Some(color) = self.nick_colors.get(user_id) {
+            color.clone()
+        } else if self.room.own_user_id() == user_id {
             "weechat.color.chat_nick_self".into()
         } else {
             Weechat::info_get("nick_color_name", user_id.as_str())
                 .expect("Couldn't get the nick color name")
         };
 
+        let ambiguous_nick =
+            self.ambiguity_map.get(user_id).map(|a| *a).unwrap_or(false);
+        let show_user_id = self.config.borrow().look().always_show_user_id();
+        let show_power = self.config.borrow().look().show_power_in_prefix();
+        let prefix_template = self.config.borrow().look().prefix_template();
+        let nick_format = self.config.borrow().look().nick_format();
+        let power_prefixes = self.config.borrow().look().power_prefixes();
+
+        WeechatRoomMember {
+            color: Rc::new(color),
+            ambiguous_nick: Rc::new(ambiguous_nick),
+            show_user_id: Rc::new(show_user_id),
+            show_power: Rc::new(show_power),
+            prefix_template: Rc::new(prefix_template),
+            nick_format: Rc::new(nick_format),
+            power_prefixes: Rc::new(power_prefixes),
+            inner: member,
+        }
+    }
+
+    /// Retrieve a reference to a Weechat room member by user ID.
+    pub async fn get(&self, user_id: &UserId) -> Option<WeechatRoomMember> {
         let room = self.room.clone();
         let user = user_id.to_owned();
 
@@ -226,16 +507,7 @@ impl Members {
             .await
             .expect("Fetching the room member from the store panicked")
         {
-            Ok(m) => m.map(|m| WeechatRoomMember {
-                color: Rc::new(color),
-                ambiguous_nick: Rc::new(
-                    self.ambiguity_map
-                        .get(m.user_id())
-                        .map(|a| *a)
-                        .unwrap_or(false),
-                ),
-                inner: m,
-            }),
+            Ok(m) => m.map(|m| self.wrap(m)),
             Err(e) => {
                 Weechat::print(&format!(
                     "{}: Error fetching a room member from the store: {}",
@@ -251,6 +523,11 @@ impl Members {
         &self.room
     }
 
+    /// Calculate the short name of the room buffer.
+    ///
+    /// The name is based on the display name of the room as the spec defines
+    /// it, i.e. the room name, the canonical alias or a name made out of the
+    /// members, in that order.
     pub fn calculate_buffer_name(&self) -> Result<String, StoreError> {
         let room = self.room();
         let room_name = self.runtime.block_on(room.display_name())?.to_string();
@@ -381,6 +658,26 @@ impl Members {
             let timestamp: i64 =
                 (event.origin_server_ts.0 / uint!(1000)).into();
             buffer.print_date_tags(timestamp as i64, &[], &message);
+
+            if matches!(event.membership_change(), MembershipChange::Knocked)
+                && self.can_invite().await
+            {
+                buffer.print_date_tags(
+                    timestamp as i64,
+                    &[],
+                    &format!(
+                        "{}Use {}/matrix room accept-knock {}{} or \
+                         {}/matrix room reject-knock {}{} to respond",
+                        Weechat::prefix(Prefix::Network),
+                        Weechat::color("bold"),
+                        target_id,
+                        Weechat::color("-bold"),
+                        Weechat::color("bold"),
+                        target_id,
+                        Weechat::color("-bold"),
+                    ),
+                );
+            }
         }
     }
 }
@@ -402,59 +699,128 @@ impl WeechatRoomMember {
         self.inner.name()
     }
 
-    fn nicklist_group_name(&self) -> &str {
-        match self.inner.normalized_power_level() {
-            p if p >= 100 => "000|o",
-            p if p >= 50 => "001|h",
-            p if p > 0 => "002|v",
-            _ => "999|...",
-        }
+    /// The power prefix band the member is in, together with the position of
+    /// the band.
+    fn power_band(&self) -> Option<(usize, &PowerPrefix)> {
+        let level = self.inner.normalized_power_level();
+
+        self.power_prefixes
+            .iter()
+            .enumerate()
+            .find(|(_, band)| level >= band.level)
+    }
+
+    fn nicklist_group_name(&self) -> String {
+        self.power_band()
+            .map(|(index, band)| band.group_name(index))
+            .unwrap_or_else(|| NICKLIST_DEFAULT_GROUP.to_owned())
+    }
+
+    pub fn power_level(&self) -> i64 {
+        self.inner.normalized_power_level()
     }
 
-    fn nicklist_prefix(&self) -> &str {
+    /// A human readable name of the standard power level band the member is
+    /// in.
+    pub fn power_level_name(&self) -> &str {
         match self.inner.normalized_power_level() {
-            p if p >= 100 => "&",
-            p if p >= 50 => "@",
-            p if p > 0 => "+",
-            _ => " ",
+            p if p >= 100 => "Administrator",
+            p if p >= 50 => "Moderator",
+            p if p > 0 => "Voice",
+            _ => "User",
         }
     }
 
-    fn prefix(&self) -> &str {
-        self.nicklist_prefix().trim()
+    /// The prefix that shows the power level of the member in the nicklist,
+    /// colored with the color of the power level band.
+    pub fn prefix_colored(&self) -> String {
+        format!(
+            "{}{}{}",
+            Weechat::color(self.prefix_color()),
+            self.prefix(),
+            Weechat::color("reset")
+        )
+    }
+
+    fn nicklist_prefix(&self) -> String {
+        self.power_band()
+            .map(|(_, band)| band.prefix.to_string())
+            .unwrap_or_else(|| " ".to_owned())
+    }
+
+    fn prefix(&self) -> String {
+        self.nicklist_prefix().trim().to_owned()
     }
 
     fn prefix_color(&self) -> &str {
-        match self.prefix() {
-            "&" => "lightgreen",
-            "@" => "lightmagenta",
-            "+" => "yellow",
-            _ => "default",
-        }
+        self.power_band()
+            .and_then(|(index, _)| PREFIX_COLORS.get(index))
+            .copied()
+            .unwrap_or("default")
     }
 
+    /// The colored nick of the member, formatted using the nick format
+    /// option.
     pub fn nick_colored(&self) -> String {
-        if *self.ambiguous_nick {
-            // TODO: this should color the parenthesis differently.
-            format!(
-                "{}{}{} ({})",
-                Weechat::color(&self.color()),
-                self.nick_raw(),
-                Weechat::color("reset"),
-                self.user_id(),
+        let nick = format!(
+            "{}{}{}",
+            Weechat::color(self.color()),
+            self.nick_raw(),
+            Weechat::color("reset")
+        );
+
+        // Ambiguous nicks are disambiguated by the plain user ID and don't get
+        // a power level sign.
+        let (prefix, mxid) = if *self.ambiguous_nick {
+            (String::new(), format!(" ({})", self.user_id()))
+        } else if *self.show_user_id {
+            (
+                self.power_badge(),
+                format!(
+                    " {}({}){}",
+                    Weechat::color("darkgray"),
+                    self.user_id(),
+                    Weechat::color("reset")
+                ),
             )
         } else {
-            format!(
-                "{}{}{}{}{}",
-                Weechat::color(self.prefix_color()),
-                self.prefix(),
-                Weechat::color(self.color()),
-                self.nick_raw(),
-                Weechat::color("reset")
-            )
+            (self.power_badge(), String::new())
+        };
+
+        expand_nick_format(
+            &self.nick_format,
+            &[
+                ("prefix", &prefix),
+                ("nick", &nick),
+                ("mxid", &mxid),
+                ("userid", self.user_id().as_str()),
+            ],
+        )
+        .unwrap_or_else(|_| format!("{}{}{}", prefix, nick, mxid))
+    }
+
+    /// The colored power level sign that is shown in front of the nick, empty
+    /// if the show_power_in_prefix option is disabled.
+    fn power_badge(&self) -> String {
+        if *self.show_power {
+            self.prefix_colored()
+        } else {
+            String::new()
         }
     }
 
+    /// The prefix of the messages of the member, formatted using the prefix
+    /// template option.
+    pub fn prefix_formatted(&self) -> String {
+        expand_prefix_template(
+            &self.prefix_template,
+            &self.nick_colored(),
+            self.user_id().as_str(),
+            &self.power_badge(),
+        )
+        .unwrap_or_else(|_| self.nick_colored())
+    }
+
     pub fn nick(&self) -> String {
         if *self.ambiguous_nick {
             format!("{} ({})", self.nick_raw(), self.user_id())
@@ -23,22 +23,33 @@
 //! decrypt a previously undecryptable event.
 
 mod members;
+mod polls;
+mod reactions;
+mod thread;
 
-use members::Members;
 pub use members::WeechatRoomMember;
-use tokio::runtime::Handle;
-use tracing::{debug, trace};
+use members::{Members, NICKLIST_DEFAULT_GROUP};
+pub use polls::PollAnswerCount;
+use polls::Polls;
+pub use reactions::ReactionCount;
+use reactions::Reactions;
+use thread::RoomThread;
+use tracing::debug;
 
 use std::{
     borrow::Cow,
     cell::RefCell,
     collections::HashMap,
+    convert::TryFrom,
+    future::Future,
     ops::Deref,
+    path::PathBuf,
     rc::Rc,
     sync::{
         atomic::{AtomicBool, Ordering},
         Mutex, MutexGuard,
     },
+    time::{Duration, Instant},
 };
 
 use unicode_segmentation::UnicodeSegmentation;
@@ -49,21 +60,33 @@ use matrix_sdk::{
     deserialized_responses::AmbiguityChange,
     room::Room,
     ruma::{
+        api::client::error::ErrorKind,
         events::{
+            poll::unstable_start::UnstablePollStartEventContent,
+            reaction::{ReactionEventContent, SyncReactionEvent},
+            receipt::{ReceiptEventContent, ReceiptThread, ReceiptType},
+            relation::{Annotation, Replacement},
             room::{
+                guest_access::GuestAccess,
+                history_visibility::HistoryVisibility,
+                join_rules::{AllowRule, JoinRule, Restricted},
                 member::RoomMemberEventContent,
                 message::{
-                    MessageType, RoomMessageEventContent,
+                    MessageType, Relation, RoomMessageEventContent,
+                    RoomMessageEventContentWithoutRelation,
                     TextMessageEventContent,
                 },
                 redaction::SyncRoomRedactionEvent,
             },
-            AnyMessageLikeEventContent, AnySyncMessageLikeEvent,
-            AnySyncStateEvent, AnySyncTimelineEvent, AnyTimelineEvent,
-            OriginalSyncMessageLikeEvent, SyncMessageLikeEvent, SyncStateEvent,
+            AnyMessageLikeEvent, AnyMessageLikeEventContent,
+            AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent,
+            AnyTimelineEvent, OriginalSyncMessageLikeEvent,
+            SyncMessageLikeEvent, SyncStateEvent,
         },
-        EventId, MilliSecondsSinceUnixEpoch, OwnedRoomAliasId,
-        OwnedTransactionId, RoomId, TransactionId, UserId,
+        presence::PresenceState,
+        uint, DeviceKeyAlgorithm, EventId, MilliSecondsSinceUnixEpoch,
+        OwnedEventId, OwnedRoomAliasId, OwnedTransactionId, OwnedUserId,
+        RoomId, TransactionId, UserId,
     },
     StoreError,
 };
@@ -73,17 +96,41 @@ use weechat::{
         Buffer, BufferBuilderAsync, BufferHandle, BufferInputCallbackAsync,
         BufferLine, LineData,
     },
-    Weechat,
+    Prefix, Task, Weechat,
 };
 
 use crate::{
     config::{Config, RedactionStyle},
     connection::Connection,
-    render::{Render, RenderedEvent},
-    utils::{Edit, ToTag},
-    PLUGIN_NAME,
+    render::{
+        expand_highlight_names, format_timestamp, mentions_name,
+        render_poll_answers, render_poll_results, render_reactions,
+        render_read_marker, render_state_change, session_tag,
+        strip_reply_fallback, Render, RenderedEvent,
+    },
+    server::{DeviceTrust, InnerServer},
+    utils::{format_age, guess_mime_type, via_servers, Edit, ToTag},
+    Servers, PLUGIN_NAME,
 };
 
+/// How long the buffer needs to stay unscrolled before a read receipt is sent.
+const READ_RECEIPT_DELAY: Duration = Duration::from_secs(2);
+
+/// How long to wait between the batches of older messages that are fetched
+/// by /matrix room history.
+const HISTORY_BATCH_DELAY: Duration = Duration::from_millis(500);
+
+/// How many members are added to the nicklist at once when a room is
+/// restored.
+const MEMBER_RESTORE_BATCH_SIZE: usize = 200;
+
+/// How many servers are suggested by /matrix room via.
+const VIA_SERVER_COUNT: usize = 3;
+
+/// How long a typing notice is shown if the server doesn't tell us that the
+/// user stopped typing.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Clone)]
 pub struct RoomHandle {
     inner: MatrixRoom,
@@ -156,10 +203,40 @@ pub struct MatrixRoom {
 
     messages_in_flight: IntMutex,
     prev_batch: Rc<RefCell<Option<PrevBatch>>>,
+    last_event_id: Rc<RefCell<Option<OwnedEventId>>>,
+    /// The timestamp and the formatted time of the newest message that got a
+    /// timestamp.
+    last_timestamp: Rc<RefCell<Option<(i64, String)>>>,
+    /// The sender and event ID of the last printed message that a notice
+    /// can be merged into.
+    last_message: Rc<RefCell<Option<(OwnedUserId, OwnedEventId)>>>,
+    typing_since: Rc<RefCell<Option<Instant>>>,
+    read_receipt: Rc<RefCell<Option<OwnedEventId>>>,
+    read_receipt_generation: Rc<RefCell<u64>>,
+    read_marker: Rc<RefCell<Option<OwnedEventId>>>,
+    unread: Rc<RefCell<UnreadCounts>>,
+    typing_users: Rc<RefCell<Vec<(OwnedUserId, String)>>>,
+    typing_generation: Rc<RefCell<u64>>,
+    raw_toggled: Rc<RefCell<HashMap<OwnedEventId, Vec<String>>>>,
+    threads: Rc<RefCell<HashMap<OwnedEventId, RoomThread>>>,
+    /// The tasks that were spawned for the room and didn't finish yet, they
+    /// get cancelled when the room buffer is closed.
+    tasks: Rc<RefCell<HashMap<u64, Task<()>>>>,
+    task_counter: Rc<RefCell<u64>>,
 
     outgoing_messages: MessageQueue,
 
     members: Members,
+    reactions: Reactions,
+    polls: Polls,
+}
+
+/// The number of messages, and how many of them are highlights, that arrived
+/// in a room since it was last read.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UnreadCounts {
+    pub messages: usize,
+    pub highlights: usize,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -194,12 +271,61 @@ impl MessageQueue {
     ) -> Option<(bool, RoomMessageEventContent)> {
         self.queue.borrow_mut().remove(uuid)
     }
+
+    /// Get a snapshot of the queued messages.
+    ///
+    /// Returns the transaction id, if the message was echoed, and the content
+    /// of every message that is waiting for a response from the server.
+    fn entries(
+        &self,
+    ) -> Vec<(OwnedTransactionId, bool, RoomMessageEventContent)> {
+        self.queue
+            .borrow()
+            .iter()
+            .map(|(id, (echo, content))| (id.clone(), *echo, content.clone()))
+            .collect()
+    }
+
+    fn clear(&self) -> usize {
+        let mut queue = self.queue.borrow_mut();
+        let len = queue.len();
+        queue.clear();
+
+        len
+    }
+}
+
+/// Get a short human readable description of an error that happened while
+/// sending a message.
+fn send_error_label(error: &matrix_sdk::Error) -> String {
+    use matrix_sdk::ruma::api::client::error::ErrorKind;
+
+    match error.client_api_error_kind() {
+        Some(ErrorKind::Forbidden { .. }) => "forbidden".to_owned(),
+        Some(ErrorKind::LimitExceeded { .. }) => "rate limited".to_owned(),
+        Some(ErrorKind::TooLarge { .. }) => "too large".to_owned(),
+        Some(ErrorKind::UnknownToken { .. }) => "not logged in".to_owned(),
+        Some(kind) => format!("server error {}", kind),
+        None => match error {
+            matrix_sdk::Error::Http(_) => "network error".to_owned(),
+            e => e.to_string(),
+        },
+    }
+}
+
+/// Create a short single line excerpt of a message.
+fn excerpt(message: &str) -> String {
+    message
+        .replace('\n', " ")
+        .graphemes(true)
+        .take(40)
+        .collect()
 }
 
 impl RoomHandle {
     pub fn new(
         server_name: &str,
-        runtime: Handle,
+        servers: &Servers,
         connection: &Rc<RefCell<Option<Connection>>>,
         config: Rc<RefCell<Config>>,
         room: Room,
@@ -207,7 +333,18 @@ impl RoomHandle {
         room_id: &RoomId,
         own_user_id: &UserId,
     ) -> Self {
-        let members = Members::new(room.clone(), runtime.clone());
+        let runtime = servers.runtime().to_owned();
+        let member_cap = config.borrow().look().nicklist_member_cap();
+        let member_count = room.joined_members_count();
+        let nicklist_capped =
+            member_cap > 0 && member_count > member_cap as u64;
+
+        let members = Members::new(
+            room.clone(),
+            runtime.clone(),
+            config.clone(),
+            nicklist_capped,
+        );
 
         let own_nick = runtime
             .block_on(room.get_member_no_sync(own_user_id))
@@ -224,11 +361,27 @@ impl RoomHandle {
             prev_batch: Rc::new(RefCell::new(
                 room.last_prev_batch().map(PrevBatch::Backwards),
             )),
+            last_event_id: Rc::new(RefCell::new(None)),
+            last_timestamp: Rc::new(RefCell::new(None)),
+            last_message: Rc::new(RefCell::new(None)),
+            typing_since: Rc::new(RefCell::new(None)),
+            read_receipt: Rc::new(RefCell::new(None)),
+            read_receipt_generation: Rc::new(RefCell::new(0)),
+            read_marker: Rc::new(RefCell::new(None)),
+            unread: Rc::new(RefCell::new(UnreadCounts::default())),
+            typing_users: Rc::new(RefCell::new(Vec::new())),
+            typing_generation: Rc::new(RefCell::new(0)),
+            raw_toggled: Rc::new(RefCell::new(HashMap::new())),
+            threads: Rc::new(RefCell::new(HashMap::new())),
+            tasks: Rc::new(RefCell::new(HashMap::new())),
+            task_counter: Rc::new(RefCell::new(0)),
             own_user_id: own_user_id.into(),
             members: members.clone(),
             buffer: members.buffer,
             outgoing_messages: MessageQueue::new(),
             messages_in_flight: IntMutex::new(),
+            reactions: Reactions::new(),
+            polls: Polls::new(),
             room,
         };
 
@@ -236,10 +389,24 @@ impl RoomHandle {
 
         let buffer_handle = BufferBuilderAsync::new(&buffer_name)
             .input_callback(room.clone())
-            .close_callback(|_weechat: &Weechat, _buffer: &Buffer| {
-                // TODO: remove the roombuffer from the server here.
-                // TODO: leave the room if the plugin isn't unloading.
-                Ok(())
+            .close_callback({
+                let servers = servers.clone();
+                let server_name = server_name.to_owned();
+                let room_id = room_id.to_owned();
+                let buffer_name = buffer_name.clone();
+                let room = room.clone();
+
+                move |_weechat: &Weechat, _buffer: &Buffer| {
+                    room.abort_tasks();
+                    servers.remove_room_buffer(&buffer_name);
+
+                    if !servers.is_unloading() {
+                        if let Some(server) = servers.get(&server_name) {
+                            server.room_buffer_closed(&room_id);
+                        }
+                    }
+                    Ok(())
+                }
             })
             .build()
             .expect("Can't create new room buffer");
@@ -248,38 +415,26 @@ impl RoomHandle {
             .upgrade()
             .expect("Can't upgrade newly created buffer");
 
-        buffer
-            .add_nicklist_group(
-                "000|o",
-                "weechat.color.nicklist_group",
-                true,
-                None,
-            )
-            .expect("Can't create nicklist group");
-        buffer
-            .add_nicklist_group(
-                "001|h",
-                "weechat.color.nicklist_group",
-                true,
-                None,
-            )
-            .expect("Can't create nicklist group");
-        buffer
-            .add_nicklist_group(
-                "002|v",
-                "weechat.color.nicklist_group",
-                true,
-                None,
-            )
-            .expect("Can't create nicklist group");
-        buffer
-            .add_nicklist_group(
-                "999|...",
-                "weechat.color.nicklist_group",
-                true,
-                None,
-            )
-            .expect("Can't create nicklist group");
+        servers.add_room_buffer(&buffer_name, server_name, room_id);
+
+        let power_prefixes = room.config.borrow().look().power_prefixes();
+
+        let group_names = power_prefixes
+            .iter()
+            .enumerate()
+            .map(|(index, band)| band.group_name(index))
+            .chain(std::iter::once(NICKLIST_DEFAULT_GROUP.to_owned()));
+
+        for group_name in group_names {
+            buffer
+                .add_nicklist_group(
+                    &group_name,
+                    "weechat.color.nicklist_group",
+                    true,
+                    None,
+                )
+                .expect("Can't create nicklist group");
+        }
 
         buffer.enable_nicklist();
         buffer.disable_nicklist_groups();
@@ -307,12 +462,23 @@ impl RoomHandle {
 
         *room.members.buffer.borrow_mut() = Some(buffer_handle.clone());
 
+        room.update_buffer_name();
+
+        if nicklist_capped {
+            buffer.set_localvar("nicklist_capped", "1");
+            room.print_network(&format!(
+                "This room has {} members, only members that speak will be \
+                 added to the nicklist",
+                member_count
+            ));
+        }
+
         Self { inner: room }
     }
 
     pub async fn restore(
         server_name: &str,
-        runtime: Handle,
+        servers: &Servers,
         room: Room,
         connection: &Rc<RefCell<Option<Connection>>>,
         config: Rc<RefCell<Config>>,
@@ -323,9 +489,10 @@ impl RoomHandle {
         let own_user_id = room.own_user_id();
         let prev_batch = room.last_prev_batch();
 
+        let runtime = servers.runtime().to_owned();
         let room_buffer = Self::new(
             server_name,
-            runtime.clone(),
+            servers,
             connection,
             config,
             room_clone,
@@ -336,21 +503,59 @@ impl RoomHandle {
 
         debug!("Restoring room {}", room.room_id());
 
-        let matrix_members = runtime
-            .spawn(async move { room.joined_user_ids().await })
+        let print_summary =
+            room_buffer.config.borrow().look().restore_summary();
+
+        let (matrix_members, last_active) = runtime
+            .spawn(async move {
+                let members = room.joined_user_ids().await?;
+
+                // Timeline events aren't kept in the store, the newest read
+                // receipt of the members tells us when the room was last
+                // active instead.
+                let mut last_active = None;
+
+                if print_summary {
+                    for user_id in &members {
+                        if let Ok(Some((_, receipt))) = room
+                            .user_receipt(
+                                ReceiptType::Read,
+                                ReceiptThread::Unthreaded,
+                                user_id,
+                            )
+                            .await
+                        {
+                            last_active = last_active.max(receipt.ts);
+                        }
+                    }
+                }
+
+                Ok::<_, StoreError>((members, last_active))
+            })
             .await
             .expect("Couldn't get the joined user ids")?;
 
-        for user_id in matrix_members {
-            trace!("Restoring member {}", &user_id);
-            room_buffer.members.restore_member(user_id).await;
-        }
-
         *room_buffer.prev_batch.borrow_mut() =
             prev_batch.map(PrevBatch::Forward);
 
         room_buffer.update_buffer_name();
         room_buffer.set_topic();
+        room_buffer.set_guest_access();
+        room_buffer.set_create_info();
+
+        if print_summary {
+            room_buffer.print_summary(last_active);
+        }
+
+        let user_ids: Vec<OwnedUserId> = matrix_members
+            .into_iter()
+            .filter(|u| {
+                !room_buffer.members.is_nicklist_capped()
+                    || **u == *room_buffer.own_user_id
+            })
+            .collect();
+
+        room_buffer.restore_members(user_ids);
 
         Ok(room_buffer)
     }
@@ -369,11 +574,72 @@ impl BufferInputCallbackAsync for MatrixRoom {
             ))
         };
 
-        self.send_message(content).await;
+        self.spawn_task(|room| async move { room.send_message(content).await });
     }
 }
 
 impl MatrixRoom {
+    /// Spawn a task on behalf of the room.
+    ///
+    /// The task is created by calling `task` with a clone of the room. It's
+    /// cancelled if the room buffer gets closed before the task finishes, so
+    /// it doesn't keep on fetching or sending things for a room nobody looks
+    /// at anymore.
+    pub fn spawn_task<T, F>(&self, task: T)
+    where
+        T: FnOnce(MatrixRoom) -> F,
+        F: Future<Output = ()> + 'static,
+    {
+        let future = task(self.clone());
+
+        let id = {
+            let mut counter = self.task_counter.borrow_mut();
+            *counter += 1;
+            *counter
+        };
+
+        let tasks = Rc::downgrade(&self.tasks);
+
+        let task = Weechat::spawn(async move {
+            future.await;
+
+            if let Some(tasks) = tasks.upgrade() {
+                let task = tasks.borrow_mut().remove(&id);
+                drop(task);
+            }
+        });
+
+        self.tasks.borrow_mut().insert(id, task);
+    }
+
+    /// Cancel all the tasks of the room that are still running.
+    pub fn abort_tasks(&self) {
+        let tasks: Vec<Task<()>> =
+            self.tasks.borrow_mut().drain().map(|(_, t)| t).collect();
+
+        // The tasks are dropped outside of the borrow, dropping a task drops
+        // its future as well.
+        drop(tasks);
+    }
+
+    /// Add the given members to the nicklist in the background.
+    ///
+    /// Every batch of members is restored in its own task, Weechat gets to
+    /// process its main loop between the batches, so restoring a room with
+    /// a huge number of members doesn't freeze the UI.
+    fn restore_members(&self, user_ids: Vec<OwnedUserId>) {
+        for batch in user_ids.chunks(MEMBER_RESTORE_BATCH_SIZE) {
+            let batch = batch.to_vec();
+
+            self.spawn_task(|room| async move {
+                room.members.restore_members(batch).await;
+
+                // The display name of the room might depend on the members.
+                room.update_buffer_name();
+            });
+        }
+    }
+
     pub fn is_encrypted(&self) -> bool {
         self.members
             .runtime
@@ -392,6 +658,22 @@ impl MatrixRoom {
         self.room.is_public()
     }
 
+    pub fn is_guest_accessible(&self) -> bool {
+        matches!(self.room.guest_access(), GuestAccess::CanJoin)
+    }
+
+    /// Mark the buffer as a private or a channel buffer, depending on whether
+    /// the room is a direct message room.
+    pub fn update_buffer_type(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            if self.is_direct() {
+                buffer.set_localvar("type", "private")
+            } else {
+                buffer.set_localvar("type", "channel")
+            }
+        }
+    }
+
     pub fn is_direct(&self) -> bool {
         self.members
             .runtime
@@ -418,6 +700,22 @@ impl MatrixRoom {
     fn print_rendered_event(&self, rendered: RenderedEvent) {
         let buffer = self.buffer_handle();
 
+        // Messages that arrive while the room is being looked at were already
+        // read, they don't count as unread.
+        let displayed =
+            buffer.upgrade().map_or(false, |b| b.window().is_some());
+
+        if rendered.is_notifying() && !displayed {
+            let mut unread = self.unread.borrow_mut();
+            unread.messages += 1;
+
+            if rendered.is_highlighted() {
+                unread.highlights += 1;
+            }
+
+            Weechat::bar_item_update("matrix_unread");
+        }
+
         if let Ok(buffer) = buffer.upgrade() {
             for line in rendered.content.lines {
                 let message = format!("{}{}", &rendered.prefix, &line.message);
@@ -441,6 +739,15 @@ impl MatrixRoom {
             return;
         };
 
+        if let Some(redacts) = &event.redacts {
+            if let Some(target) = self.reactions.target(redacts) {
+                let old_summary = self.reactions.summary(&target);
+                self.reactions.remove(redacts);
+                self.update_reaction_summary(&target, &old_summary);
+                return;
+            }
+        }
+
         let buffer_handle = self.buffer_handle();
 
         let buffer = if let Ok(b) = buffer_handle.upgrade() {
@@ -594,10 +901,74 @@ impl MatrixRoom {
                 ),
                 _ => return None,
             },
+            Sticker(c) => c.render_with_prefix(
+                send_time,
+                event_id,
+                &sender,
+                &self.homeserver,
+            ),
+            PollStart(c) => {
+                c.render_with_prefix(send_time, event_id, sender, &())
+            }
+            UnstablePollStart(UnstablePollStartEventContent::New(c)) => {
+                c.render_with_prefix(send_time, event_id, sender, &())
+            }
             _ => return None,
         };
 
-        Some(rendered)
+        Some(self.add_timestamp(rendered))
+    }
+
+    /// Put the time of the event in front of the prefix if a timestamp format
+    /// is configured.
+    ///
+    /// If only changes of the timestamp should be shown, a time that is the
+    /// same as the one of the previous message is replaced by blanks. Older
+    /// messages, e.g. ones that were fetched from the history, always get the
+    /// full timestamp since they don't follow the previous message.
+    fn add_timestamp(&self, rendered: RenderedEvent) -> RenderedEvent {
+        let format = self.config.borrow().look().timestamp_format().to_string();
+
+        let time = if format.is_empty() {
+            return rendered;
+        } else if let Some(time) =
+            format_timestamp(rendered.message_timestamp, &format)
+        {
+            time
+        } else {
+            return rendered;
+        };
+
+        if !self.config.borrow().look().timestamp_changes_only() {
+            return rendered.add_timestamp(&time);
+        }
+
+        // Local echoes don't have a timestamp yet, they are sent out now.
+        let timestamp = if rendered.message_timestamp == 0 {
+            chrono::Utc::now().timestamp()
+        } else {
+            rendered.message_timestamp
+        };
+
+        let mut last_timestamp = self.last_timestamp.borrow_mut();
+
+        match &*last_timestamp {
+            Some((last, _)) if timestamp < *last => {
+                rendered.add_timestamp(&time)
+            }
+            Some((_, last_time)) if *last_time == time => {
+                let blank = " ".repeat(time.chars().count());
+                *last_timestamp = Some((timestamp, time));
+
+                rendered.add_timestamp(&blank)
+            }
+            _ => {
+                let rendered = rendered.add_timestamp(&time);
+                *last_timestamp = Some((timestamp, time));
+
+                rendered
+            }
+        }
     }
 
     async fn render_sync_message(
@@ -612,26 +983,115 @@ impl MatrixRoom {
 
         if let Some(content) = event.original_content() {
             let send_time = event.origin_server_ts();
-            self.render_message_content(
-                event.event_id(),
-                send_time,
-                &sender,
-                &content,
-            )
-            .await
-            .map(|r| {
-                // TODO: the tags are different if the room is a DM.
-                if sender.user_id() == &*self.own_user_id {
-                    r.add_self_tags()
-                } else {
-                    r.add_msg_tags()
-                }
-            })
+            let rendered = self
+                .render_message_content(
+                    event.event_id(),
+                    send_time,
+                    &sender,
+                    &content,
+                )
+                .await?;
+
+            // TODO: the tags are different if the room is a DM.
+            if sender.user_id() == &*self.own_user_id {
+                Some(rendered.add_self_tags())
+            } else {
+                let rendered = self
+                    .apply_user_highlights(event, rendered.add_msg_tags())
+                    .await;
+
+                Some(self.apply_name_highlights(event, rendered).await)
+            }
         } else {
             self.render_redacted_event(event).await
         }
     }
 
+    /// Highlight the message if the user highlights option asks for it,
+    /// either because of who sent it or because it mentions one of the
+    /// configured users.
+    async fn apply_user_highlights(
+        &self,
+        event: &AnySyncMessageLikeEvent,
+        rendered: RenderedEvent,
+    ) -> RenderedEvent {
+        let highlights = self.config.borrow().look().user_highlights();
+
+        if highlights
+            .get(event.sender())
+            .map_or(false, |h| h.on_message())
+        {
+            return rendered.add_highlight();
+        }
+
+        let mentioned_ids = match event.original_content() {
+            Some(AnyMessageLikeEventContent::RoomMessage(c)) => {
+                c.mentions.map(|m| m.user_ids).unwrap_or_default()
+            }
+            _ => Default::default(),
+        };
+
+        let text: Vec<String> = rendered
+            .content
+            .lines
+            .iter()
+            .map(|l| Weechat::remove_color(&l.message))
+            .collect();
+        let text = text.join("\n");
+
+        for (user_id, _) in highlights.iter().filter(|(_, h)| h.on_mention()) {
+            let mentioned = mentioned_ids.contains(user_id)
+                || text.contains(user_id.as_str())
+                || self
+                    .members
+                    .get(user_id)
+                    .await
+                    .and_then(|m| m.display_name().map(|n| text.contains(n)))
+                    .unwrap_or_default();
+
+            if mentioned {
+                return rendered.add_highlight();
+            }
+        }
+
+        rendered
+    }
+
+    /// Highlight the message if its body mentions one of the names of the
+    /// highlight names option, e.g. our display name.
+    async fn apply_name_highlights(
+        &self,
+        event: &AnySyncMessageLikeEvent,
+        rendered: RenderedEvent,
+    ) -> RenderedEvent {
+        if rendered.is_highlighted() {
+            return rendered;
+        }
+
+        let content = match event.original_content() {
+            Some(AnyMessageLikeEventContent::RoomMessage(c)) => c,
+            _ => return rendered,
+        };
+
+        // The reply fallback quotes the parent, names mentioned in the parent
+        // shouldn't highlight the reply. The formatted body isn't looked at,
+        // the plain body already contains the names mentioned in it.
+        let body = strip_reply_fallback(content.body());
+
+        let own_member = self.members.get(&self.own_user_id).await;
+        let names = expand_highlight_names(
+            &self.config.borrow().look().highlight_names(),
+            own_member.as_ref().and_then(|m| m.display_name()),
+            self.own_user_id.localpart(),
+        );
+
+        if names.iter().any(|name| mentions_name(body, name)) {
+            rendered.add_highlight()
+        } else {
+            rendered
+        }
+    }
+
     // Add the content of the message to our outgoing message queue and print out
     // a local echo line if local echo is enabled.
     async fn queue_outgoing_message(
@@ -639,16 +1099,34 @@ impl MatrixRoom {
         transaction_id: &TransactionId,
         content: &RoomMessageEventContent,
     ) {
-        if self.config.borrow().look().local_echo() {
+        let is_thread_reply =
+            matches!(content.relates_to, Some(Relation::Thread(_)));
+
+        if self.config.borrow().look().local_echo() && !is_thread_reply {
             if let MessageType::Text(c) = &content.msgtype {
                 let sender =
                     self.members.get(&self.own_user_id).await.unwrap_or_else(
                         || panic!("No own member {}", self.own_user_id),
                     );
 
-                let local_echo = c
-                    .render_with_prefix_for_echo(&sender, transaction_id, &())
+                let local_echo = self
+                    .add_timestamp(c.render_with_prefix_for_echo(
+                        &sender,
+                        transaction_id,
+                        &(),
+                    ))
                     .add_self_tags();
+
+                // Show the message we're replying to, so the echo looks the
+                // same as the event we'll receive from the server.
+                let local_echo = if let Some(Relation::Reply { in_reply_to }) =
+                    &content.relates_to
+                {
+                    self.quote_parent(&in_reply_to.event_id, local_echo)
+                } else {
+                    local_echo
+                };
+
                 self.print_rendered_event(local_echo);
 
                 self.outgoing_messages
@@ -682,6 +1160,10 @@ impl MatrixRoom {
     /// buffer.send_message(content).await
     /// ```
     pub async fn send_message(&self, content: RoomMessageEventContent) {
+        if !self.safe_to_send() {
+            return;
+        }
+
         let transaction_id = TransactionId::new();
 
         let connection = self.connection.borrow().clone();
@@ -700,10 +1182,9 @@ impl MatrixRoom {
                     self.handle_outgoing_message(&transaction_id, &r.event_id)
                         .await;
                 }
-                Err(_e) => {
-                    // TODO: print out an error, remember to modify the local
-                    // echo line if there is one.
+                Err(e) => {
                     self.outgoing_messages.remove(&transaction_id);
+                    self.mark_failed_echo(&transaction_id, &e);
                 }
             }
         } else if let Ok(buffer) = self.buffer_handle().upgrade() {
@@ -711,356 +1192,2804 @@ impl MatrixRoom {
         }
     }
 
-    /// Send out a typing notice.
-    ///
-    /// This will send out a typing notice or reset the one in progress, if
-    /// needed. It will make sure that only one typing notice request is in
-    /// flight at a time.
-    ///
-    /// Typing notices are sent out only if we have more than 4 letters in the
-    /// input and the input isn't a command.
+    /// Check if sending to the room is allowed by the safe mode, prints an
+    /// error if it isn't.
     ///
-    /// If the input is empty the typing notice is disabled.
-    pub fn update_typing_notice(&self) {
-        let buffer_handle = self.buffer_handle();
+    /// In safe mode, messages are only sent to encrypted rooms in which all
+    /// the devices are verified.
+    fn safe_to_send(&self) -> bool {
+        if !self.config.borrow().network().safe_mode() {
+            return true;
+        }
 
-        let buffer = if let Ok(b) = buffer_handle.upgrade() {
-            b
+        let problem = if !self.is_encrypted() {
+            "the room isn't encrypted"
+        } else if !self.contains_only_verified_devices() {
+            "the room contains unverified devices"
         } else {
-            return;
+            return true;
         };
 
-        let input = buffer.input();
+        self.print_error(&format!(
+            "Safe mode is on and {}, the message wasn't sent, use \
+             /matrix safe-mode off to disable it",
+            problem
+        ));
 
-        if input.starts_with('/') && !input.starts_with("//") {
-            // Don't send typing notices for commands.
+        false
+    }
+
+    /// Upload a file and send it to the room.
+    pub async fn upload(&self, path: PathBuf, caption: Option<String>) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        if !self.safe_to_send() {
             return;
         }
 
-        let connection = self.connection.clone();
-        let room = self.room().clone();
+        let content_type = guess_mime_type(&path);
+        self.print_network(&format!(
+            "Uploading {} ({})...",
+            path.display(),
+            content_type
+        ));
 
-        let send = |typing: bool| async move {
-            let connection = connection.borrow().clone();
+        match connection
+            .send_attachment(
+                self.room().clone(),
+                path.clone(),
+                content_type,
+                caption,
+            )
+            .await
+        {
+            Ok(_) => {
+                self.print_network(&format!("Uploaded {}", path.display()))
+            }
+            Err(e) => self.print_error(&format!(
+                "Error uploading {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
 
-            if let Some(connection) = connection {
-                let _ = connection.send_typing_notice(room, typing).await;
-            };
+    /// Attach the reason why sending a message failed to its local echo, or
+    /// print out an error if the message wasn't echoed.
+    fn mark_failed_echo(
+        &self,
+        transaction_id: &TransactionId,
+        error: &matrix_sdk::Error,
+    ) {
+        let label = send_error_label(error);
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
         };
 
-        if input.len() < 4 {
-            // If we have an active typing notice and our input is short, e.g.
-            // we removed the input set the typing notice to false.
-            Weechat::spawn(send(false)).detach();
-        } else if input.len() >= 4 {
-            // If we have some valid input and no active typing notice, send
-            // one out.
-            Weechat::spawn(send(true)).detach();
+        let echo_tag = Cow::from(format!("matrix_echo_{}", transaction_id));
+        let line = buffer
+            .lines()
+            .filter(|l| l.tags().contains(&echo_tag))
+            .last();
+
+        if let Some(line) = line {
+            line.set_message(&format!(
+                "{} {}<{}failed: {}{}>{}",
+                line.message(),
+                Weechat::color("chat_delimiters"),
+                Weechat::color("red"),
+                label,
+                Weechat::color("chat_delimiters"),
+                Weechat::color("reset"),
+            ));
+        } else {
+            self.print_error(&format!("Error sending message: {}", label));
         }
     }
 
-    pub fn is_busy(&self) -> bool {
-        self.messages_in_flight.locked()
-    }
+    /// Get the ID of the message at the given offset, counting from the newest
+    /// message in the buffer which has the offset 1.
+    pub fn event_id_at_offset(&self, offset: usize) -> Option<OwnedEventId> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let mut event_ids: Vec<OwnedEventId> = Vec::new();
 
-    pub fn reset_prev_batch(&self) {
-        // TODO: we'll want to be able to scroll up again after we clear the
-        // buffer.
-        *self.prev_batch.borrow_mut() = None;
-    }
+        for line in buffer.lines() {
+            let tags = line.tags();
 
-    pub async fn get_messages(&self) {
-        let messages_lock = self.messages_in_flight.clone();
+            if tags
+                .iter()
+                .any(|t| t == "matrix_state" || t == "matrix_redacted")
+            {
+                continue;
+            }
 
-        let connection = self.connection.borrow().as_ref().cloned();
+            let event_id = tags
+                .iter()
+                .find_map(|t| t.strip_prefix("matrix_id_"))
+                .and_then(|e| EventId::parse(e).ok());
 
-        let prev_batch =
-            if let Some(p) = self.prev_batch.borrow().as_ref().cloned() {
-                p
-            } else {
-                return;
-            };
+            if let Some(event_id) = event_id {
+                if event_ids.last() != Some(&event_id) {
+                    event_ids.push(event_id);
+                }
+            }
+        }
 
-        let guard = if let Ok(l) = messages_lock.try_lock() {
-            l
+        event_ids.into_iter().rev().nth(offset.checked_sub(1)?)
+    }
+
+    /// Toggle the message at the given offset between its rendered form and
+    /// its raw content.
+    ///
+    /// The raw content is shown on the first line of the message, the other
+    /// lines of the message are emptied until the message is toggled back.
+    /// The raw content is fetched from the server, so it doesn't need to be
+    /// kept around for every message.
+    pub async fn toggle_raw(&self, offset: usize) {
+        let event_id = if let Some(e) = self.event_id_at_offset(offset) {
+            e
         } else {
+            self.print_error(&format!("No message found at offset {}", offset));
             return;
         };
 
-        Weechat::bar_item_update("buffer_modes");
-        Weechat::bar_item_update("matrix_modes");
+        let rendered = self.raw_toggled.borrow_mut().remove(&event_id);
 
-        if let Some(connection) = connection {
-            let room = self.room().clone();
+        let raw = if rendered.is_some() {
+            None
+        } else if let Some(raw) = self
+            .fetch_message(&event_id)
+            .await
+            .and_then(|e| e.original_content())
+            .and_then(|c| serde_json::to_string(&c).ok())
+        {
+            Some(raw)
+        } else {
+            self.print_error(
+                "The raw content of the message at the given offset couldn't \
+                 be fetched",
+            );
+            return;
+        };
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let event_id_tag = Cow::from(event_id.to_tag());
+        let lines: Vec<BufferLine> = buffer
+            .lines()
+            .filter(|l| l.tags().contains(&event_id_tag))
+            .collect();
+
+        if let Some(rendered) = rendered {
+            for (line, message) in lines.iter().zip(rendered) {
+                line.set_message(&message);
+            }
+        } else if let Some(raw) = raw {
+            let rendered =
+                lines.iter().map(|l| l.message().to_string()).collect();
+
+            for (i, line) in lines.iter().enumerate() {
+                line.set_message(if i == 0 { &raw } else { "" });
+            }
+
+            self.raw_toggled.borrow_mut().insert(event_id, rendered);
+        }
+    }
+
+    /// React with the given key to the message at the given offset, or remove
+    /// our reaction if we already reacted with the same key.
+    pub async fn toggle_reaction(&self, offset: usize, key: &str) {
+        let target = if let Some(t) = self.event_id_at_offset(offset) {
+            t
+        } else {
+            self.print_error(&format!("No message found at offset {}", offset));
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let result =
+            if let Some(reaction) = self.reactions.own_reaction(&target, key) {
+                connection
+                    .redact(self.room().clone(), reaction, None)
+                    .await
+                    .map(|_| ())
+            } else {
+                if !self.safe_to_send() {
+                    return;
+                }
+
+                let content = ReactionEventContent::new(Annotation::new(
+                    target,
+                    key.to_owned(),
+                ));
+
+                connection
+                    .send_message(
+                        self.room().clone(),
+                        AnyMessageLikeEventContent::Reaction(content),
+                        None,
+                    )
+                    .await
+                    .map(|_| ())
+            };
+
+        if let Err(e) = result {
+            self.print_error(&format!("Error toggling the reaction: {}", e));
+        }
+    }
+
+    /// Find the number of lines, counting from the bottom of the buffer, up to
+    /// and including the first line of the given event.
+    fn lines_from_bottom(&self, event_id: &EventId) -> Option<usize> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let event_id_tag = Cow::from(event_id.to_tag());
+        let lines: Vec<_> = buffer.lines().collect();
+
+        let position = lines
+            .iter()
+            .position(|l| l.tags().contains(&event_id_tag))?;
+
+        Some(lines.len() - position)
+    }
+
+    /// Scroll the buffer to the message the message at the given offset is
+    /// replying to.
+    ///
+    /// If the parent isn't in the buffer, older messages are fetched from the
+    /// server until it is found or the start of the room is reached.
+    pub async fn goto_parent(&self, offset: usize) {
+        let event_id = if let Some(e) = self.event_id_at_offset(offset) {
+            e
+        } else {
+            self.print_error(&format!("No message found at offset {}", offset));
+            return;
+        };
+
+        let parent = self
+            .fetch_message(&event_id)
+            .await
+            .and_then(|e| Self::reply_parent(e.original_content()));
+
+        let parent = if let Some(p) = parent {
+            p
+        } else {
+            self.print_error(&format!(
+                "The message at offset {} isn't a reply",
+                offset
+            ));
+            return;
+        };
+
+        let mut lines = self.lines_from_bottom(&parent);
+
+        while lines.is_none() && self.prev_batch.borrow().is_some() {
+            let num_lines =
+                self.buffer_handle().upgrade().map(|b| b.num_lines());
+            self.get_messages().await;
+
+            // Stop if we didn't manage to fetch anything, e.g. if we're
+            // disconnected.
+            if num_lines
+                == self.buffer_handle().upgrade().map(|b| b.num_lines())
+            {
+                break;
+            }
+
+            lines = self.lines_from_bottom(&parent);
+        }
+
+        let lines = if let Some(l) = lines {
+            l
+        } else {
+            self.print_error(&format!(
+                "The message {} couldn't be found in the room history",
+                parent
+            ));
+            return;
+        };
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            if buffer.window().is_none() {
+                self.print_error("The room buffer isn't displayed in a window");
+                return;
+            }
+
+            // Lines are counted without wrapping, so this is only exact if
+            // none of the lines below the parent wrap.
+            if buffer
+                .run_command("/window scroll_bottom")
+                .and_then(|_| {
+                    buffer.run_command(&format!("/window scroll -{}", lines))
+                })
+                .is_err()
+            {
+                self.print_error("Error scrolling to the message");
+            }
+        }
+    }
+
+    /// Check if the given event is printed in the buffer and was sent by us.
+    fn is_own_event(&self, event_id: &EventId) -> bool {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return false;
+        };
+
+        let event_id_tag = Cow::from(event_id.to_tag());
+        let sender_tag = Cow::from(self.own_user_id.to_tag());
+
+        buffer
+            .lines()
+            .find(|l| l.tags().contains(&event_id_tag))
+            .map(|l| l.tags().contains(&sender_tag))
+            .unwrap_or(false)
+    }
+
+    /// Edit one of our own messages, replacing its content with the given
+    /// one.
+    ///
+    /// The message in the buffer is updated right away, before the edit is
+    /// sent out.
+    pub async fn edit_message(&self, event_id: &EventId, text: String) {
+        if !self.is_own_event(event_id) {
+            self.print_error("Only your own messages can be edited");
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        if !self.safe_to_send() {
+            return;
+        }
+
+        let (fallback, new_content) =
+            if self.config.borrow().input().markdown_input() {
+                (
+                    TextMessageEventContent::markdown(format!("* {}", text)),
+                    TextMessageEventContent::markdown(text),
+                )
+            } else {
+                (
+                    TextMessageEventContent::plain(format!("* {}", text)),
+                    TextMessageEventContent::plain(text),
+                )
+            };
+
+        let new_content = RoomMessageEventContentWithoutRelation::new(
+            MessageType::Text(new_content),
+        );
+
+        let sender = self
+            .members
+            .get(&self.own_user_id)
+            .await
+            .unwrap_or_else(|| panic!("No own member {}", self.own_user_id));
+
+        if let Some(rendered) = self
+            .render_message_content(
+                event_id,
+                MilliSecondsSinceUnixEpoch::now(),
+                &sender,
+                &AnyMessageLikeEventContent::RoomMessage(
+                    new_content.clone().with_relation(None),
+                ),
+            )
+            .await
+        {
+            self.replace_edit(
+                event_id,
+                &self.own_user_id,
+                rendered.add_self_tags(),
+            );
+        }
+
+        let mut content =
+            RoomMessageEventContent::new(MessageType::Text(fallback));
+        content.relates_to = Some(Relation::Replacement(Replacement::new(
+            event_id.to_owned(),
+            new_content,
+        )));
+
+        if let Err(e) = connection
+            .send_message(
+                self.room().clone(),
+                AnyMessageLikeEventContent::RoomMessage(content),
+                None,
+            )
+            .await
+        {
+            self.print_error(&format!(
+                "Error sending the edit of {}: {}",
+                event_id, e
+            ));
+        }
+    }
+
+    /// Find the latest of our own messages that contains the given text.
+    fn find_own_message(&self, text: &str) -> Option<OwnedEventId> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let lines: Vec<_> = buffer.lines().collect();
+
+        lines.iter().rev().find_map(|line| {
+            let tags = line.tags();
+
+            if !tags.iter().any(|t| t == "self_msg")
+                || tags.iter().any(|t| t == "matrix_redacted")
+                || !Weechat::remove_color(&line.message()).contains(text)
+            {
+                return None;
+            }
+
+            tags.iter()
+                .find_map(|t| t.strip_prefix("matrix_id_"))
+                .and_then(|e| EventId::parse(e).ok())
+        })
+    }
+
+    /// Redact an event in this room.
+    ///
+    /// The target can either be an event ID or some text, in which case our
+    /// latest message containing the text will be redacted.
+    pub async fn redact(&self, target: &str, reason: Option<String>) {
+        let event_id = if target.starts_with('$') {
+            EventId::parse(target).ok()
+        } else {
+            self.find_own_message(target)
+        };
+
+        let event_id = if let Some(e) = event_id {
+            e
+        } else {
+            self.print_error(&format!("No message found matching {}", target));
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection
+            .redact(self.room().clone(), event_id.clone(), reason)
+            .await
+        {
+            Ok(_) => {
+                self.print_network(&format!("Redacted event {}", event_id))
+            }
+            Err(e) => self.print_error(&format!(
+                "Error redacting event {}: {}",
+                event_id, e
+            )),
+        }
+    }
+
+    /// Try to decrypt the messages we previously failed to decrypt because we
+    /// didn't have the room key of the given Megolm session.
+    ///
+    /// Decrypted messages are re-rendered in place, the buffer gets sorted
+    /// afterwards since the lines of a message might have been placed before
+    /// its correct position was known.
+    pub async fn retry_decryption(&self, session_id: &str) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            return;
+        };
+
+        let mut replaced = false;
+
+        for event_id in self.events_with_session(session_id) {
+            let rendered = if let Some(r) =
+                self.render_decrypted(&connection, &event_id).await
+            {
+                r
+            } else {
+                continue;
+            };
+
+            replaced |= self.replace_event(&event_id, rendered);
+        }
+
+        if replaced {
+            self.sort_messages();
+        }
+    }
+
+    /// Find the undecryptable events in the buffer that were encrypted with
+    /// the given Megolm session.
+    fn events_with_session(&self, session_id: &str) -> Vec<OwnedEventId> {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return Vec::new();
+        };
+
+        let session_tag = Cow::from(session_tag(session_id));
+        let mut event_ids: Vec<OwnedEventId> = Vec::new();
+
+        for line in buffer.lines().filter(|l| l.tags().contains(&session_tag)) {
+            let event_id = line
+                .tags()
+                .iter()
+                .find_map(|t| t.strip_prefix("matrix_id_"))
+                .and_then(|e| EventId::parse(e).ok());
+
+            if let Some(event_id) = event_id {
+                if !event_ids.contains(&event_id) {
+                    event_ids.push(event_id);
+                }
+            }
+        }
+
+        event_ids
+    }
+
+    /// Fetch an event that couldn't be decrypted before and render it, if it
+    /// can be decrypted now.
+    async fn render_decrypted(
+        &self,
+        connection: &Connection,
+        event_id: &EventId,
+    ) -> Option<RenderedEvent> {
+        let event = match connection
+            .room_event(self.room().clone(), event_id.to_owned())
+            .await
+        {
+            Ok(e) if e.encryption_info.is_some() => e,
+            _ => return None,
+        };
+
+        let event = if let Ok(AnyTimelineEvent::MessageLike(e)) =
+            event.event.deserialize()
+        {
+            e
+        } else {
+            return None;
+        };
+
+        let content = event.original_content()?;
+        let sender = self.members.get(event.sender()).await?;
+
+        let rendered = self
+            .render_message_content(
+                event_id,
+                event.origin_server_ts(),
+                &sender,
+                &content,
+            )
+            .await?;
+
+        Some(if sender.user_id() == &*self.own_user_id {
+            rendered.add_self_tags()
+        } else {
+            rendered.add_msg_tags()
+        })
+    }
+
+    /// Print an undecryptable message once the configured grace period has
+    /// passed, unless its room key arrived in the meantime, in which case the
+    /// decrypted message is printed instead.
+    fn print_after_grace_period(
+        &self,
+        event_id: &EventId,
+        placeholder: RenderedEvent,
+        grace_period: Duration,
+    ) {
+        let event_id = event_id.to_owned();
+
+        self.spawn_task(|room| async move {
+            let _ = room
+                .members
+                .runtime
+                .spawn(tokio::time::sleep(grace_period))
+                .await;
+
+            let connection = room.connection.borrow().clone();
+            let decrypted = match connection {
+                Some(c) => room.render_decrypted(&c, &event_id).await,
+                None => None,
+            };
+
+            if let Some(rendered) = decrypted {
+                room.print_rendered_event(rendered);
+            } else {
+                room.print_rendered_event(placeholder);
+            }
+
+            // Other messages might have been printed while we waited.
+            room.sort_messages();
+        });
+    }
+
+    /// Replace the lines of an event with a newly rendered version of it.
+    ///
+    /// Returns false if the event isn't printed in the buffer.
+    fn replace_event(
+        &self,
+        event_id: &EventId,
+        rendered: RenderedEvent,
+    ) -> bool {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return false;
+        };
+
+        let event_id_tag = Cow::from(event_id.to_tag());
+        let lines: Vec<BufferLine> = buffer
+            .lines()
+            .filter(|l| l.tags().contains(&event_id_tag))
+            .collect();
+
+        if lines.is_empty() {
+            false
+        } else {
+            self.replace_event_helper(&buffer, lines, rendered);
+            true
+        }
+    }
+
+    /// Update the presence of a member of this room.
+    pub async fn update_presence(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+    ) {
+        self.members.update_presence(user_id, presence).await;
+    }
+
+    /// The nicks of the members that are currently typing in this room.
+    pub fn typing_nicks(&self) -> Vec<String> {
+        self.typing_users
+            .borrow()
+            .iter()
+            .map(|(_, nick)| nick.clone())
+            .collect()
+    }
+
+    /// Update the list of members that are currently typing.
+    ///
+    /// The list is cleared after a while if the server doesn't update it, in
+    /// case we miss the update telling us that the members stopped typing.
+    pub async fn set_typing_users(&self, user_ids: Vec<OwnedUserId>) {
+        let mut typing_users = Vec::new();
+
+        for user_id in user_ids.into_iter().filter(|u| **u != *self.own_user_id)
+        {
+            let nick = self
+                .members
+                .get(&user_id)
+                .await
+                .map(|m| m.nick())
+                .unwrap_or_else(|| user_id.to_string());
+
+            typing_users.push((user_id, nick));
+        }
+
+        *self.typing_users.borrow_mut() = typing_users;
+        Weechat::bar_item_update("matrix_typing");
+
+        let generation = {
+            let mut generation = self.typing_generation.borrow_mut();
+            *generation += 1;
+            *generation
+        };
+
+        self.spawn_task(|room| async move {
+            let _ = room
+                .members
+                .runtime
+                .spawn(tokio::time::sleep(TYPING_TIMEOUT))
+                .await;
+
+            if *room.typing_generation.borrow() == generation {
+                room.typing_users.borrow_mut().clear();
+                Weechat::bar_item_update("matrix_typing");
+            }
+        });
+    }
+
+    /// Remove a member from the typing list, e.g. because they sent a
+    /// message.
+    fn remove_typing_user(&self, user_id: &UserId) {
+        let mut typing_users = self.typing_users.borrow_mut();
+        let count = typing_users.len();
+
+        typing_users.retain(|(u, _)| u != user_id);
+
+        if typing_users.len() != count {
+            Weechat::bar_item_update("matrix_typing");
+        }
+    }
+
+    /// Send out a typing notice.
+    ///
+    /// This will send out a typing notice or reset the one in progress, if
+    /// needed. It will make sure that only one typing notice request is in
+    /// flight at a time.
+    ///
+    /// Typing notices are sent out only if we have more than 4 letters in the
+    /// input and the input isn't a command.
+    ///
+    /// If the input is empty the typing notice is disabled.
+    pub fn update_typing_notice(&self) {
+        let buffer_handle = self.buffer_handle();
+
+        let buffer = if let Ok(b) = buffer_handle.upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let input = buffer.input();
+
+        if input.starts_with('/') && !input.starts_with("//") {
+            // Don't send typing notices for commands.
+            return;
+        }
+
+        let connection = self.connection.clone();
+        let room = self.room().clone();
+
+        let send = |typing: bool| async move {
+            let connection = connection.borrow().clone();
+
+            if let Some(connection) = connection {
+                let _ = connection.send_typing_notice(room, typing).await;
+            };
+        };
+
+        if input.len() < 4 {
+            // If we have an active typing notice and our input is short, e.g.
+            // we removed the input set the typing notice to false.
+            *self.typing_since.borrow_mut() = None;
+            self.spawn_task(|_| send(false));
+        } else if input.len() >= 4 {
+            // If we have some valid input and no active typing notice, send
+            // one out, but only once we have been typing for the configured
+            // delay.
+            let delay = Duration::from_millis(
+                self.config.borrow().network().typing_notice_delay() as u64,
+            );
+            let typing_since = *self
+                .typing_since
+                .borrow_mut()
+                .get_or_insert_with(Instant::now);
+
+            if typing_since.elapsed() >= delay {
+                self.spawn_task(|_| send(true));
+            }
+        }
+    }
+
+    /// Are read receipts enabled for this room, the room specific setting
+    /// takes precedence over the global one.
+    pub fn read_receipts_enabled(&self) -> bool {
+        let local_setting =
+            self.buffer_handle().upgrade().ok().and_then(|b| {
+                b.get_localvar("read_receipts").map(|v| v == "on")
+            });
+
+        local_setting
+            .unwrap_or_else(|| self.config.borrow().network().read_receipts())
+    }
+
+    /// Enable or disable read receipts for this room.
+    pub fn set_read_receipts(&self, enabled: bool) {
+        if let Ok(b) = self.buffer_handle().upgrade() {
+            b.set_localvar("read_receipts", if enabled { "on" } else { "off" });
+        }
+
+        self.print_network(&format!(
+            "Read receipts have been {} for this room",
+            if enabled { "enabled" } else { "disabled" }
+        ));
+    }
+
+    /// Explain who is able to join the room and which part of the room history
+    /// new members are able to read.
+    pub fn print_visibility(&self) {
+        let join_rule = match self.room.join_rule() {
+            JoinRule::Public => "Anyone can join the room",
+            JoinRule::Invite => "Only invited users can join the room",
+            JoinRule::Knock => {
+                "Users can ask to join the room, but need to be invited"
+            }
+            JoinRule::Restricted(_) => {
+                "Members of some other rooms can join the room, everyone \
+                 else needs to be invited"
+            }
+            JoinRule::KnockRestricted(_) => {
+                "Members of some other rooms can join the room, everyone \
+                 else can ask to join"
+            }
+            JoinRule::Private => "Nobody can join the room",
+            _ => "The join rule of the room is unknown",
+        };
+
+        let history = match self.room.history_visibility() {
+            HistoryVisibility::WorldReadable => {
+                "Anyone, even without joining, can read all of the history"
+            }
+            HistoryVisibility::Shared => {
+                "Anyone who joins can read all of the history"
+            }
+            HistoryVisibility::Invited => {
+                "Members can read the history from the point they were invited"
+            }
+            HistoryVisibility::Joined => {
+                "Members can read the history from the point they joined"
+            }
+            _ => "The history visibility of the room is unknown",
+        };
+
+        let guests = if self.is_guest_accessible() {
+            "Guests are allowed to join"
+        } else {
+            "Guests are not allowed to join"
+        };
+
+        self.print_network(&format!(
+            "Who can see this room:\n    {}\n    {}\n    {}",
+            join_rule, history, guests
+        ));
+    }
+
+    /// Change the join rule of the room.
+    ///
+    /// The allowed rooms are only used for the restricted join rules, members
+    /// of those rooms, usually spaces, are allowed to join without an invite.
+    pub async fn set_join_rule(&self, rule: &str, allowed_rooms: &[String]) {
+        let mut allow = Vec::new();
+
+        for room in allowed_rooms {
+            match RoomId::parse(room) {
+                Ok(r) => allow.push(AllowRule::room_membership(r)),
+                Err(_) => {
+                    self.print_error(&format!("Invalid room ID {}", room));
+                    return;
+                }
+            }
+        }
+
+        let join_rule = match rule {
+            "public" => JoinRule::Public,
+            "invite" => JoinRule::Invite,
+            "knock" => JoinRule::Knock,
+            "restricted" | "knock-restricted" if allow.is_empty() => {
+                self.print_error(
+                    "The restricted join rules need at least one room whose \
+                     members are allowed to join",
+                );
+                return;
+            }
+            "restricted" => JoinRule::Restricted(Restricted::new(allow)),
+            "knock-restricted" => {
+                JoinRule::KnockRestricted(Restricted::new(allow))
+            }
+            _ => unreachable!(),
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection
+            .set_join_rule(self.room().clone(), join_rule)
+            .await
+        {
+            Ok(()) => (),
+            Err(e) => {
+                let message = if matches!(
+                    e.client_api_error_kind(),
+                    Some(ErrorKind::Forbidden)
+                ) {
+                    "You don't have the permission to change the join rule \
+                     of this room"
+                        .to_owned()
+                } else {
+                    format!("Error changing the join rule: {}", e)
+                };
+
+                self.print_error(&message);
+            }
+        }
+    }
+
+    /// Print the encryption algorithm and the session rotation settings of the
+    /// room.
+    pub fn print_encryption_info(&self) {
+        let settings = if let Some(s) = self.room.encryption_settings() {
+            s
+        } else {
+            self.print_network("This room isn't encrypted");
+            return;
+        };
+
+        // The defaults for the rotation period as defined by the spec.
+        let rotation_ms =
+            settings.rotation_period_ms.unwrap_or(uint!(604800000));
+        let rotation_msgs = settings.rotation_period_msgs.unwrap_or(uint!(100));
+
+        self.print_network(&format!(
+            "Encryption info:\n{:>24}: {}\n{:>24}: {} ms\n{:>24}: {}",
+            "Algorithm",
+            settings.algorithm,
+            "Rotation period",
+            rotation_ms,
+            "Rotation messages",
+            rotation_msgs,
+        ));
+    }
+
+    /// Discard the current outbound group session, the next message we send
+    /// will start a new one.
+    pub async fn rotate_keys(&self) {
+        if !self.is_encrypted() {
+            self.print_error("This room isn't encrypted");
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection.discard_room_key(self.room().clone()).await {
+            Ok(()) => self.print_network(
+                "The room key has been discarded, the next message will be \
+                 encrypted using a new session",
+            ),
+            Err(e) => self
+                .print_error(&format!("Error discarding the room key: {}", e)),
+        }
+    }
+
+    /// Find the newest event that is displayed in the window of the buffer,
+    /// using the event ID tags of the buffer lines.
+    ///
+    /// If the window is scrolled up, the lines below the bottom of the window
+    /// aren't displayed and are skipped. If the buffer isn't displayed in a
+    /// window, the newest event of the buffer is used.
+    fn last_displayed_event_id(&self) -> Option<OwnedEventId> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let hidden_lines = buffer
+            .window()
+            .map_or(0, |w| usize::try_from(w.lines_after()).unwrap_or(0));
+
+        buffer.lines().rev().skip(hidden_lines).find_map(|line| {
+            line.tags()
+                .iter()
+                .find_map(|t| t.strip_prefix("matrix_id_"))
+                .and_then(|e| EventId::parse(e).ok())
+        })
+    }
+
+    /// Is the buffer of the room displayed in a window.
+    pub fn is_displayed(&self) -> bool {
+        self.buffer_handle()
+            .upgrade()
+            .map_or(false, |b| b.window().is_some())
+    }
+
+    /// Send out a read receipt once the buffer stopped being scrolled for a
+    /// while, so scrolling through the buffer doesn't send out a receipt for
+    /// every step.
+    pub fn schedule_read_receipt(&self) {
+        let generation = {
+            let mut generation = self.read_receipt_generation.borrow_mut();
+            *generation += 1;
+            *generation
+        };
+
+        self.spawn_task(|room| async move {
+            let _ = room
+                .members
+                .runtime
+                .spawn(tokio::time::sleep(READ_RECEIPT_DELAY))
+                .await;
+
+            if *room.read_receipt_generation.borrow() == generation {
+                room.send_read_receipt();
+            }
+        });
+    }
+
+    /// The number of messages and highlights that arrived since the room
+    /// was last read.
+    pub fn unread(&self) -> UnreadCounts {
+        *self.unread.borrow()
+    }
+
+    /// Send out a read receipt for the last message in the room, if we didn't
+    /// already do so and read receipts are enabled.
+    ///
+    /// This is called when the room is being looked at, so the unread counts
+    /// are reset even if read receipts are disabled.
+    pub fn send_read_receipt(&self) {
+        if *self.unread.borrow() != UnreadCounts::default() {
+            *self.unread.borrow_mut() = UnreadCounts::default();
+            Weechat::bar_item_update("matrix_unread");
+        }
+
+        if !self.read_receipts_enabled() {
+            return;
+        }
+
+        let event_id = self
+            .last_displayed_event_id()
+            .or_else(|| self.last_event_id.borrow().clone());
+
+        let event_id = if let Some(e) = event_id {
+            e
+        } else {
+            return;
+        };
+
+        if self.read_receipt.borrow().as_ref() == Some(&event_id) {
+            return;
+        }
+
+        self.spawn_task(|room| async move {
+            let connection = room.connection.borrow().clone();
+
+            if let Some(connection) = connection {
+                match connection
+                    .send_read_receipt(room.room().clone(), event_id.clone())
+                    .await
+                {
+                    Ok(()) => *room.read_receipt.borrow_mut() = Some(event_id),
+                    Err(e) => room.print_error(&format!(
+                        "Error sending a read receipt: {}",
+                        e
+                    )),
+                }
+            }
+        });
+    }
+
+    /// Mark the room as read up to the event at the bottom of the buffer,
+    /// this sends a fully read marker and a read receipt even if read
+    /// receipts are disabled for the room.
+    pub fn mark_read(&self) {
+        if !self.mark_displayed_events_read() {
+            self.print_error("There's no event that could be marked as read");
+        }
+    }
+
+    /// Send a fully read marker and a read receipt for the event at the
+    /// bottom of the buffer.
+    ///
+    /// Returns false if the buffer doesn't contain any event.
+    fn mark_displayed_events_read(&self) -> bool {
+        if *self.unread.borrow() != UnreadCounts::default() {
+            *self.unread.borrow_mut() = UnreadCounts::default();
+            Weechat::bar_item_update("matrix_unread");
+        }
+
+        let event_id = if let Some(e) = self.last_displayed_event_id() {
+            e
+        } else {
+            return false;
+        };
+
+        self.spawn_task(|room| async move {
+            let connection = room.connection.borrow().clone();
+
+            if let Some(connection) = connection {
+                match connection
+                    .mark_read(room.room().clone(), event_id.clone())
+                    .await
+                {
+                    Ok(()) => *room.read_receipt.borrow_mut() = Some(event_id),
+                    Err(e) => room.print_error(&format!(
+                        "Error marking the room as read: {}",
+                        e
+                    )),
+                }
+            }
+        });
+
+        true
+    }
+
+    /// The room buffer has been switched to, mark the room as read if that's
+    /// enabled, otherwise only send out a read receipt.
+    pub fn buffer_switched(&self) {
+        if self.config.borrow().look().auto_mark_read()
+            && self.read_receipts_enabled()
+        {
+            // A room without any events isn't worth complaining about when
+            // it's switched to.
+            self.mark_displayed_events_read();
+        } else {
+            self.send_read_receipt();
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.messages_in_flight.locked()
+    }
+
+    /// Print a message with a given prefix to the room buffer.
+    fn print_with_prefix(&self, prefix: &str, message: &str) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.print(&format!("{}{}: {}", prefix, PLUGIN_NAME, message));
+        }
+    }
+
+    /// Print a network message to the room buffer.
+    pub fn print_network(&self, message: &str) {
+        self.print_with_prefix(&Weechat::prefix(Prefix::Network), message);
+    }
+
+    /// Print a one line summary of the room using the data we have cached,
+    /// e.g. "42 members, encrypted, 3 unread (1 highlight), last active 3h
+    /// ago".
+    fn print_summary(&self, last_active: Option<MilliSecondsSinceUnixEpoch>) {
+        let members = self.room.joined_members_count();
+        let counts = self.room.unread_notification_counts();
+
+        let mut parts = vec![
+            format!(
+                "{} member{}",
+                members,
+                if members == 1 { "" } else { "s" }
+            ),
+            if self.is_encrypted() {
+                "encrypted".to_owned()
+            } else {
+                "unencrypted".to_owned()
+            },
+        ];
+
+        if counts.notification_count > 0 {
+            let mut unread = format!("{} unread", counts.notification_count);
+
+            if counts.highlight_count > 0 {
+                unread.push_str(&format!(
+                    " ({} highlight{})",
+                    counts.highlight_count,
+                    if counts.highlight_count == 1 { "" } else { "s" }
+                ));
+            }
+
+            parts.push(unread);
+        }
+
+        if let Some(last_active) = last_active {
+            let now = MilliSecondsSinceUnixEpoch::now().as_secs();
+            let age = now.saturating_sub(last_active.as_secs());
+
+            parts.push(format!("last active {}", format_age(age.into())));
+        }
+
+        self.print_network(&parts.join(", "));
+    }
+
+    /// Print an error message to the room buffer.
+    pub fn print_error(&self, message: &str) {
+        self.print_with_prefix(&Weechat::prefix(Prefix::Error), message);
+    }
+
+    /// Print out the messages that are still waiting for a response from the
+    /// server.
+    pub fn print_outgoing_queue(&self) {
+        let entries = self.outgoing_messages.entries();
+
+        if entries.is_empty() {
+            self.print_network("The outgoing message queue is empty");
+            return;
+        }
+
+        let lines: Vec<String> = entries
+            .iter()
+            .map(|(transaction_id, echo, content)| {
+                let excerpt = excerpt(content.body());
+
+                format!(
+                    "    {} {}[{}{}{}]{} {}",
+                    transaction_id,
+                    Weechat::color("chat_delimiters"),
+                    Weechat::color("reset"),
+                    if *echo { "echoed" } else { "not echoed" },
+                    Weechat::color("chat_delimiters"),
+                    Weechat::color("reset"),
+                    excerpt,
+                )
+            })
+            .collect();
+
+        self.print_network(&format!(
+            "Outgoing message queue ({} messages):\n{}",
+            entries.len(),
+            lines.join("\n")
+        ));
+    }
+
+    /// Print the joined members of the room, grouped by their power level.
+    pub async fn print_names(&self) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let members = match connection.joined_members(self.room().clone()).await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                self.print_error(&format!("Error fetching the members: {}", e));
+                return;
+            }
+        };
+
+        let mut members: Vec<WeechatRoomMember> =
+            members.into_iter().map(|m| self.members.wrap(m)).collect();
+        members.sort_by_key(|m| {
+            (std::cmp::Reverse(m.power_level()), m.nick().to_lowercase())
+        });
+
+        let mut lines = vec![format!("Members ({} total):", members.len())];
+
+        let mut groups: Vec<(&str, Vec<String>)> = Vec::new();
+
+        for member in &members {
+            let nick = format!(
+                "{}{}{}{}",
+                member.prefix_colored(),
+                Weechat::color(member.color()),
+                member.nick(),
+                Weechat::color("reset")
+            );
+
+            match groups.last_mut() {
+                Some((name, nicks)) if *name == member.power_level_name() => {
+                    nicks.push(nick)
+                }
+                _ => groups.push((member.power_level_name(), vec![nick])),
+            }
+        }
+
+        lines.extend(groups.into_iter().map(|(name, nicks)| {
+            format!("  {} ({}): {}", name, nicks.len(), nicks.join(" "))
+        }));
+
+        self.print_network(&lines.join("\n"));
+    }
+
+    /// Print the servers that should be used as `via` servers for permalinks
+    /// to the room.
+    pub async fn print_via_servers(&self) {
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let members = match connection.joined_members(self.room().clone()).await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                self.print_error(&format!("Error fetching the members: {}", e));
+                return;
+            }
+        };
+
+        let members: Vec<_> = members
+            .iter()
+            .map(|m| (m.user_id(), m.power_level()))
+            .collect();
+        let servers = via_servers(&members, VIA_SERVER_COUNT);
+
+        if servers.is_empty() {
+            self.print_network("No suitable via servers found");
+            return;
+        }
+
+        let query = servers
+            .iter()
+            .map(|s| format!("via={}", s))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        self.print_network(&format!(
+            "Via servers: {}\n  https://matrix.to/#/{}?{}",
+            servers
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.room_id(),
+            query
+        ));
+    }
+
+    /// Print the profile of a room member, their power level and the
+    /// devices they have.
+    pub async fn whois(&self, user: &str) {
+        let member = match self.members.resolve_user(user) {
+            Some(user_id) => self.members.get(&user_id).await,
+            None => None,
+        };
+
+        let member = if let Some(m) = member {
+            m
+        } else {
+            self.print_error(&format!("No such member {}", user));
+            return;
+        };
+
+        let mut lines = vec![
+            format!(
+                "{}{}{} ({})",
+                Weechat::color(member.color()),
+                member
+                    .display_name()
+                    .unwrap_or_else(|| member.user_id().as_str()),
+                Weechat::color("reset"),
+                member.user_id(),
+            ),
+            format!(
+                "  Power level: {} ({})",
+                member.power_level(),
+                member.power_level_name()
+            ),
+        ];
+
+        if let Some(connection) = self.connection.borrow().clone() {
+            let client = connection.client().clone();
+            let user_id = member.user_id().to_owned();
+
+            let devices = self
+                .members
+                .runtime
+                .spawn(async move {
+                    client.encryption().get_user_devices(&user_id).await.map(
+                        |devices| {
+                            devices
+                                .devices()
+                                .map(|d| {
+                                    (
+                                        d.device_id().to_owned(),
+                                        d.get_key(DeviceKeyAlgorithm::Ed25519)
+                                            .map(|k| k.to_base64()),
+                                        d.display_name().map(|n| n.to_owned()),
+                                        d.is_verified(),
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                        },
+                    )
+                })
+                .await
+                .expect("Fetching the devices of a member panicked");
+
+            match devices {
+                Ok(devices) if devices.is_empty() => {
+                    lines.push("  Devices: none".to_owned())
+                }
+                Ok(devices) => {
+                    lines.push("  Devices:".to_owned());
+                    lines.extend(devices.into_iter().map(
+                        |(device_id, fingerprint, name, verified)| {
+                            let trust = if verified {
+                                DeviceTrust::Verified
+                            } else {
+                                DeviceTrust::Unverified
+                            };
+
+                            InnerServer::format_device(
+                                &device_id,
+                                fingerprint,
+                                name.as_deref(),
+                                false,
+                                trust,
+                                None,
+                                None,
+                            )
+                        },
+                    ));
+                }
+                Err(e) => {
+                    lines.push(format!("  Error fetching the devices: {:?}", e))
+                }
+            }
+        }
+
+        self.print_network(&lines.join("\n"));
+    }
+
+    /// Override the nick color of a room member, `None` resets the color to
+    /// the automatically computed one.
+    pub async fn set_nick_color(&self, user: &str, color: Option<String>) {
+        let user_id = if let Some(u) = self.members.resolve_user(user) {
+            u
+        } else {
+            self.print_error(&format!("No such member {}", user));
+            return;
+        };
+
+        match self.members.set_nick_color(&user_id, color.clone()).await {
+            Ok(()) => self.print_network(&format!(
+                "Nick color of {} set to {}{}{}",
+                user_id,
+                Weechat::color(color.as_deref().unwrap_or("reset")),
+                color.as_deref().unwrap_or("the default color"),
+                Weechat::color("reset"),
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error storing the nick color of {}: {}",
+                user_id, e
+            )),
+        }
+    }
+
+    /// Let a user that knocked on the room in, by inviting them.
+    pub async fn accept_knock(&self, user: &str) {
+        let (user_id, connection) = if let Some(r) = self.knock_target(user) {
+            r
+        } else {
+            return;
+        };
+
+        match connection
+            .invite(self.room().clone(), user_id.clone())
+            .await
+        {
+            Ok(()) => self.print_network(&format!(
+                "Accepted the request of {} to join the room",
+                user_id
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error accepting the request of {} to join the room: {}",
+                user_id, e
+            )),
+        }
+    }
+
+    /// Kick a member from the room.
+    ///
+    /// The member gets removed from the nicklist once the membership event of
+    /// the kick comes in.
+    pub async fn kick(&self, user: &str, reason: Option<String>) {
+        let member = match self.members.resolve_user(user) {
+            Some(user_id) => self.members.get(&user_id).await,
+            None => None,
+        };
+
+        let member = if let Some(m) = member {
+            m
+        } else {
+            self.print_error(&format!("No such member {}", user));
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let own_power_level = self
+            .members
+            .get(&self.own_user_id)
+            .await
+            .map(|m| m.power_level())
+            .unwrap_or_default();
+
+        if !self.members.can_kick().await {
+            self.print_error(
+                "You don't have the permission to kick members from this room",
+            );
+            return;
+        } else if member.power_level() >= own_power_level {
+            self.print_error(&format!(
+                "You can't kick {}, their power level isn't lower than yours",
+                member.nick()
+            ));
+            return;
+        }
+
+        let user_id = member.user_id().to_owned();
+
+        match connection
+            .kick(self.room().clone(), user_id.clone(), reason.clone())
+            .await
+        {
+            Ok(()) => self.print_network(&format!(
+                "Kicked {} from the room{}",
+                user_id,
+                reason.map(|r| format!(": {}", r)).unwrap_or_default()
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error kicking {} from the room: {}",
+                user_id, e
+            )),
+        }
+    }
+
+    /// Change the power level of a member of the room.
+    ///
+    /// The member is moved to the right nicklist group once the power levels
+    /// state event comes in.
+    pub async fn set_power_level(&self, user: &str, power_level: i64) {
+        let member = match self.members.resolve_user(user) {
+            Some(user_id) => self.members.get(&user_id).await,
+            None => None,
+        };
+
+        let member = if let Some(m) = member {
+            m
+        } else {
+            self.print_error(&format!("No such member {}", user));
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let own_power_level = self
+            .members
+            .get(&self.own_user_id)
+            .await
+            .map(|m| m.power_level())
+            .unwrap_or_default();
+
+        let is_own = *member.user_id() == *self.own_user_id;
+
+        if !self.members.can_change_power_levels().await {
+            self.print_error(
+                "You don't have the permission to change power levels in \
+                 this room",
+            );
+            return;
+        } else if power_level > own_power_level {
+            self.print_error(&format!(
+                "You can't give out a power level of {}, your own power level \
+                 is {}",
+                power_level, own_power_level
+            ));
+            return;
+        } else if !is_own && member.power_level() >= own_power_level {
+            self.print_error(&format!(
+                "You can't change the power level of {}, their power level \
+                 isn't lower than yours",
+                member.nick()
+            ));
+            return;
+        }
+
+        let user_id = member.user_id().to_owned();
+
+        match connection
+            .set_power_level(self.room().clone(), user_id.clone(), power_level)
+            .await
+        {
+            Ok(()) => self.print_network(&format!(
+                "Changed the power level of {} to {}",
+                user_id, power_level
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error changing the power level of {}: {}",
+                user_id, e
+            )),
+        }
+    }
+
+    /// Invite a user to the room.
+    pub async fn invite(&self, user: &str) {
+        let user_id = if let Ok(u) = UserId::parse(user) {
+            u
+        } else {
+            self.print_error(&format!("Invalid user ID {}", user));
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        if !self.members.can_invite().await {
+            self.print_error(
+                "You don't have the permission to invite users to this room",
+            );
+            return;
+        }
+
+        match connection
+            .invite_user(self.room().clone(), user_id.clone())
+            .await
+        {
+            Ok(()) => {
+                self.print_network(&format!("Invited {} to the room", user_id))
+            }
+            Err(e) => self.print_error(&e),
+        }
+    }
+
+    /// Deny a user that knocked on the room entry, by kicking them.
+    pub async fn reject_knock(&self, user: &str, reason: Option<String>) {
+        let (user_id, connection) = if let Some(r) = self.knock_target(user) {
+            r
+        } else {
+            return;
+        };
+
+        match connection
+            .kick(self.room().clone(), user_id.clone(), reason)
+            .await
+        {
+            Ok(()) => self.print_network(&format!(
+                "Rejected the request of {} to join the room",
+                user_id
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error rejecting the request of {} to join the room: {}",
+                user_id, e
+            )),
+        }
+    }
+
+    /// Get the user ID of a user that knocked on the room and the connection
+    /// we should use to respond to the knock, printing an error if either of
+    /// them isn't available.
+    fn knock_target(&self, user: &str) -> Option<(OwnedUserId, Connection)> {
+        let user_id = if let Ok(u) = UserId::parse(user) {
+            u
+        } else {
+            self.print_error(&format!("Invalid user ID {}", user));
+            return None;
+        };
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return None;
+        };
+
+        Some((user_id, connection))
+    }
+
+    /// Drop all the messages from the outgoing message queue.
+    ///
+    /// Requests that are already in flight won't be cancelled, but their
+    /// responses won't be rendered anymore.
+    pub fn clear_outgoing_queue(&self) {
+        let count = self.outgoing_messages.clear();
+
+        self.print_network(&format!(
+            "Dropped {} messages from the outgoing message queue",
+            count
+        ));
+    }
+
+    pub fn reset_prev_batch(&self) {
+        // TODO: we'll want to be able to scroll up again after we clear the
+        // buffer.
+        *self.prev_batch.borrow_mut() = None;
+    }
+
+    /// Fetch a single batch of older messages, print them out and remember
+    /// where the next batch starts.
+    ///
+    /// Returns the number of events the batch contained, or None if the
+    /// batch couldn't be fetched. The messages lock needs to be held while
+    /// this is called.
+    async fn fetch_messages_batch(
+        &self,
+        connection: &Connection,
+        prev_batch: PrevBatch,
+    ) -> Option<usize> {
+        let room = self.room().clone();
+        let r = connection.room_messages(room, prev_batch).await.ok()?;
+
+        for event in r.chunk.iter().filter_map(|e| e.event.deserialize().ok()) {
+            self.handle_room_event(&event).await;
+        }
+
+        let mut prev_batch = self.prev_batch.borrow_mut();
+
+        if let Some(PrevBatch::Forward(t)) = prev_batch.as_ref() {
+            *prev_batch = Some(PrevBatch::Backwards(t.to_owned()));
+            self.sort_messages();
+        } else if r.chunk.is_empty() {
+            *prev_batch = None;
+        } else {
+            *prev_batch = r.end.map(PrevBatch::Backwards);
+            self.sort_messages();
+        }
+
+        Some(r.chunk.len())
+    }
+
+    pub async fn get_messages(&self) {
+        let messages_lock = self.messages_in_flight.clone();
+
+        let connection = self.connection.borrow().as_ref().cloned();
+
+        let prev_batch =
+            if let Some(p) = self.prev_batch.borrow().as_ref().cloned() {
+                p
+            } else {
+                return;
+            };
+
+        let guard = if let Ok(l) = messages_lock.try_lock() {
+            l
+        } else {
+            return;
+        };
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+
+        if let Some(connection) = connection {
+            self.fetch_messages_batch(&connection, prev_batch).await;
+        }
+
+        drop(guard);
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+    }
+
+    /// Load older messages, batch by batch, until the given number of
+    /// messages has been loaded or the start of the room is reached.
+    ///
+    /// The batches are fetched one after the other with a small delay in
+    /// between, so we don't hammer the server with requests.
+    pub async fn load_history(&self, count: usize) {
+        let messages_lock = self.messages_in_flight.clone();
+
+        let connection = if let Some(c) = self.connection.borrow().clone() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let guard = if let Ok(l) = messages_lock.try_lock() {
+            l
+        } else {
+            self.print_error("Older messages are already being fetched");
+            return;
+        };
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+
+        let mut loaded = 0;
+
+        while loaded < count {
+            let prev_batch =
+                if let Some(p) = self.prev_batch.borrow().as_ref().cloned() {
+                    p
+                } else {
+                    break;
+                };
+
+            match self.fetch_messages_batch(&connection, prev_batch).await {
+                Some(0) | None => break,
+                Some(n) => loaded += n,
+            }
+
+            if loaded < count {
+                let _ = self
+                    .members
+                    .runtime
+                    .spawn(tokio::time::sleep(HISTORY_BATCH_DELAY))
+                    .await;
+            }
+        }
+
+        drop(guard);
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+
+        if self.prev_batch.borrow().is_none() {
+            self.print_network(&format!(
+                "Loaded {} older events, the start of the room has been \
+                 reached",
+                loaded
+            ));
+        } else {
+            self.print_network(&format!("Loaded {} older events", loaded));
+        }
+    }
+
+    fn sort_messages(&self) {
+        struct LineCopy {
+            date: i64,
+            date_printed: i64,
+            tags: Vec<String>,
+            prefix: String,
+            message: String,
+        }
+
+        impl<'a> From<BufferLine<'a>> for LineCopy {
+            fn from(line: BufferLine) -> Self {
+                Self {
+                    date: line.date(),
+                    date_printed: line.date_printed(),
+                    message: line.message().to_string(),
+                    prefix: line.prefix().to_string(),
+                    tags: line.tags().iter().map(|t| t.to_string()).collect(),
+                }
+            }
+        }
+
+        // TODO: update the highlight once Weechat starts supporting it.
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let mut lines: Vec<LineCopy> =
+                buffer.lines().map(|l| l.into()).collect();
+            lines.sort_by_key(|l| l.date);
+
+            for (line, new) in buffer.lines().zip(lines.drain(..)) {
+                let tags =
+                    new.tags.iter().map(|t| t.as_str()).collect::<Vec<&str>>();
+                let data = LineData {
+                    prefix: Some(&new.prefix),
+                    message: Some(&new.message),
+                    date: Some(new.date),
+                    date_printed: Some(new.date_printed),
+                    tags: Some(&tags),
+                };
+                line.update(data)
+            }
+        }
+    }
+
+    /// Replace the local echo of an event with a fully rendered one.
+    fn replace_local_echo(
+        &self,
+        transaction_id: &TransactionId,
+        buffer: &Buffer,
+        rendered: RenderedEvent,
+    ) {
+        let uuid_tag =
+            Cow::from(format!("matrix_echo_{}", transaction_id.to_string()));
+        let line_contains_uuid = |l: &BufferLine| l.tags().contains(&uuid_tag);
+
+        let mut lines = buffer.lines();
+        let mut current_line = lines.rfind(line_contains_uuid);
+
+        // We go in reverse order here since we also use rfind(). We got from
+        // the bottom of the buffer to the top since we're expecting these
+        // lines to be freshly printed and thus at the bottom.
+        let mut line_num = rendered.content.lines.len();
+
+        while let Some(line) = &current_line {
+            line_num -= 1;
+            let rendered_line = &rendered.content.lines[line_num];
+
+            line.set_message(&rendered_line.message);
+            current_line = lines.next_back().filter(line_contains_uuid);
+        }
+    }
+
+    async fn handle_outgoing_message(
+        &self,
+        transaction_id: &TransactionId,
+        event_id: &EventId,
+    ) {
+        if let Some((echo, content)) =
+            self.outgoing_messages.remove(&transaction_id)
+        {
+            let event = OriginalSyncMessageLikeEvent {
+                sender: (&*self.own_user_id).to_owned(),
+                origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+                event_id: event_id.to_owned(),
+                content,
+                unsigned: Default::default(),
+            };
+
+            let event = AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(event),
+            );
+
+            let rendered = self
+                .render_sync_message(&event)
+                .await
+                .expect("Sent out an event that we don't know how to render");
+
+            if let Some(root) = Self::thread_root(&event) {
+                self.add_thread_reply(root, event_id, rendered);
+            } else if let Ok(buffer) = self.buffer_handle().upgrade() {
+                if echo {
+                    self.replace_local_echo(&transaction_id, &buffer, rendered);
+                } else {
+                    self.print_rendered_event(rendered);
+                }
+            }
+        }
+    }
+
+    fn set_topic(&self) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer.set_title(&self.room().topic().unwrap_or_default());
+        }
+    }
+
+    fn set_alias(&self) {
+        if let Some(alias) = self.alias() {
+            if let Ok(b) = self.buffer_handle().upgrade() {
+                b.set_localvar("alias", alias.as_str());
+            }
+        }
+    }
+
+    /// Expose the room version and federation status from the create event of
+    /// the room as localvars.
+    fn set_create_info(&self) {
+        let content = if let Some(c) = self.room.create_content() {
+            c
+        } else {
+            return;
+        };
+
+        if let Ok(b) = self.buffer_handle().upgrade() {
+            b.set_localvar("room_version", content.room_version.as_str());
+            b.set_localvar(
+                "federated",
+                if content.federate { "1" } else { "0" },
+            );
+        }
+    }
+
+    /// Expose the guest access state of the room as a localvar and warn if
+    /// guests can join a public room.
+    fn set_guest_access(&self) {
+        let guest_accessible = self.is_guest_accessible();
+
+        if let Ok(b) = self.buffer_handle().upgrade() {
+            b.set_localvar(
+                "guest_access",
+                if guest_accessible { "1" } else { "0" },
+            );
+        }
+
+        if guest_accessible
+            && self.is_public()
+            && self.config.borrow().look().guest_access_warning()
+        {
+            self.print_network(
+                "This room is public and guests are allowed to join it, \
+                 messages can be read without an account on a homeserver",
+            );
+        }
+    }
+
+    fn update_buffer_name(&self) {
+        self.members.update_buffer_name();
+    }
+
+    fn replace_edit(
+        &self,
+        event_id: &EventId,
+        sender: &UserId,
+        event: RenderedEvent,
+    ) {
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let sender_tag = Cow::from(sender.to_tag());
+            let event_id_tag = Cow::from(event_id.to_tag());
+            let quote_tag = Cow::from("matrix_reply_quote");
+
+            // Edits don't carry the reply quote, keep the quote line of the
+            // original message as it is.
+            let lines: Vec<BufferLine> = buffer
+                .lines()
+                .filter(|l| {
+                    let tags = l.tags();
+                    tags.contains(&event_id_tag) && !tags.contains(&quote_tag)
+                })
+                .collect();
 
-            if let Ok(r) = connection.room_messages(room, prev_batch).await {
-                for event in
-                    r.chunk.iter().filter_map(|e| e.event.deserialize().ok())
-                {
-                    self.handle_room_event(&event).await;
+            if lines
+                .get(0)
+                .map(|l| l.tags().contains(&sender_tag))
+                .unwrap_or(false)
+            {
+                self.replace_event_helper(&buffer, lines, event);
+            }
+        }
+    }
+
+    fn replace_event_helper(
+        &self,
+        buffer: &Buffer,
+        lines: Vec<BufferLine<'_>>,
+        event: RenderedEvent,
+    ) {
+        use std::cmp::Ordering;
+        let date = lines.get(0).map(|l| l.date()).unwrap_or_default();
+
+        for (line, new) in lines.iter().zip(event.content.lines.iter()) {
+            let tags: Vec<&str> = new.tags.iter().map(|t| t.as_str()).collect();
+            let data = LineData {
+                // Our prefixes always come with a \t character, but when we
+                // replace stuff we're able to replace the prefix and the
+                // message separately, so trim the whitespace in the prefix.
+                prefix: Some(event.prefix.trim_end()),
+                message: Some(&new.message),
+                tags: Some(&tags),
+                ..Default::default()
+            };
+
+            line.update(data);
+        }
+
+        match lines.len().cmp(&event.content.lines.len()) {
+            Ordering::Greater => {
+                for line in &lines[event.content.lines.len()..] {
+                    line.set_message("");
+                }
+            }
+            Ordering::Less => {
+                for line in &event.content.lines[lines.len()..] {
+                    let message = format!("{}{}", &event.prefix, &line.message);
+                    let tags: Vec<&str> =
+                        line.tags.iter().map(|t| t.as_str()).collect();
+                    buffer.print_date_tags(date, &tags, &message)
                 }
 
-                let mut prev_batch = self.prev_batch.borrow_mut();
+                self.sort_messages()
+            }
+            Ordering::Equal => (),
+        }
+    }
+
+    async fn handle_edits(&self, event: &AnySyncMessageLikeEvent) {
+        // TODO: remove this expect.
+        let sender =
+            self.members.get(event.sender()).await.expect(
+                "Rendering a message but the sender isn't in the nicklist",
+            );
 
-                if let Some(PrevBatch::Forward(t)) = prev_batch.as_ref() {
-                    *prev_batch = Some(PrevBatch::Backwards(t.to_owned()));
-                    self.sort_messages();
-                } else if r.chunk.is_empty() {
-                    *prev_batch = None;
-                } else {
-                    *prev_batch = r.end.map(PrevBatch::Backwards);
-                    self.sort_messages();
+        if let Some((event_id, content)) = event.get_edit() {
+            let send_time = event.origin_server_ts();
+
+            if let Some(rendered) = self
+                .render_message_content(
+                    event_id,
+                    send_time,
+                    &sender,
+                    &AnyMessageLikeEventContent::RoomMessage(
+                        content.clone().with_relation(None),
+                    ),
+                )
+                .await
+                .map(|r| {
+                    // TODO: the tags are different if the room is a DM.
+                    if sender.user_id() == &*self.own_user_id {
+                        r.add_self_tags()
+                    } else {
+                        r.add_msg_tags()
+                    }
+                })
+            {
+                self.replace_edit(event_id, event.sender(), rendered);
+            }
+        }
+    }
+
+    fn handle_reaction(&self, event: &SyncReactionEvent) {
+        if let SyncMessageLikeEvent::Original(e) = event {
+            let annotation = &e.content.relates_to;
+            let old_summary = self.reactions.summary(&annotation.event_id);
+
+            if self.reactions.add(
+                &annotation.event_id,
+                &e.event_id,
+                &annotation.key,
+                *e.sender == *self.own_user_id,
+            ) {
+                self.update_reaction_summary(
+                    &annotation.event_id,
+                    &old_summary,
+                );
+            }
+        }
+    }
+
+    /// Replace the reaction summary at the end of the last line of the target
+    /// event with an up to date one.
+    fn update_reaction_summary(
+        &self,
+        target: &EventId,
+        old_summary: &[ReactionCount],
+    ) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let event_id_tag = Cow::from(target.to_tag());
+        let old_summary = render_reactions(old_summary);
+        let new_summary = render_reactions(&self.reactions.summary(target));
+
+        let line = buffer
+            .lines()
+            .filter(|l| l.tags().contains(&event_id_tag))
+            .last();
+
+        if let Some(line) = line {
+            let message = line.message();
+
+            // The read marker always stays at the end of the line.
+            let read_marker = format!(" {}", render_read_marker());
+            let (message, read_marker) =
+                match message.strip_suffix(&read_marker) {
+                    Some(m) => (m, read_marker.as_str()),
+                    None => (&*message, ""),
+                };
+
+            let message = if old_summary.is_empty() {
+                message
+            } else {
+                message
+                    .strip_suffix(&format!(" {}", old_summary))
+                    .unwrap_or(message)
+            };
+
+            if new_summary.is_empty() {
+                line.set_message(&format!("{}{}", message, read_marker));
+            } else {
+                line.set_message(&format!(
+                    "{} {}{}",
+                    message, new_summary, read_marker
+                ));
+            }
+        }
+    }
+
+    /// Find the newest event in the buffer that was sent by us.
+    fn last_own_event_id(&self) -> Option<OwnedEventId> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let sender_tag = Cow::from(self.own_user_id.to_tag());
+        let mut lines = buffer.lines();
+
+        while let Some(line) = lines.next_back() {
+            let tags = line.tags();
+
+            if !tags.contains(&sender_tag) {
+                continue;
+            }
+
+            let event_id = tags
+                .iter()
+                .find_map(|t| t.strip_prefix("matrix_id_"))
+                .and_then(|e| EventId::parse(e).ok());
+
+            if event_id.is_some() {
+                return event_id;
+            }
+        }
+
+        None
+    }
+
+    /// Add or remove the read marker at the end of the last line of the given
+    /// event.
+    fn set_read_marker(&self, event_id: &EventId, read: bool) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let event_id_tag = Cow::from(event_id.to_tag());
+        let read_marker = format!(" {}", render_read_marker());
+
+        let line = buffer
+            .lines()
+            .filter(|l| l.tags().contains(&event_id_tag))
+            .last();
+
+        if let Some(line) = line {
+            let message = line.message();
+
+            match (message.strip_suffix(&read_marker), read) {
+                (None, true) => {
+                    line.set_message(&format!("{}{}", message, read_marker))
                 }
+                (Some(m), false) => line.set_message(m),
+                _ => (),
             }
         }
+    }
 
-        drop(guard);
+    /// Mark our last message as read if any of the given receipts shows that
+    /// another member has read it, or a message that came after it.
+    ///
+    /// Only one message carries the marker at a time, the marker moves to the
+    /// newer message once that one gets read.
+    pub fn handle_receipts(&self, content: &ReceiptEventContent) {
+        if !self.config.borrow().look().read_marker() {
+            return;
+        }
 
-        Weechat::bar_item_update("buffer_modes");
-        Weechat::bar_item_update("matrix_modes");
+        let own_event = if let Some(e) = self.last_own_event_id() {
+            e
+        } else {
+            return;
+        };
+
+        if self.read_marker.borrow().as_ref() == Some(&own_event) {
+            return;
+        }
+
+        let own_position = if let Some(p) = self.lines_from_bottom(&own_event) {
+            p
+        } else {
+            return;
+        };
+
+        let read = content.iter().any(|(event_id, receipts)| {
+            let read_by_others = receipts
+                .get(&ReceiptType::Read)
+                .map_or(false, |r| r.keys().any(|u| **u != *self.own_user_id));
+
+            read_by_others
+                && self
+                    .lines_from_bottom(event_id)
+                    .map_or(false, |p| p <= own_position)
+        });
+
+        if read {
+            if let Some(previous) = self.read_marker.borrow_mut().take() {
+                self.set_read_marker(&previous, false);
+            }
+
+            self.set_read_marker(&own_event, true);
+            *self.read_marker.borrow_mut() = Some(own_event);
+        }
     }
 
-    fn sort_messages(&self) {
-        struct LineCopy {
-            date: i64,
-            date_printed: i64,
-            tags: Vec<String>,
-            prefix: String,
-            message: String,
+    async fn handle_room_message(&self, event: &AnySyncMessageLikeEvent) {
+        if let AnySyncMessageLikeEvent::Reaction(r) = event {
+            self.handle_reaction(r);
+            return;
+        }
+
+        // If the message has a transaction id it's a message that we sent out
+        // ourselves, the content will be in the outgoing message queue and it
+        // may have been printed out as a local echo.
+        if let (Some(id), AnySyncMessageLikeEvent::RoomMessage(_)) =
+            (event.transaction_id(), event)
+        {
+            self.handle_outgoing_message(id, event.event_id()).await;
+            return;
+        }
+
+        self.members.add_speaker(event.sender()).await;
+
+        if self.handle_poll_event(event).await {
+            return;
+        }
+
+        if let AnySyncMessageLikeEvent::RoomRedaction(r) = event {
+            self.redact_event(r).await;
+        } else if event.is_edit() {
+            self.handle_edits(event).await;
+        } else if let Some(rendered) = self.render_sync_message(event).await {
+            let rendered = self.add_thread_context(event, rendered);
+            let rendered = self.add_reply_quote(event, rendered);
+
+            let undecryptable = matches!(
+                event,
+                AnySyncMessageLikeEvent::RoomEncrypted(
+                    SyncMessageLikeEvent::Original(_)
+                )
+            );
+            let grace_period =
+                self.config.borrow().network().utd_grace_period() as u64;
+
+            if let Some(root) = Self::thread_root(event) {
+                self.add_thread_reply(root, event.event_id(), rendered);
+            } else if undecryptable && grace_period > 0 {
+                self.print_after_grace_period(
+                    event.event_id(),
+                    rendered,
+                    Duration::from_secs(grace_period),
+                );
+            } else {
+                let merge = self.should_merge_notice(event);
+
+                let rendered = if merge {
+                    rendered.merge_into_previous()
+                } else {
+                    rendered
+                };
+
+                self.print_rendered_event(rendered);
+
+                // A merged notice continues the message, so another notice
+                // can be merged after it.
+                *self.last_message.borrow_mut() =
+                    if merge || !Self::is_notice(event) {
+                        Some((
+                            event.sender().to_owned(),
+                            event.event_id().to_owned(),
+                        ))
+                    } else {
+                        None
+                    };
+            }
+
+            if let Some(answers) = Self::poll_answers(event) {
+                self.polls.add(event.event_id(), answers);
+                // Votes might have arrived before the poll did while we
+                // were paginating backwards.
+                self.update_poll_tally(event.event_id());
+            }
+
+            *self.last_event_id.borrow_mut() =
+                Some(event.event_id().to_owned());
+        }
+    }
+
+    fn is_notice(event: &AnySyncMessageLikeEvent) -> bool {
+        matches!(
+            event,
+            AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(e)
+            ) if matches!(e.content.msgtype, MessageType::Notice(_))
+        )
+    }
+
+    /// Should the notice be merged into the message that was printed right
+    /// before it.
+    ///
+    /// This is the case if the merge_notices option is enabled and the last
+    /// line of the buffer belongs to a message of the same sender.
+    fn should_merge_notice(&self, event: &AnySyncMessageLikeEvent) -> bool {
+        if !self.config.borrow().look().merge_notices()
+            || !Self::is_notice(event)
+        {
+            return false;
+        }
+
+        let event_tag = match &*self.last_message.borrow() {
+            Some((sender, event_id)) if **sender == *event.sender() => {
+                Cow::from(event_id.to_tag())
+            }
+            _ => return false,
+        };
+
+        self.buffer_handle()
+            .upgrade()
+            .ok()
+            .and_then(|b| b.lines().next_back())
+            .map_or(false, |line| line.tags().contains(&event_tag))
+    }
+
+    /// Get the ID and text of the answers of a poll start event.
+    fn poll_answers(
+        event: &AnySyncMessageLikeEvent,
+    ) -> Option<Vec<(String, String)>> {
+        match event.original_content()? {
+            AnyMessageLikeEventContent::PollStart(c) => Some(
+                c.poll
+                    .answers
+                    .iter()
+                    .map(|a| {
+                        let text = a.text.find_plain().unwrap_or_default();
+                        (a.id.clone(), text.to_owned())
+                    })
+                    .collect(),
+            ),
+            AnyMessageLikeEventContent::UnstablePollStart(
+                UnstablePollStartEventContent::New(c),
+            ) => Some(
+                c.poll_start
+                    .answers
+                    .iter()
+                    .map(|a| (a.id.clone(), a.text.clone()))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Aggregate poll responses and close ended polls.
+    ///
+    /// Returns true if the event was a poll response or a poll end event.
+    async fn handle_poll_event(&self, event: &AnySyncMessageLikeEvent) -> bool {
+        use AnyMessageLikeEventContent::*;
+
+        let content = if let Some(c) = event.original_content() {
+            c
+        } else {
+            return false;
+        };
+
+        match content {
+            PollResponse(c) => {
+                let poll = c.relates_to.event_id;
+
+                if self.polls.vote(
+                    &poll,
+                    event.sender(),
+                    event.origin_server_ts(),
+                    c.selections.to_vec(),
+                ) {
+                    self.update_poll_tally(&poll);
+                }
+            }
+            UnstablePollResponse(c) => {
+                let poll = c.relates_to.event_id;
+
+                if self.polls.vote(
+                    &poll,
+                    event.sender(),
+                    event.origin_server_ts(),
+                    c.poll_response.answers,
+                ) {
+                    self.update_poll_tally(&poll);
+                }
+            }
+            PollEnd(c) => self.end_poll(event, &c.relates_to.event_id).await,
+            UnstablePollEnd(c) => {
+                self.end_poll(event, &c.relates_to.event_id).await
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Close the poll and print out its final results.
+    async fn end_poll(&self, event: &AnySyncMessageLikeEvent, poll: &EventId) {
+        if !self.polls.end(poll, event.origin_server_ts()) {
+            return;
         }
 
-        impl<'a> From<BufferLine<'a>> for LineCopy {
-            fn from(line: BufferLine) -> Self {
-                Self {
-                    date: line.date(),
-                    date_printed: line.date_printed(),
-                    message: line.message().to_string(),
-                    prefix: line.prefix().to_string(),
-                    tags: line.tags().iter().map(|t| t.to_string()).collect(),
-                }
-            }
+        self.update_poll_tally(poll);
+
+        let tally = self.polls.tally(poll).unwrap_or_default();
+        let sender = self
+            .members
+            .get(event.sender())
+            .await
+            .map(|m| m.nick_colored())
+            .unwrap_or_else(|| event.sender().to_string());
+
+        if let Ok(buffer) = self.buffer_handle().upgrade() {
+            let timestamp: i64 =
+                (event.origin_server_ts().0 / uint!(1000)).into();
+            let event_tag = event.event_id().to_tag();
+
+            buffer.print_date_tags(
+                timestamp,
+                &[&event_tag, "matrix_poll_end", "notify_message"],
+                &format!(
+                    "{}{} has ended the poll: {}",
+                    Weechat::prefix(Prefix::Network),
+                    sender,
+                    render_poll_results(&tally)
+                ),
+            );
+        }
+    }
+
+    /// Replace the answer lines of a poll with ones showing the current
+    /// number of votes.
+    fn update_poll_tally(&self, poll: &EventId) {
+        let tally = if let Some(t) = self.polls.tally(poll) {
+            t
+        } else {
+            return;
+        };
+
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        let event_id_tag = Cow::from(poll.to_tag());
+        let lines: Vec<BufferLine> = buffer
+            .lines()
+            .filter(|l| l.tags().contains(&event_id_tag))
+            .collect();
+
+        let mut answers = render_poll_answers(&tally);
+
+        // The reaction summary lives at the end of the last line of the
+        // event, don't lose it.
+        let reactions = render_reactions(&self.reactions.summary(poll));
+
+        if let Some(last) = answers.last_mut().filter(|_| !reactions.is_empty())
+        {
+            *last = format!("{} {}", last, reactions);
         }
 
-        // TODO: update the highlight once Weechat starts supporting it.
-        if let Ok(buffer) = self.buffer_handle().upgrade() {
-            let mut lines: Vec<LineCopy> =
-                buffer.lines().map(|l| l.into()).collect();
-            lines.sort_by_key(|l| l.date);
+        // The first line holds the question, the answers follow.
+        for (line, answer) in lines.iter().skip(1).zip(answers) {
+            line.set_message(&answer);
+        }
+    }
 
-            for (line, new) in buffer.lines().zip(lines.drain(..)) {
-                let tags =
-                    new.tags.iter().map(|t| t.as_str()).collect::<Vec<&str>>();
-                let data = LineData {
-                    prefix: Some(&new.prefix),
-                    message: Some(&new.message),
-                    date: Some(new.date),
-                    date_printed: Some(new.date_printed),
-                    tags: Some(&tags),
-                };
-                line.update(data)
+    /// Get the event ID of the thread root of a threaded message.
+    fn thread_root(event: &AnySyncMessageLikeEvent) -> Option<OwnedEventId> {
+        if let Some(AnyMessageLikeEventContent::RoomMessage(c)) =
+            event.original_content()
+        {
+            if let Some(Relation::Thread(thread)) = c.relates_to {
+                return Some(thread.event_id);
             }
         }
+
+        None
     }
 
-    /// Replace the local echo of an event with a fully rendered one.
-    fn replace_local_echo(
+    /// Add a reply to its thread and update the summary line of the thread
+    /// in the room buffer.
+    fn add_thread_reply(
         &self,
-        transaction_id: &TransactionId,
-        buffer: &Buffer,
+        root: OwnedEventId,
+        event_id: &EventId,
         rendered: RenderedEvent,
     ) {
-        let uuid_tag =
-            Cow::from(format!("matrix_echo_{}", transaction_id.to_string()));
-        let line_contains_uuid = |l: &BufferLine| l.tags().contains(&uuid_tag);
+        let thread = self
+            .threads
+            .borrow_mut()
+            .entry(root.clone())
+            .or_insert_with(|| RoomThread::new(root.clone()))
+            .clone();
+
+        thread.add_reply(event_id, rendered);
+        self.update_thread_summary(&root, thread.reply_count());
+    }
 
-        let mut lines = buffer.lines();
-        let mut current_line = lines.rfind(line_contains_uuid);
+    /// Print or update the line in the room buffer that shows how many
+    /// replies a thread has.
+    fn update_thread_summary(&self, root: &EventId, reply_count: usize) {
+        let buffer = if let Ok(b) = self.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
 
-        // We go in reverse order here since we also use rfind(). We got from
-        // the bottom of the buffer to the top since we're expecting these
-        // lines to be freshly printed and thus at the bottom.
-        let mut line_num = rendered.content.lines.len();
+        let summary_tag = format!("matrix_thread_{}", root.as_str());
+        let message = format!(
+            "{}{} {} in thread{} (/thread {})",
+            Weechat::color("chat_delimiters"),
+            reply_count,
+            if reply_count == 1 { "reply" } else { "replies" },
+            Weechat::color("reset"),
+            root
+        );
 
-        while let Some(line) = &current_line {
-            line_num -= 1;
-            let rendered_line = &rendered.content.lines[line_num];
+        let line = buffer
+            .lines()
+            .find(|l| l.tags().contains(&Cow::from(summary_tag.as_str())));
 
-            line.set_message(&rendered_line.message);
-            current_line = lines.next_back().filter(line_contains_uuid);
+        if let Some(line) = line {
+            line.set_message(&message);
+        } else {
+            buffer.print_date_tags(
+                0,
+                &[&summary_tag, "no_highlight", "notify_none"],
+                &format!("\t{}", message),
+            );
         }
     }
 
-    async fn handle_outgoing_message(
-        &self,
-        transaction_id: &TransactionId,
-        event_id: &EventId,
-    ) {
-        if let Some((echo, content)) =
-            self.outgoing_messages.remove(&transaction_id)
-        {
-            let event = OriginalSyncMessageLikeEvent {
-                sender: (&*self.own_user_id).to_owned(),
-                origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
-                event_id: event_id.to_owned(),
-                content,
-                unsigned: Default::default(),
-            };
+    /// Open the buffer of the thread with the given root, or switch to it if
+    /// it's already open.
+    pub fn open_thread(&self, servers: &Servers, root: &EventId) {
+        let buffer = if let Ok(buffer) = self.buffer_handle().upgrade() {
+            buffer
+        } else {
+            return;
+        };
 
-            let event = AnySyncMessageLikeEvent::RoomMessage(
-                SyncMessageLikeEvent::Original(event),
-            );
+        let server_name = if let Some(s) = buffer.get_localvar("server") {
+            s.to_string()
+        } else {
+            return;
+        };
 
-            let rendered = self
-                .render_sync_message(&event)
-                .await
-                .expect("Sent out an event that we don't know how to render");
+        let buffer_name = format!("{}.{}", buffer.name(), root);
 
-            if let Ok(buffer) = self.buffer_handle().upgrade() {
-                if echo {
-                    self.replace_local_echo(&transaction_id, &buffer, rendered);
-                } else {
-                    self.print_rendered_event(rendered);
-                }
-            }
-        }
-    }
+        let title = if let Some((nick, excerpt)) = self.find_printed_event(root)
+        {
+            format!("Thread: {}: {}", nick, excerpt)
+        } else {
+            format!("Thread: {}", root)
+        };
 
-    fn set_topic(&self) {
-        if let Ok(buffer) = self.buffer_handle().upgrade() {
-            buffer.set_title(&self.room().topic().unwrap_or_default());
-        }
+        let thread = self
+            .threads
+            .borrow_mut()
+            .entry(root.to_owned())
+            .or_insert_with(|| RoomThread::new(root.to_owned()))
+            .clone();
+
+        thread.open(servers, &server_name, self, &buffer_name, &title);
     }
 
-    fn set_alias(&self) {
-        if let Some(alias) = self.alias() {
-            if let Ok(b) = self.buffer_handle().upgrade() {
-                b.set_localvar("alias", alias.as_str());
+    /// Get the event ID of the immediate parent of a threaded message.
+    fn thread_parent(event: &AnySyncMessageLikeEvent) -> Option<OwnedEventId> {
+        if let Some(AnyMessageLikeEventContent::RoomMessage(c)) =
+            event.original_content()
+        {
+            if let Some(Relation::Thread(thread)) = c.relates_to {
+                // If the reply is only a fallback for clients that don't
+                // support threads, the parent is the thread root.
+                let falling_back = thread.is_falling_back;
+
+                return Some(
+                    thread
+                        .in_reply_to
+                        .filter(|_| !falling_back)
+                        .map(|r| r.event_id)
+                        .unwrap_or(thread.event_id),
+                );
             }
         }
-    }
 
-    fn update_buffer_name(&self) {
-        self.members.update_buffer_name();
+        None
     }
 
-    fn replace_edit(
+    /// Find the nick of the sender and an excerpt of the message of an event
+    /// that is already printed in the buffer.
+    fn find_printed_event(
         &self,
         event_id: &EventId,
-        sender: &UserId,
-        event: RenderedEvent,
-    ) {
-        if let Ok(buffer) = self.buffer_handle().upgrade() {
-            let sender_tag = Cow::from(sender.to_tag());
-            let event_id_tag = Cow::from(event_id.to_tag());
+    ) -> Option<(String, String)> {
+        let buffer = self.buffer_handle().upgrade().ok()?;
+        let event_id_tag = Cow::from(event_id.to_tag());
+        let quote_tag = Cow::from("matrix_reply_quote");
+
+        // The quote of a reply carries the tags of the reply, skip it to get
+        // to the message itself.
+        let line = buffer.lines().find(|l| {
+            let tags = l.tags();
+            tags.contains(&event_id_tag) && !tags.contains(&quote_tag)
+        })?;
 
-            let lines: Vec<BufferLine> = buffer
-                .lines()
-                .filter(|l| l.tags().contains(&event_id_tag))
-                .collect();
+        let nick = line
+            .tags()
+            .iter()
+            .find_map(|t| t.strip_prefix("nick_").map(|n| n.to_owned()))?;
 
-            if lines
-                .get(0)
-                .map(|l| l.tags().contains(&sender_tag))
-                .unwrap_or(false)
-            {
-                self.replace_event_helper(&buffer, lines, event);
+        let message = Weechat::remove_color(&line.message());
+        let excerpt = excerpt(message.trim_start_matches("╰ "));
+
+        Some((nick, excerpt))
+    }
+
+    /// Get the event ID of the message a message is replying to.
+    fn reply_parent(
+        content: Option<AnyMessageLikeEventContent>,
+    ) -> Option<OwnedEventId> {
+        if let Some(AnyMessageLikeEventContent::RoomMessage(c)) = content {
+            if let Some(Relation::Reply { in_reply_to }) = c.relates_to {
+                return Some(in_reply_to.event_id);
             }
         }
+
+        None
     }
 
-    fn replace_event_helper(
+    /// Fetch a message of the room from the server, the message gets
+    /// decrypted if we have the room key for it.
+    async fn fetch_message(
         &self,
-        buffer: &Buffer,
-        lines: Vec<BufferLine<'_>>,
-        event: RenderedEvent,
-    ) {
-        use std::cmp::Ordering;
-        let date = lines.get(0).map(|l| l.date()).unwrap_or_default();
+        event_id: &EventId,
+    ) -> Option<AnyMessageLikeEvent> {
+        let connection = self.connection.borrow().clone()?;
 
-        for (line, new) in lines.iter().zip(event.content.lines.iter()) {
-            let tags: Vec<&str> = new.tags.iter().map(|t| t.as_str()).collect();
-            let data = LineData {
-                // Our prefixes always come with a \t character, but when we
-                // replace stuff we're able to replace the prefix and the
-                // message separately, so trim the whitespace in the prefix.
-                prefix: Some(event.prefix.trim_end()),
-                message: Some(&new.message),
-                tags: Some(&tags),
-                ..Default::default()
-            };
+        let event = connection
+            .room_event(self.room().clone(), event_id.to_owned())
+            .await
+            .ok()?
+            .event
+            .deserialize()
+            .ok()?;
 
-            line.update(data);
+        if let AnyTimelineEvent::MessageLike(e) = event {
+            Some(e)
+        } else {
+            None
         }
+    }
 
-        match lines.len().cmp(&event.content.lines.len()) {
-            Ordering::Greater => {
-                for line in &lines[event.content.lines.len()..] {
-                    line.set_message("");
-                }
-            }
-            Ordering::Less => {
-                for line in &event.content.lines[lines.len()..] {
-                    let message = format!("{}{}", &event.prefix, &line.message);
-                    let tags: Vec<&str> =
-                        line.tags.iter().map(|t| t.as_str()).collect();
-                    buffer.print_date_tags(date, &tags, &message)
-                }
-
-                self.sort_messages()
-            }
-            Ordering::Equal => (),
+    /// Quote the parent of a reply above it, replacing the reply fallback, if
+    /// the parent is printed in the buffer.
+    ///
+    /// The parent isn't fetched from the server, that would hold up the
+    /// processing of the sync response, the reply fallback is kept instead.
+    fn add_reply_quote(
+        &self,
+        event: &AnySyncMessageLikeEvent,
+        rendered: RenderedEvent,
+    ) -> RenderedEvent {
+        if let Some(parent) = Self::reply_parent(event.original_content()) {
+            self.quote_parent(&parent, rendered)
+        } else {
+            rendered
         }
     }
 
-    async fn handle_edits(&self, event: &AnySyncMessageLikeEvent) {
-        // TODO: remove this expect.
-        let sender =
-            self.members.get(event.sender()).await.expect(
-                "Rendering a message but the sender isn't in the nicklist",
-            );
-
-        if let Some((event_id, content)) = event.get_edit() {
-            let send_time = event.origin_server_ts();
-
-            if let Some(rendered) = self
-                .render_message_content(
-                    event_id,
-                    send_time,
-                    &sender,
-                    &AnyMessageLikeEventContent::RoomMessage(
-                        content.clone().with_relation(None),
-                    ),
-                )
-                .await
-                .map(|r| {
-                    // TODO: the tags are different if the room is a DM.
-                    if sender.user_id() == &*self.own_user_id {
-                        r.add_self_tags()
-                    } else {
-                        r.add_msg_tags()
-                    }
-                })
-            {
-                self.replace_edit(event_id, event.sender(), rendered);
-            }
+    /// Quote the given parent event above the rendered reply.
+    fn quote_parent(
+        &self,
+        parent: &EventId,
+        rendered: RenderedEvent,
+    ) -> RenderedEvent {
+        if let Some((nick, excerpt)) = self.find_printed_event(parent) {
+            let color = self.config.borrow().color().quote_fg().to_string();
+            rendered.add_reply_quote(&nick, &excerpt, &color)
+        } else {
+            rendered
         }
     }
 
-    async fn handle_room_message(&self, event: &AnySyncMessageLikeEvent) {
-        // If the event has a transaction id it's an event that we sent out
-        // ourselves, the content will be in the outgoing message queue and it
-        // may have been printed out as a local echo.
-        if let Some(id) = event.transaction_id() {
-            self.handle_outgoing_message(id, event.event_id()).await;
-            return;
+    /// Show the parent of a threaded message above it if the parent is known
+    /// and thread context is enabled.
+    fn add_thread_context(
+        &self,
+        event: &AnySyncMessageLikeEvent,
+        rendered: RenderedEvent,
+    ) -> RenderedEvent {
+        if !self.config.borrow().look().thread_context() {
+            return rendered;
         }
 
-        if let AnySyncMessageLikeEvent::RoomRedaction(r) = event {
-            self.redact_event(r).await;
-        } else if event.is_edit() {
-            self.handle_edits(event).await;
-        } else if let Some(rendered) = self.render_sync_message(event).await {
-            self.print_rendered_event(rendered);
+        let parent = Self::thread_parent(event)
+            .and_then(|parent| self.find_printed_event(&parent));
+
+        if let Some((nick, excerpt)) = parent {
+            rendered.add_thread_context(&nick, &excerpt)
+        } else {
+            rendered
         }
     }
 
@@ -1076,12 +4005,12 @@ impl MatrixRoom {
             let redacter = self.members.get(redacter).await?;
             let sender = self.members.get(&e.sender).await?;
 
-            Some(e.render_with_prefix(
+            Some(self.add_timestamp(e.render_with_prefix(
                 e.origin_server_ts,
                 event.event_id(),
                 &sender,
                 &redacter,
-            ))
+            )))
         } else {
             None
         }
@@ -1098,6 +4027,30 @@ impl MatrixRoom {
             .await
     }
 
+    /// Print a line describing a change of the room state, e.g. a topic
+    /// change, into the buffer.
+    async fn print_state_event(&self, event: &AnySyncStateEvent) {
+        let sender = if let Some(s) = self.members.get(event.sender()).await {
+            s
+        } else {
+            return;
+        };
+
+        if let Some(message) = render_state_change(event, &sender) {
+            let timestamp: i64 =
+                (event.origin_server_ts().0 / uint!(1000)).into();
+            let event_id_tag = event.event_id().to_tag();
+
+            if let Ok(buffer) = self.buffer_handle().upgrade() {
+                buffer.print_date_tags(
+                    timestamp,
+                    &["matrix_state", &event_id_tag],
+                    &message,
+                );
+            }
+        }
+    }
+
     fn set_prev_batch(&self) {
         if let Ok(buffer) = self.buffer_handle().upgrade() {
             if buffer.num_lines() == 0 {
@@ -1112,6 +4065,7 @@ impl MatrixRoom {
 
         match &event {
             AnySyncTimelineEvent::MessageLike(message) => {
+                self.remove_typing_user(message.sender());
                 self.handle_room_message(message).await
             }
             AnySyncTimelineEvent::State(event) => {
@@ -1154,8 +4108,11 @@ impl MatrixRoom {
                     }
                 }
             }
-            // TODO: print out state events.
-            AnyTimelineEvent::State(_) => (),
+            // Historical state events are only printed, they must not
+            // modify the current room state.
+            AnyTimelineEvent::State(event) => {
+                self.print_state_event(&event.clone().into()).await
+            }
         }
     }
 
@@ -1166,12 +4123,30 @@ impl MatrixRoom {
     pub async fn handle_sync_state_event(
         &self,
         event: &AnySyncStateEvent,
-        _state_event: bool,
+        state_event: bool,
     ) {
+        if !state_event {
+            self.print_state_event(event).await;
+        }
+
         match event {
             AnySyncStateEvent::RoomName(_) => self.update_buffer_name(),
             AnySyncStateEvent::RoomTopic(_) => self.set_topic(),
-            AnySyncStateEvent::RoomCanonicalAlias(_) => self.set_alias(),
+            AnySyncStateEvent::RoomCanonicalAlias(_) => {
+                self.set_alias();
+                self.update_buffer_name();
+            }
+            AnySyncStateEvent::RoomGuestAccess(_) => self.set_guest_access(),
+            AnySyncStateEvent::RoomCreate(_) => self.set_create_info(),
+            AnySyncStateEvent::RoomJoinRules(_) => {
+                Weechat::bar_item_update("buffer_modes");
+                Weechat::bar_item_update("matrix_modes");
+            }
+            AnySyncStateEvent::RoomEncryption(_) => {
+                Weechat::bar_item_update("buffer_modes");
+                Weechat::bar_item_update("matrix_modes");
+                Weechat::bar_item_update("matrix_encryption");
+            }
             _ => (),
         }
     }
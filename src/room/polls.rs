@@ -0,0 +1,226 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use matrix_sdk::ruma::{
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, UserId,
+};
+
+/// The number of votes a poll answer got.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PollAnswerCount {
+    /// The text of the answer.
+    pub text: String,
+    /// How many members voted for the answer.
+    pub count: usize,
+}
+
+#[derive(Debug, Default)]
+struct Poll {
+    /// The ID and text of the answers, in the order they should be shown.
+    answers: Vec<(String, String)>,
+    /// The latest selections of every member that voted, together with the
+    /// time the vote was sent.
+    votes: HashMap<OwnedUserId, (MilliSecondsSinceUnixEpoch, Vec<String>)>,
+    /// When the poll has been closed, votes after the end don't count.
+    ended: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+/// A cache of the polls in a room, keyed by the event that started the poll.
+///
+/// Responses are aggregated the same way reactions are, so the tally of a poll
+/// can be updated without having to rescan the buffer lines every time a vote
+/// comes in.
+///
+/// Events don't arrive in order, while we paginate backwards the votes and the
+/// end of a poll are received before the poll itself, so they are kept around
+/// until the poll shows up and the time they were sent decides which of them
+/// count.
+#[derive(Clone, Debug, Default)]
+pub struct Polls {
+    polls: Rc<RefCell<HashMap<OwnedEventId, Poll>>>,
+}
+
+impl Polls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the answers of the poll that was started by the given event.
+    pub fn add(&self, poll: &EventId, answers: Vec<(String, String)>) {
+        self.polls
+            .borrow_mut()
+            .entry(poll.to_owned())
+            .or_default()
+            .answers = answers;
+    }
+
+    /// Record the vote of a member, a newer vote replaces the previous one.
+    ///
+    /// Returns false if the vote doesn't count, either because the member
+    /// voted again later or because the poll ended before the vote was sent.
+    pub fn vote(
+        &self,
+        poll: &EventId,
+        voter: &UserId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+        selections: Vec<String>,
+    ) -> bool {
+        let mut polls = self.polls.borrow_mut();
+        let poll = polls.entry(poll.to_owned()).or_default();
+
+        let ended = poll.ended.map_or(false, |e| timestamp > e);
+        let outdated =
+            poll.votes.get(voter).map_or(false, |(t, _)| *t > timestamp);
+
+        if ended || outdated {
+            false
+        } else {
+            poll.votes.insert(voter.to_owned(), (timestamp, selections));
+            true
+        }
+    }
+
+    /// Close the poll, the votes that were sent after the end are discarded.
+    ///
+    /// Returns false if the poll has already ended before.
+    pub fn end(
+        &self,
+        poll: &EventId,
+        timestamp: MilliSecondsSinceUnixEpoch,
+    ) -> bool {
+        let mut polls = self.polls.borrow_mut();
+        let poll = polls.entry(poll.to_owned()).or_default();
+
+        if poll.ended.map_or(false, |e| e <= timestamp) {
+            return false;
+        }
+
+        poll.ended = Some(timestamp);
+        poll.votes.retain(|_, (t, _)| *t <= timestamp);
+
+        true
+    }
+
+    /// Get the answers of the poll together with the number of votes every
+    /// answer got, in the order the answers were given.
+    ///
+    /// Returns None if the poll itself hasn't been seen yet.
+    pub fn tally(&self, poll: &EventId) -> Option<Vec<PollAnswerCount>> {
+        let polls = self.polls.borrow();
+        let poll = polls.get(poll).filter(|p| !p.answers.is_empty())?;
+
+        Some(
+            poll.answers
+                .iter()
+                .map(|(id, text)| PollAnswerCount {
+                    text: text.to_owned(),
+                    count: poll
+                        .votes
+                        .values()
+                        .filter(|(_, s)| s.contains(id))
+                        .count(),
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{event_id, user_id};
+
+    use super::*;
+
+    fn answers() -> Vec<(String, String)> {
+        vec![
+            ("yes".to_owned(), "Yes".to_owned()),
+            ("no".to_owned(), "No".to_owned()),
+        ]
+    }
+
+    fn counts(polls: &Polls, poll: &EventId) -> Vec<usize> {
+        polls
+            .tally(poll)
+            .expect("The poll should be known")
+            .iter()
+            .map(|a| a.count)
+            .collect()
+    }
+
+    fn ts(millis: u32) -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch(millis.into())
+    }
+
+    #[test]
+    fn test_votes_replace_previous_votes() {
+        let polls = Polls::new();
+        let poll = event_id!("$poll");
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        polls.add(poll, answers());
+
+        assert!(polls.vote(poll, alice, ts(1), vec!["yes".to_owned()]));
+        assert!(polls.vote(poll, bob, ts(2), vec!["yes".to_owned()]));
+        assert_eq!(counts(&polls, poll), [2, 0]);
+
+        assert!(polls.vote(poll, alice, ts(3), vec!["no".to_owned()]));
+        assert_eq!(counts(&polls, poll), [1, 1]);
+
+        assert!(!polls.vote(poll, alice, ts(0), vec!["yes".to_owned()]));
+        assert_eq!(counts(&polls, poll), [1, 1]);
+    }
+
+    #[test]
+    fn test_votes_after_the_end_do_not_count() {
+        let polls = Polls::new();
+        let poll = event_id!("$poll");
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        polls.add(poll, answers());
+
+        assert!(polls.vote(poll, alice, ts(1), vec!["yes".to_owned()]));
+        assert!(polls.end(poll, ts(2)));
+        assert!(!polls.end(poll, ts(3)));
+        assert!(!polls.vote(poll, bob, ts(4), vec!["no".to_owned()]));
+
+        assert_eq!(counts(&polls, poll), [1, 0]);
+    }
+
+    #[test]
+    fn test_events_before_the_poll_start() {
+        let polls = Polls::new();
+        let poll = event_id!("$poll");
+        let alice = user_id!("@alice:example.org");
+        let bob = user_id!("@bob:example.org");
+
+        // Paginating backwards, the end and the votes arrive newest first and
+        // before the poll itself.
+        assert!(polls.end(poll, ts(5)));
+        assert!(!polls.vote(poll, bob, ts(6), vec!["no".to_owned()]));
+        assert!(polls.vote(poll, alice, ts(4), vec!["no".to_owned()]));
+        assert!(!polls.vote(poll, alice, ts(2), vec!["yes".to_owned()]));
+        assert!(polls.vote(poll, bob, ts(3), vec!["yes".to_owned()]));
+
+        assert!(polls.tally(poll).is_none());
+
+        polls.add(poll, answers());
+
+        assert_eq!(counts(&polls, poll), [1, 1]);
+    }
+
+    #[test]
+    fn test_an_earlier_end_discards_later_votes() {
+        let polls = Polls::new();
+        let poll = event_id!("$poll");
+        let alice = user_id!("@alice:example.org");
+
+        polls.add(poll, answers());
+
+        assert!(polls.end(poll, ts(5)));
+        assert!(polls.vote(poll, alice, ts(3), vec!["yes".to_owned()]));
+        assert!(polls.end(poll, ts(2)));
+
+        assert_eq!(counts(&polls, poll), [0, 0]);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use matrix_sdk::ruma::{EventId, OwnedEventId};
+
+/// The number of times a reaction key was used on an event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReactionCount {
+    /// The reaction key, usually an emoji.
+    pub key: String,
+    /// How many times the key was used.
+    pub count: usize,
+    /// Is one of the reactions our own.
+    pub own: bool,
+}
+
+/// A cache of the reactions in a room, keyed by the event that was reacted to.
+///
+/// This allows us to update the reaction summary of an event without having to
+/// rescan the buffer lines every time a reaction comes in or gets redacted.
+#[derive(Clone, Debug, Default)]
+pub struct Reactions {
+    /// Map from the target event to the reactions, the ID of the reaction
+    /// event, its key and if it's our own, in the order they were received.
+    targets:
+        Rc<RefCell<HashMap<OwnedEventId, Vec<(OwnedEventId, String, bool)>>>>,
+    /// Map from the reaction event to the event that was reacted to.
+    reactions: Rc<RefCell<HashMap<OwnedEventId, OwnedEventId>>>,
+}
+
+impl Reactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a reaction to the target event.
+    ///
+    /// Returns false if the reaction was already known.
+    pub fn add(
+        &self,
+        target: &EventId,
+        reaction: &EventId,
+        key: &str,
+        own: bool,
+    ) -> bool {
+        let mut reactions = self.reactions.borrow_mut();
+
+        if reactions.contains_key(reaction) {
+            return false;
+        }
+
+        reactions.insert(reaction.to_owned(), target.to_owned());
+        self.targets
+            .borrow_mut()
+            .entry(target.to_owned())
+            .or_default()
+            .push((reaction.to_owned(), key.to_owned(), own));
+
+        true
+    }
+
+    /// Get the ID of the event the given reaction is targeting.
+    pub fn target(&self, reaction: &EventId) -> Option<OwnedEventId> {
+        self.reactions.borrow().get(reaction).cloned()
+    }
+
+    /// Get the ID of our own reaction with the given key to the target event.
+    pub fn own_reaction(
+        &self,
+        target: &EventId,
+        key: &str,
+    ) -> Option<OwnedEventId> {
+        self.targets.borrow().get(target).and_then(|reactions| {
+            reactions
+                .iter()
+                .find(|(_, k, own)| *own && k == key)
+                .map(|(event_id, _, _)| event_id.clone())
+        })
+    }
+
+    /// Remove a reaction, returns the ID of the event the reaction was
+    /// targeting if the reaction was known.
+    pub fn remove(&self, reaction: &EventId) -> Option<OwnedEventId> {
+        let target = self.reactions.borrow_mut().remove(reaction)?;
+        let mut targets = self.targets.borrow_mut();
+
+        if let Some(reactions) = targets.get_mut(&target) {
+            reactions.retain(|(event_id, _, _)| event_id != reaction);
+
+            if reactions.is_empty() {
+                targets.remove(&target);
+            }
+        }
+
+        Some(target)
+    }
+
+    /// Get the reaction keys of the target event together with the number of
+    /// times each key was used, in the order the keys were first used.
+    pub fn summary(&self, target: &EventId) -> Vec<ReactionCount> {
+        let mut summary: Vec<ReactionCount> = Vec::new();
+
+        if let Some(reactions) = self.targets.borrow().get(target) {
+            for (_, key, own) in reactions {
+                if let Some(c) = summary.iter_mut().find(|c| &c.key == key) {
+                    c.count += 1;
+                    c.own |= own;
+                } else {
+                    summary.push(ReactionCount {
+                        key: key.to_owned(),
+                        count: 1,
+                        own: *own,
+                    });
+                }
+            }
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::event_id;
+
+    use super::*;
+
+    #[test]
+    fn test_summary_counts_keys_in_order() {
+        let reactions = Reactions::new();
+        let target = event_id!("$target");
+
+        assert!(reactions.add(target, event_id!("$a"), "👍", false));
+        assert!(reactions.add(target, event_id!("$b"), "🎉", false));
+        assert!(reactions.add(target, event_id!("$c"), "👍", true));
+        assert!(!reactions.add(target, event_id!("$c"), "👍", true));
+
+        assert_eq!(
+            reactions.summary(target),
+            [
+                ReactionCount {
+                    key: "👍".to_owned(),
+                    count: 2,
+                    own: true,
+                },
+                ReactionCount {
+                    key: "🎉".to_owned(),
+                    count: 1,
+                    own: false,
+                },
+            ]
+        );
+        assert_eq!(
+            reactions.own_reaction(target, "👍").as_deref(),
+            Some(event_id!("$c"))
+        );
+        assert_eq!(reactions.own_reaction(target, "🎉"), None);
+    }
+
+    #[test]
+    fn test_removing_reactions() {
+        let reactions = Reactions::new();
+        let target = event_id!("$target");
+
+        reactions.add(target, event_id!("$a"), "👍", false);
+        reactions.add(target, event_id!("$b"), "👍", true);
+
+        assert_eq!(reactions.remove(event_id!("$b")).as_deref(), Some(target));
+        assert_eq!(reactions.remove(event_id!("$b")), None);
+        assert_eq!(reactions.own_reaction(target, "👍"), None);
+        assert_eq!(reactions.summary(target)[0].count, 1);
+
+        reactions.remove(event_id!("$a"));
+
+        assert!(reactions.summary(target).is_empty());
+        assert_eq!(reactions.target(event_id!("$a")), None);
+    }
+}
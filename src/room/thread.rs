@@ -0,0 +1,193 @@
+//! Matrix threads get their own buffer, keyed by the event ID of the thread
+//! root. The room buffer only shows a summary line with the number of replies
+//! for every thread.
+
+use std::{cell::RefCell, rc::Rc};
+
+use matrix_sdk::{
+    async_trait,
+    ruma::{
+        events::{
+            relation::Thread,
+            room::message::{
+                MessageType, Relation, RoomMessageEventContent,
+                TextMessageEventContent,
+            },
+        },
+        EventId, OwnedEventId,
+    },
+};
+
+use weechat::{
+    buffer::{
+        Buffer, BufferBuilderAsync, BufferHandle, BufferInputCallbackAsync,
+    },
+    Weechat,
+};
+
+use super::MatrixRoom;
+use crate::{render::RenderedEvent, Servers};
+
+/// A printed line of a thread reply, kept around so the thread buffer can be
+/// filled once it gets opened.
+struct ThreadLine {
+    timestamp: i64,
+    tags: Vec<String>,
+    message: String,
+}
+
+#[derive(Clone)]
+pub struct RoomThread {
+    root: Rc<OwnedEventId>,
+    latest_event: Rc<RefCell<OwnedEventId>>,
+    lines: Rc<RefCell<Vec<ThreadLine>>>,
+    reply_count: Rc<RefCell<usize>>,
+    buffer: Rc<RefCell<Option<BufferHandle>>>,
+}
+
+impl RoomThread {
+    pub fn new(root: OwnedEventId) -> Self {
+        Self {
+            latest_event: Rc::new(RefCell::new(root.clone())),
+            root: Rc::new(root),
+            lines: Rc::new(RefCell::new(Vec::new())),
+            reply_count: Rc::new(RefCell::new(0)),
+            buffer: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// The number of replies in the thread that we know of.
+    pub fn reply_count(&self) -> usize {
+        *self.reply_count.borrow()
+    }
+
+    fn buffer_handle(&self) -> Option<BufferHandle> {
+        self.buffer.borrow().clone()
+    }
+
+    fn print_line(buffer: &Buffer, line: &ThreadLine) {
+        let tags: Vec<&str> = line.tags.iter().map(|t| t.as_str()).collect();
+        buffer.print_date_tags(line.timestamp, &tags, &line.message);
+    }
+
+    /// Add a reply to the thread, the reply gets printed if the thread
+    /// buffer is open.
+    pub fn add_reply(&self, event_id: &EventId, rendered: RenderedEvent) {
+        *self.reply_count.borrow_mut() += 1;
+        *self.latest_event.borrow_mut() = event_id.to_owned();
+
+        let buffer_handle = self.buffer_handle();
+        let buffer = buffer_handle.as_ref().and_then(|b| b.upgrade().ok());
+
+        for line in rendered.content.lines {
+            let line = ThreadLine {
+                timestamp: rendered.message_timestamp,
+                tags: line.tags,
+                message: format!("{}{}", rendered.prefix, line.message),
+            };
+
+            if let Some(buffer) = &buffer {
+                Self::print_line(buffer, &line);
+            }
+
+            self.lines.borrow_mut().push(line);
+        }
+    }
+
+    /// Open the buffer of the thread, or switch to it if it's already open.
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - The servers, the thread buffer gets indexed there.
+    ///
+    /// * `server_name` - The name of the server the room belongs to.
+    ///
+    /// * `room` - The room the thread belongs to.
+    ///
+    /// * `buffer_name` - The full name the thread buffer should have.
+    ///
+    /// * `title` - The title of the thread buffer, usually an excerpt of the
+    /// thread root.
+    pub fn open(
+        &self,
+        servers: &Servers,
+        server_name: &str,
+        room: &MatrixRoom,
+        buffer_name: &str,
+        title: &str,
+    ) {
+        if let Some(buffer_handle) = self.buffer_handle() {
+            if let Ok(buffer) = buffer_handle.upgrade() {
+                buffer.switch_to();
+                return;
+            }
+        }
+
+        let input = ThreadInput {
+            room: room.clone(),
+            thread: self.clone(),
+        };
+
+        let buffer_handle = BufferBuilderAsync::new(buffer_name)
+            .input_callback(input)
+            .close_callback({
+                let servers = servers.clone();
+                let buffer_name = buffer_name.to_owned();
+
+                move |_weechat: &Weechat, _buffer: &Buffer| {
+                    servers.remove_room_buffer(&buffer_name);
+                    Ok(())
+                }
+            })
+            .build()
+            .expect("Can't create new thread buffer");
+
+        servers.add_room_buffer(buffer_name, server_name, room.room_id());
+
+        let buffer = buffer_handle
+            .upgrade()
+            .expect("Can't upgrade newly created buffer");
+
+        buffer.enable_multiline();
+        buffer.set_title(title);
+        buffer.set_localvar("type", "channel");
+        buffer.set_localvar("server", server_name);
+        buffer.set_localvar("room_id", room.room_id().as_str());
+        buffer.set_localvar("thread_root", self.root.as_str());
+
+        for line in self.lines.borrow().iter() {
+            Self::print_line(&buffer, line);
+        }
+
+        buffer.switch_to();
+
+        *self.buffer.borrow_mut() = Some(buffer_handle.clone());
+    }
+}
+
+/// Input callback of a thread buffer, messages are sent to the room as
+/// replies in the thread.
+struct ThreadInput {
+    room: MatrixRoom,
+    thread: RoomThread,
+}
+
+#[async_trait(?Send)]
+impl BufferInputCallbackAsync for ThreadInput {
+    async fn callback(&mut self, _: BufferHandle, input: String) {
+        let text = if self.room.config.borrow().input().markdown_input() {
+            TextMessageEventContent::markdown(input)
+        } else {
+            TextMessageEventContent::plain(input)
+        };
+
+        let mut content = RoomMessageEventContent::new(MessageType::Text(text));
+        content.relates_to = Some(Relation::Thread(Thread::plain(
+            (*self.thread.root).clone(),
+            self.thread.latest_event.borrow().clone(),
+        )));
+
+        self.room
+            .spawn_task(|room| async move { room.send_message(content).await });
+    }
+}
@@ -54,13 +54,17 @@
 //! receiver fetches events individually from a mpsc channel. This makes sure
 //! that processing events will not block the Weechat mainloop for too long.
 
-use chrono::{offset::Utc, DateTime};
+use chrono::{
+    offset::{Local, Utc},
+    DateTime,
+};
 use std::{
     cell::{Ref, RefCell, RefMut},
     cmp::Reverse,
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     path::PathBuf,
     rc::{Rc, Weak},
+    time::Duration,
 };
 use tracing::error;
 use url::Url;
@@ -68,14 +72,22 @@ use url::Url;
 use matrix_sdk::{
     self,
     deserialized_responses::AmbiguityChange,
-    encryption::RoomKeyImportResult,
+    encryption::{verification::VerificationRequest, RoomKeyImportResult},
     room::Room,
     ruma::{
-        api::client::session::login::v3::Response as LoginResponse,
+        api::client::{
+            filter::FilterDefinition,
+            session::login::v3::Response as LoginResponse,
+        },
         events::{
-            room::member::RoomMemberEventContent, AnySyncStateEvent,
-            AnySyncTimelineEvent, SyncStateEvent,
+            receipt::ReceiptEventContent,
+            room::{member::RoomMemberEventContent, message::MessageType},
+            AnyGlobalAccountDataEvent, AnySyncMessageLikeEvent,
+            AnySyncStateEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+            SyncStateEvent,
         },
+        presence::PresenceState,
+        push::Ruleset,
         DeviceId, DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch,
         OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId,
     },
@@ -89,12 +101,22 @@ use weechat::{
 };
 
 use crate::{
-    config::ServerBuffer,
-    connection::{Connection, InteractiveAuthInfo},
-    room::RoomHandle,
+    config::{NewDirectMessage, ServerBuffer},
+    connection::{Connection, InteractiveAuthInfo, SyncLimits},
+    room::{MatrixRoom, RoomHandle},
+    utils::{directory_size, format_size},
+    verification::VerificationBuffer,
     ConfigHandle, Servers, PLUGIN_NAME,
 };
 
+/// The URL the homeserver redirects to after a SSO login, the login token will
+/// be part of the query string. Nothing needs to listen on it, the user copies
+/// the token from the address bar.
+const SSO_REDIRECT_URL: &str = "http://localhost/";
+
+/// The maximal number of users a user directory search returns.
+const USER_SEARCH_LIMIT: u64 = 20;
+
 #[derive(Debug)]
 pub enum ServerError {
     StartError(String),
@@ -103,7 +125,7 @@ pub enum ServerError {
 }
 
 #[derive(Debug, Clone, Copy)]
-enum DeviceTrust {
+pub(crate) enum DeviceTrust {
     Verified,
     Unverified,
     Unsupported,
@@ -116,6 +138,7 @@ pub struct ServerSettings {
     pub autoconnect: bool,
     pub username: String,
     pub password: String,
+    pub access_token: String,
     pub ssl_verify: bool,
 }
 
@@ -128,6 +151,7 @@ impl Default for ServerSettings {
             homeserver: None,
             username: "".to_owned(),
             password: "".to_owned(),
+            access_token: "".to_owned(),
         }
     }
 }
@@ -173,9 +197,22 @@ pub struct InnerServer {
     login_state: Rc<RefCell<Option<LoginInfo>>>,
     connection: Rc<RefCell<Option<Connection>>>,
     server_buffer: Rc<RefCell<Option<BufferHandle>>>,
+    reconnecting: Rc<RefCell<bool>>,
+    login_token: Rc<RefCell<Option<String>>>,
+    verifications: Rc<RefCell<Vec<(OwnedUserId, String)>>>,
+    /// The users we ignore, None until the list arrived over sync.
+    ignored_users: Rc<RefCell<Option<BTreeSet<OwnedUserId>>>>,
+    /// Our push rules, None until they arrived over sync.
+    push_rules: Rc<RefCell<Option<Ruleset>>>,
+    /// When the last sync response arrived.
+    last_sync: Rc<RefCell<Option<DateTime<Utc>>>>,
 }
 
 impl MatrixServer {
+    /// Create a new server and its config options.
+    ///
+    /// No other server with the same name may exist, otherwise the config
+    /// options of the existing server would be clobbered.
     pub fn new(
         name: &str,
         config: &ConfigHandle,
@@ -195,6 +232,12 @@ impl MatrixServer {
             login_state: Rc::new(RefCell::new(None)),
             connection: Rc::new(RefCell::new(None)),
             server_buffer: Rc::new(RefCell::new(None)),
+            reconnecting: Rc::new(RefCell::new(false)),
+            login_token: Rc::new(RefCell::new(None)),
+            verifications: Rc::new(RefCell::new(Vec::new())),
+            ignored_users: Rc::new(RefCell::new(None)),
+            push_rules: Rc::new(RefCell::new(None)),
+            last_sync: Rc::new(RefCell::new(None)),
         };
 
         let server = server.into();
@@ -234,6 +277,65 @@ impl MatrixServer {
         Ok(())
     }
 
+    /// Connect to the server, logging in using a login token we received
+    /// from a SSO login.
+    pub fn connect_with_login_token(
+        &self,
+        token: String,
+    ) -> Result<(), ServerError> {
+        *self.login_token.borrow_mut() = Some(token);
+        self.connect()
+    }
+
+    /// Print the URL the user needs to visit to log in using SSO.
+    pub async fn print_sso_login_url(&self) {
+        let client = match self.get_or_create_client() {
+            Ok(c) => c,
+            Err(e) => {
+                self.print_error(&format!("{:?}", e));
+                return;
+            }
+        };
+
+        let url = self
+            .servers
+            .runtime()
+            .spawn(async move {
+                client
+                    .matrix_auth()
+                    .get_sso_login_url(SSO_REDIRECT_URL, None)
+                    .await
+            })
+            .await
+            .expect("Fetching the SSO login URL panicked");
+
+        match url {
+            Ok(url) => self.print_network(&format!(
+                "Open the following URL in a browser to log in to {}{}{}:\n\
+                 {}\n\
+                 After logging in you'll be redirected to {}, copy the \
+                 value of the loginToken parameter from the address bar and \
+                 run /matrix connect --sso-token <token> {}",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset"),
+                url,
+                SSO_REDIRECT_URL,
+                self.name(),
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error fetching the SSO login URL: {:?}",
+                e
+            )),
+        }
+    }
+
+    /// Is this the only handle to the server, dropping the last handle frees
+    /// the config options of the server and closes its buffers.
+    pub fn is_last_handle(&self) -> bool {
+        Rc::strong_count(&self.inner) == 1
+    }
+
     fn inner(&self) -> Rc<InnerServer> {
         self.inner.clone()
     }
@@ -391,6 +493,25 @@ impl MatrixServer {
             .new_string_option(password)
             .expect("Can't create password option");
 
+        let server = server_copy;
+        let server_copy = server.clone();
+
+        let access_token =
+            StringOptionSettings::new(format!("{}.access_token", server_name))
+                .set_change_callback(move |_, option| {
+                    let server_ref = server.upgrade().expect(
+                        "Server got deleted while server config is alive",
+                    );
+
+                    server_ref.settings.borrow_mut().access_token =
+                        Weechat::eval_string_expression(&option.value())
+                            .expect("Can't evaluate access token");
+                });
+
+        server_section
+            .new_string_option(access_token)
+            .expect("Can't create access token option");
+
         let server = server_copy;
 
         let ssl_verify =
@@ -410,13 +531,39 @@ impl MatrixServer {
             .new_boolean_option(ssl_verify)
             .expect("Can't create autoconnect option");
     }
+
+    /// The buffer of a room was closed, forget about the room and leave it in
+    /// the background.
+    pub fn room_buffer_closed(&self, room_id: &RoomId) {
+        let room = self.rooms.borrow_mut().remove(room_id);
+
+        let (room, connection) = match (room, self.connection()) {
+            (Some(r), Some(c)) => (r, c),
+            _ => return,
+        };
+
+        let server = self.clone();
+
+        Weechat::spawn(async move {
+            if let Err(e) = connection.leave_room(room.room().clone()).await {
+                server.print_error(&format!(
+                    "Error leaving room {}: {}",
+                    room.room_id(),
+                    e
+                ));
+            }
+        })
+        .detach();
+    }
 }
 
 impl Drop for MatrixServer {
     fn drop(&mut self) {
-        // TODO close all the server buffers.
-        // Only free the server config if it's the only clone of the InnerServer
-        if Rc::strong_count(&self.inner) == 1 {
+        // Only free the server config and close the buffers if it's the only
+        // clone of the InnerServer
+        if self.is_last_handle() {
+            self.close_buffers();
+
             let config = &self.config;
             let mut config_borrow = config.borrow_mut();
 
@@ -425,6 +572,7 @@ impl Drop for MatrixServer {
                 .expect("Can't get server section");
 
             for option_name in &[
+                "access_token",
                 "autoconnect",
                 "homeserver",
                 "password",
@@ -451,6 +599,32 @@ impl InnerServer {
         self.rooms.borrow().values().cloned().collect()
     }
 
+    /// Close the buffers of all our rooms and the server buffer.
+    ///
+    /// The rooms are forgotten before their buffers get closed, the close
+    /// callbacks of the room buffers won't find the rooms to leave them.
+    fn close_buffers(&self) {
+        let rooms: Vec<RoomHandle> =
+            self.rooms.borrow_mut().drain().map(|(_, r)| r).collect();
+
+        for room in rooms {
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                buffer.close();
+            }
+        }
+
+        let server_buffer = self.server_buffer.borrow_mut().take();
+
+        if let Some(buffer) = server_buffer.and_then(|b| b.upgrade().ok()) {
+            buffer.close();
+        }
+    }
+
+    /// Get the room with the given room ID, if we're a member of it.
+    pub fn room(&self, room_id: &RoomId) -> Option<RoomHandle> {
+        self.rooms.borrow().get(room_id).cloned()
+    }
+
     pub(crate) fn get_or_create_room(&self, room_id: &RoomId) -> RoomHandle {
         if !self.rooms.borrow().contains_key(room_id) {
             let homeserver = self
@@ -477,7 +651,7 @@ impl InnerServer {
             });
             let buffer = RoomHandle::new(
                 &self.server_name,
-                self.servers.runtime().to_owned(),
+                &self.servers,
                 &self.connection,
                 self.config.inner.clone(),
                 room,
@@ -485,12 +659,53 @@ impl InnerServer {
                 room_id,
                 &login_state.user_id,
             );
-            self.rooms.borrow_mut().insert(room_id.to_owned(), buffer);
+            self.rooms
+                .borrow_mut()
+                .insert(room_id.to_owned(), buffer.clone());
+
+            // Rooms that are created during the initial sync aren't new to us,
+            // only the ones that show up afterwards are.
+            if self.last_sync.borrow().is_some() && buffer.is_direct() {
+                self.new_direct_room(&buffer);
+            }
         }
 
         self.rooms.borrow().get(room_id).cloned().unwrap()
     }
 
+    /// A buffer for a direct message room we didn't know about was created,
+    /// notify about it or switch to it depending on the config.
+    fn new_direct_room(&self, room: &RoomHandle) {
+        let action = self.config.borrow().look().new_direct_message();
+
+        let buffer = if let Ok(b) = room.buffer_handle().upgrade() {
+            b
+        } else {
+            return;
+        };
+
+        match action {
+            NewDirectMessage::Ignore => (),
+            NewDirectMessage::Notify => {
+                let users: Vec<String> = room
+                    .room()
+                    .direct_targets()
+                    .iter()
+                    .map(|u| u.to_string())
+                    .collect();
+
+                self.print_network(&format!(
+                    "New direct message room with {} in buffer {}{}{}",
+                    users.join(", "),
+                    Weechat::color("chat_buffer"),
+                    buffer.name(),
+                    Weechat::color("reset")
+                ));
+            }
+            NewDirectMessage::Switch => buffer.switch_to(),
+        }
+    }
+
     pub fn config(&self) -> ConfigHandle {
         self.config.clone()
     }
@@ -503,6 +718,10 @@ impl InnerServer {
         self.settings.borrow().password.clone()
     }
 
+    pub fn access_token(&self) -> String {
+        self.settings.borrow().access_token.clone()
+    }
+
     pub async fn restore_room(&self, room: Room) {
         let homeserver = self
             .settings
@@ -513,7 +732,7 @@ impl InnerServer {
 
         match RoomHandle::restore(
             &self.server_name,
-            self.servers.runtime().to_owned(),
+            &self.servers,
             room,
             &self.connection,
             self.config.inner.clone(),
@@ -708,10 +927,481 @@ impl InnerServer {
         room_id: &RoomId,
         event: AnySyncTimelineEvent,
     ) {
+        // Verification requests can be sent as a room message as well, the
+        // event id of the message is the id of the verification flow.
+        if let AnySyncTimelineEvent::MessageLike(
+            AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(e),
+            ),
+        ) = &event
+        {
+            if let MessageType::VerificationRequest(_) = &e.content.msgtype {
+                self.receive_verification_request(
+                    e.sender.clone(),
+                    e.event_id.to_string(),
+                );
+            }
+        }
+
         let room = self.get_or_create_room(room_id);
         room.handle_sync_room_event(event).await
     }
 
+    /// Dispatch a global account data event that arrived over sync.
+    pub fn receive_account_data(&self, event: AnyGlobalAccountDataEvent) {
+        match event {
+            AnyGlobalAccountDataEvent::Direct(_) => self.direct_rooms_changed(),
+            AnyGlobalAccountDataEvent::IgnoredUserList(e) => self
+                .ignored_users_changed(
+                    e.content.ignored_users.into_keys().collect(),
+                ),
+            AnyGlobalAccountDataEvent::PushRules(e) => {
+                *self.push_rules.borrow_mut() = Some(e.content.global)
+            }
+            _ => (),
+        }
+    }
+
+    /// Update the list of ignored users, changes to an already known list get
+    /// printed out.
+    fn ignored_users_changed(&self, ignored_users: BTreeSet<OwnedUserId>) {
+        let previous = self
+            .ignored_users
+            .borrow_mut()
+            .replace(ignored_users.clone());
+
+        if let Some(previous) = previous {
+            for user_id in ignored_users.difference(&previous) {
+                self.print_network(&format!(
+                    "You are now ignoring {}",
+                    user_id
+                ));
+            }
+
+            for user_id in previous.difference(&ignored_users) {
+                self.print_network(&format!(
+                    "You are no longer ignoring {}",
+                    user_id
+                ));
+            }
+        }
+    }
+
+    /// The keywords of our push rules that cause notifications, as far as
+    /// they are known.
+    pub fn push_rule_keywords(&self) -> Vec<String> {
+        self.push_rules
+            .borrow()
+            .as_ref()
+            .map(|rules| {
+                rules
+                    .content
+                    .iter()
+                    .filter(|r| r.enabled && !r.default)
+                    .map(|r| r.pattern.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The `m.direct` mapping changed, mark the room buffers accordingly.
+    pub fn direct_rooms_changed(&self) {
+        for room in self.rooms() {
+            room.update_buffer_type();
+        }
+    }
+
+    /// Print the mapping from users to the rooms that are direct messages with
+    /// them.
+    pub async fn print_direct_rooms(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection.direct_rooms().await {
+            Ok(direct_rooms) if direct_rooms.is_empty() => {
+                self.print_network("There are no direct message rooms")
+            }
+            Ok(direct_rooms) => {
+                let lines: Vec<String> = direct_rooms
+                    .iter()
+                    .map(|(user_id, rooms)| {
+                        let rooms: Vec<&str> =
+                            rooms.iter().map(|r| r.as_str()).collect();
+                        format!("    {}: {}", user_id, rooms.join(", "))
+                    })
+                    .collect();
+
+                self.print_network(&format!(
+                    "Direct message rooms:\n{}",
+                    lines.join("\n")
+                ));
+            }
+            Err(e) => self.print_error(&format!(
+                "Error fetching the direct message rooms: {:?}",
+                e
+            )),
+        }
+    }
+
+    /// Find the direct message room we share with the given user.
+    fn find_direct_room(&self, user_id: &UserId) -> Option<RoomHandle> {
+        self.rooms().into_iter().find(|r| {
+            r.is_direct() && r.room().direct_targets().contains(user_id)
+        })
+    }
+
+    /// Switch to the buffer of the direct message room with the given user,
+    /// the room is created if we don't share one with the user yet.
+    pub async fn query(&self, user_id: OwnedUserId) {
+        if let Some(room) = self.find_direct_room(&user_id) {
+            if let Ok(buffer) = room.buffer_handle().upgrade() {
+                buffer.switch_to();
+            }
+            return;
+        }
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let room = match connection.create_dm(user_id.clone()).await {
+            Ok(r) => r,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error creating a direct message room with {}: {:?}",
+                    user_id, e
+                ));
+                return;
+            }
+        };
+
+        let room_id = room.room_id().to_owned();
+
+        // The room might have arrived over sync while we were waiting for the
+        // response, only restore it if we don't have a buffer for it yet.
+        if !self.rooms.borrow().contains_key(&room_id) {
+            self.restore_room(room).await;
+        }
+
+        let room = self.rooms.borrow().get(&room_id).cloned();
+
+        if let Some(buffer) =
+            room.and_then(|r| r.buffer_handle().upgrade().ok())
+        {
+            buffer.switch_to();
+        }
+    }
+
+    /// Search the user directory of the homeserver and print out the users
+    /// that match.
+    pub async fn search_users(&self, term: &str) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let response = match connection
+            .search_users(term.to_owned(), USER_SEARCH_LIMIT)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error searching the user directory: {:?}",
+                    e
+                ));
+                return;
+            }
+        };
+
+        if response.results.is_empty() {
+            self.print_network(&format!(
+                "No users found matching \"{}\"",
+                term
+            ));
+            return;
+        }
+
+        let mut lines = vec![format!("Users matching \"{}\":", term)];
+
+        lines.extend(response.results.iter().map(|user| {
+            let mut line = match &user.display_name {
+                Some(name) => format!(
+                    "    {}{}{} ({})",
+                    Weechat::color("chat_nick"),
+                    name,
+                    Weechat::color("reset"),
+                    user.user_id
+                ),
+                None => format!("    {}", user.user_id),
+            };
+
+            if let Some(avatar_url) = &user.avatar_url {
+                line.push_str(&format!(" avatar: {}", avatar_url));
+            }
+
+            line
+        }));
+
+        if response.limited {
+            lines.push(format!(
+                "Only the first {} results are shown, refine the search term \
+                 to find more users",
+                response.results.len()
+            ));
+        }
+
+        self.print_network(&lines.join("\n"));
+    }
+
+    /// Mark or unmark a room as a direct message with the given user.
+    pub async fn set_direct_room(
+        &self,
+        user_id: OwnedUserId,
+        room_id: OwnedRoomId,
+        direct: bool,
+    ) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection
+            .set_direct_room(user_id.clone(), room_id.clone(), direct)
+            .await
+        {
+            Ok(()) if direct => self.print_network(&format!(
+                "Marked {} as a direct message room with {}",
+                room_id, user_id
+            )),
+            Ok(()) => self.print_network(&format!(
+                "Unmarked {} as a direct message room with {}",
+                room_id, user_id
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error updating the direct message rooms: {:?}",
+                e
+            )),
+        }
+    }
+
+    /// Retry to decrypt the messages of a room we received a new room key
+    /// for.
+    pub async fn receive_room_keys(&self, room_id: &RoomId, session_id: &str) {
+        let room = self.rooms.borrow().get(room_id).cloned();
+
+        if let Some(room) = room {
+            room.retry_decryption(session_id).await;
+        }
+    }
+
+    /// Leave the given room, forget about it and close its buffer.
+    pub async fn leave_room(&self, room_id: &RoomId) {
+        let room = if let Some(r) = self.rooms.borrow().get(room_id).cloned() {
+            r
+        } else {
+            return;
+        };
+
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            room.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection.leave_room(room.room().clone()).await {
+            Ok(()) => {
+                // Remove the room before closing the buffer so the close
+                // callback doesn't try to leave the room again.
+                self.rooms.borrow_mut().remove(room_id);
+
+                if let Ok(buffer) = room.buffer_handle().upgrade() {
+                    buffer.close();
+                }
+            }
+            Err(e) => {
+                room.print_error(&format!("Error leaving the room: {}", e))
+            }
+        }
+    }
+
+    /// Read receipts were received for a room, mark our last message as read
+    /// if needed.
+    pub fn receive_receipts(
+        &self,
+        room_id: &RoomId,
+        content: &ReceiptEventContent,
+    ) {
+        let room = self.rooms.borrow().get(room_id).cloned();
+
+        if let Some(room) = room {
+            room.handle_receipts(content);
+        }
+    }
+
+    pub async fn receive_typing(
+        &self,
+        room_id: &RoomId,
+        user_ids: Vec<OwnedUserId>,
+    ) {
+        let room = self.rooms.borrow().get(room_id).cloned();
+
+        if let Some(room) = room {
+            room.set_typing_users(user_ids).await;
+        }
+    }
+
+    /// Update the presence of an user in all the rooms we share with them.
+    pub async fn receive_presence(
+        &self,
+        user_id: &UserId,
+        presence: PresenceState,
+    ) {
+        for room in self.rooms() {
+            room.update_presence(user_id, presence.clone()).await;
+        }
+    }
+
+    /// Are we trying to reconnect to the server after a failed sync.
+    pub fn is_reconnecting(&self) -> bool {
+        *self.reconnecting.borrow()
+    }
+
+    /// The sync loop lost the connection to the server and will retry after
+    /// the given delay.
+    pub fn connection_lost(&self, error: &str, delay: Duration) {
+        if !self.is_reconnecting() {
+            self.print_error(&format!(
+                "Lost the connection to {}{}{}: {}",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset"),
+                error
+            ));
+        }
+
+        self.print_network(&format!(
+            "Reconnecting to {}{}{} in {} seconds",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset"),
+            delay.as_secs().max(1)
+        ));
+
+        *self.reconnecting.borrow_mut() = true;
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+    }
+
+    /// A sync response has been processed, remember when that happened.
+    pub fn receive_sync(&self) {
+        *self.last_sync.borrow_mut() = Some(Utc::now());
+    }
+
+    /// The sync loop managed to sync again after losing the connection.
+    pub fn connection_restored(&self) {
+        *self.reconnecting.borrow_mut() = false;
+
+        self.print_network(&format!(
+            "Reconnected to {}{}{}",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset")
+        ));
+
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+    }
+
+    /// Remember a verification request so it can be listed and answered
+    /// using the verifications command.
+    pub fn receive_verification_request(
+        &self,
+        user_id: OwnedUserId,
+        flow_id: String,
+    ) {
+        let entry = (user_id, flow_id);
+
+        if self.verifications.borrow().contains(&entry) {
+            return;
+        }
+
+        self.print_network(&format!(
+            "{} sent a verification request, answer it in the verification \
+             buffer or use /matrix verifications",
+            entry.0
+        ));
+
+        if let Some(connection) = self.connection() {
+            let server_name = self.name().to_owned();
+            let (user_id, flow_id) = entry.clone();
+
+            Weechat::spawn(async move {
+                if let Some(request) =
+                    connection.verification_request(user_id, flow_id).await
+                {
+                    VerificationBuffer::open(&server_name, connection, request);
+                }
+            })
+            .detach();
+        }
+
+        self.verifications.borrow_mut().push(entry);
+    }
+
+    /// Get the verification requests that are still pending, requests that
+    /// have been finished or cancelled in the meantime are forgotten.
+    pub async fn pending_verifications(
+        &self,
+    ) -> Vec<(OwnedUserId, VerificationRequest)> {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            return Vec::new();
+        };
+
+        let entries = self.verifications.borrow().clone();
+        let mut pending = Vec::new();
+
+        for (user_id, flow_id) in entries {
+            match connection
+                .verification_request(user_id.clone(), flow_id.clone())
+                .await
+            {
+                Some(r) if !r.is_done() && !r.is_cancelled() => {
+                    pending.push((user_id, r))
+                }
+                _ => self
+                    .verifications
+                    .borrow_mut()
+                    .retain(|(u, f)| u != &user_id || f != &flow_id),
+            }
+        }
+
+        pending
+    }
+
+    /// Take the login token that should be used for the next login, if any.
+    pub fn take_login_token(&self) -> Option<String> {
+        self.login_token.borrow_mut().take()
+    }
+
+    pub fn receive_session(&self, user_id: OwnedUserId) {
+        *self.login_state.borrow_mut() = Some(LoginInfo { user_id });
+    }
+
     pub fn receive_login(&self, response: LoginResponse) {
         let login_state = LoginInfo {
             user_id: response.user_id,
@@ -725,15 +1415,210 @@ impl InnerServer {
         std::fs::create_dir_all(path)
     }
 
+    /// Get the directory where the data of the server, e.g. the device ID and
+    /// the crypto store, is kept.
+    ///
+    /// This is a subdirectory of the configured data directory, or of the
+    /// `matrix-rust` directory in the Weechat home directory if none is
+    /// configured.
     pub fn get_server_path(&self) -> PathBuf {
-        let mut path = Weechat::home_dir();
+        let data_dir = self.config.borrow().network().data_dir();
         let server_name: &str = &self.server_name;
-        path.push("matrix-rust");
+
+        let mut path = if data_dir.is_empty() {
+            let mut path = Weechat::home_dir();
+            path.push("matrix-rust");
+            path
+        } else if let (Some(p), Some(home)) =
+            (data_dir.strip_prefix("~/"), std::env::var_os("HOME"))
+        {
+            PathBuf::from(home).join(p)
+        } else {
+            PathBuf::from(data_dir)
+        };
+
         path.push(server_name);
 
         path
     }
 
+    /// Get the upper bounds of the sync timeout and of the backoff between
+    /// syncs, used when the server starts rate limiting us.
+    pub fn sync_limits(&self) -> SyncLimits {
+        let config = self.config.borrow();
+
+        SyncLimits {
+            max_timeout: Duration::from_secs(
+                config.network().sync_timeout_max() as u64,
+            ),
+            max_backoff: Duration::from_secs(
+                config.network().sync_backoff_max() as u64,
+            ),
+        }
+    }
+
+    /// Get the filter definition that should be used for syncing.
+    ///
+    /// This is the custom filter from the config, if one is set, otherwise
+    /// the default sync filter.
+    pub fn sync_filter(&self) -> FilterDefinition {
+        let custom_filter = self.config.borrow().network().sync_filter();
+
+        if custom_filter.is_empty() {
+            Connection::sync_filter()
+        } else {
+            serde_json::from_str(&custom_filter).unwrap_or_else(|e| {
+                self.print_error(&format!(
+                    "Invalid custom sync filter, using the default one: {}",
+                    e
+                ));
+                Connection::sync_filter()
+            })
+        }
+    }
+
+    /// Print out the filter definition that is used for syncing.
+    pub fn print_sync_filter(&self) {
+        let custom = !self.config.borrow().network().sync_filter().is_empty();
+
+        match serde_json::to_string_pretty(&self.sync_filter()) {
+            Ok(filter) => {
+                self.print_network(&format!(
+                    "Sync filter ({}) for server {}{}{}:",
+                    if custom { "custom" } else { "default" },
+                    Weechat::color("chat_server"),
+                    self.name(),
+                    Weechat::color("reset")
+                ));
+                self.print(&filter);
+            }
+            Err(e) => self.print_error(&format!(
+                "Error serializing the sync filter {:?}",
+                e
+            )),
+        }
+    }
+
+    /// Print the values of all the options of the server, the way they are
+    /// used after evaluation, secrets are masked.
+    pub fn print_config(&self) {
+        let settings = self.settings.borrow();
+
+        let mask = |secret: &str| {
+            if secret.is_empty() {
+                "(not set)"
+            } else {
+                "********"
+            }
+        };
+        let url = |url: &Option<Url>| {
+            url.as_ref()
+                .map_or_else(|| "(not set)".to_owned(), |u| u.to_string())
+        };
+
+        self.print_network(&format!(
+            "Configuration of server {}{}{}:\n\
+             {:indent$}homeserver: {}\n\
+             {:indent$}proxy: {}\n\
+             {:indent$}autoconnect: {}\n\
+             {:indent$}username: {}\n\
+             {:indent$}password: {}\n\
+             {:indent$}access_token: {}\n\
+             {:indent$}ssl_verify: {}",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset"),
+            "",
+            url(&settings.homeserver),
+            "",
+            url(&settings.proxy),
+            "",
+            settings.autoconnect,
+            "",
+            settings.username,
+            "",
+            mask(&settings.password),
+            "",
+            mask(&settings.access_token),
+            "",
+            settings.ssl_verify,
+            indent = 4
+        ));
+    }
+
+    /// Print the latest events of a room as they are returned by the
+    /// homeserver, without rendering them.
+    ///
+    /// # Arguments
+    ///
+    /// * `room` - The room whose events should be printed.
+    ///
+    /// * `count` - The number of events that should be printed.
+    pub async fn print_room_timeline(&self, room: &MatrixRoom, count: usize) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let messages =
+            match connection.room_timeline(room.room().clone(), count).await {
+                Ok(m) => m,
+                Err(e) => {
+                    self.print_error(&format!(
+                        "Error fetching the timeline of {}: {}",
+                        room.room_id(),
+                        e
+                    ));
+                    return;
+                }
+            };
+
+        let mut lines = vec![format!(
+            "{:<19} {:<32} {:<36} {}",
+            "time", "type", "sender", "event id"
+        )];
+
+        for event in messages.chunk.iter().rev() {
+            let line = match event.event.deserialize() {
+                Ok(e) => {
+                    let time = e
+                        .origin_server_ts()
+                        .to_system_time()
+                        .map(|t| {
+                            DateTime::<Local>::from(t)
+                                .format("%Y-%m-%d %H:%M:%S")
+                                .to_string()
+                        })
+                        .unwrap_or_default();
+
+                    format!(
+                        "{:<19} {:<32} {:<36} {}",
+                        time,
+                        e.event_type().to_string(),
+                        e.sender().as_str(),
+                        e.event_id()
+                    )
+                }
+                Err(e) => format!("{:<19} invalid event: {}", "", e),
+            };
+
+            lines.push(line);
+        }
+
+        self.print_network(&format!(
+            "Last {} events of {}:\n{}",
+            messages.chunk.len(),
+            room.room_id(),
+            lines
+                .iter()
+                .map(|l| format!("    {}", l))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ));
+    }
+
     pub fn connection(&self) -> Option<Connection> {
         self.connection.borrow().clone()
     }
@@ -831,6 +1716,115 @@ impl InnerServer {
         };
     }
 
+    /// Rename one of our devices.
+    ///
+    /// The first argument is treated as the device id if it's one of our
+    /// devices, otherwise all the arguments are the new name of our own
+    /// device.
+    pub async fn set_device_name(&self, args: Vec<String>) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let own_device_id = if let Some(d) = connection.client().device_id() {
+            d.to_owned()
+        } else {
+            self.print_error("Our own device ID isn't known yet");
+            return;
+        };
+
+        let devices = match connection.devices().await {
+            Ok(r) => r.devices,
+            Err(e) => {
+                self.print_error(&format!("Error fetching devices {:?}", e));
+                return;
+            }
+        };
+
+        let (device_id, name) = match args.split_first() {
+            Some((first, rest))
+                if !rest.is_empty()
+                    && devices
+                        .iter()
+                        .any(|d| d.device_id.as_str() == first) =>
+            {
+                (OwnedDeviceId::from(first.as_str()), rest.join(" "))
+            }
+            _ => (own_device_id, args.join(" ")),
+        };
+
+        match connection
+            .set_device_name(device_id.clone(), name.clone())
+            .await
+        {
+            Ok(_) => self.print_network(&format!(
+                "Successfully set the name of device {} to {}",
+                device_id, name
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error setting the name of device {}: {}",
+                device_id, e
+            )),
+        }
+    }
+
+    pub async fn change_password(
+        &self,
+        new_password: String,
+        logout_devices: bool,
+    ) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let mut result = connection
+            .change_password(new_password.clone(), logout_devices, None)
+            .await;
+
+        if let Err(e) = &result {
+            if let Some(info) = e.as_uiaa_response() {
+                let auth_info = {
+                    let settings = self.settings.borrow();
+                    InteractiveAuthInfo {
+                        user: settings.username.clone(),
+                        password: settings.password.clone(),
+                        session: info.session.clone(),
+                    }
+                };
+
+                result = connection
+                    .change_password(
+                        new_password,
+                        logout_devices,
+                        Some(auth_info),
+                    )
+                    .await;
+            }
+        }
+
+        match result {
+            Ok(_) => {
+                self.print_network(&format!(
+                    "Successfully changed the account password{}, don't \
+                     forget to update the password option of the server",
+                    if logout_devices {
+                        " and logged out all other devices"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+            Err(e) => self
+                .print_error(&format!("Error changing the password {:#?}", e)),
+        }
+    }
+
     pub async fn export_keys(&self, file: PathBuf, passphrase: String) {
         let client = self.get_client().unwrap();
 
@@ -853,6 +1847,36 @@ impl InnerServer {
         };
     }
 
+    /// Compact the store of the server, reporting its size.
+    ///
+    /// The SQLite store of the SDK doesn't offer a way to flush or compact it
+    /// from the outside, in that case only the current size is reported.
+    pub async fn compact_store(&self) {
+        let path = self.get_server_path();
+
+        let size = self
+            .servers
+            .runtime()
+            .spawn(async move { directory_size(&path) })
+            .await
+            .expect("Calculating the store size panicked");
+
+        match size {
+            Ok(size) => self.print_network(&format!(
+                "The store of server {}{}{} uses {}, the SQLite store doesn't \
+                 support compaction, no space was reclaimed",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset"),
+                format_size(size),
+            )),
+            Err(e) => self.print_error(&format!(
+                "Error calculating the size of the store: {}",
+                e
+            )),
+        }
+    }
+
     pub async fn import_keys(&self, file: PathBuf, passphrase: String) {
         let client = self.get_client().unwrap();
 
@@ -1035,7 +2059,7 @@ impl InnerServer {
         Ok(())
     }
 
-    fn format_device(
+    pub(crate) fn format_device(
         device_id: &DeviceId,
         fingerprint: Option<String>,
         display_name: Option<&str>,
@@ -1157,6 +2181,108 @@ impl InnerServer {
         }
     }
 
+    /// Print out the spec versions and capabilities the homeserver supports.
+    pub async fn capabilities(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        let (versions, capabilities) =
+            match connection.server_capabilities().await {
+                Ok(r) => r,
+                Err(e) => {
+                    self.print_error(&format!(
+                        "Error fetching the server capabilities {:?}",
+                        e
+                    ));
+                    return;
+                }
+            };
+
+        let capabilities = capabilities.capabilities;
+        let yes_no = |enabled: bool| if enabled { "yes" } else { "no" };
+
+        let room_versions = capabilities
+            .room_versions
+            .available
+            .iter()
+            .map(|(version, stability)| {
+                format!("{} ({})", version, stability.as_str())
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let unstable_features = versions
+            .unstable_features
+            .iter()
+            .filter(|(_, enabled)| **enabled)
+            .map(|(feature, _)| feature.as_str())
+            .collect::<Vec<&str>>()
+            .join(", ");
+
+        self.print_network(&format!(
+            "Capabilities of server {}{}{}:",
+            Weechat::color("chat_server"),
+            self.name(),
+            Weechat::color("reset")
+        ));
+        let fields = [
+            ("Spec versions", versions.versions.join(", ")),
+            ("Unstable features", unstable_features),
+            (
+                "Default room version",
+                capabilities.room_versions.default.to_string(),
+            ),
+            ("Available room versions", room_versions),
+            (
+                "Password changes",
+                yes_no(capabilities.change_password.enabled).to_owned(),
+            ),
+            (
+                "Display name changes",
+                yes_no(capabilities.set_displayname.enabled).to_owned(),
+            ),
+            (
+                "Avatar changes",
+                yes_no(capabilities.set_avatar_url.enabled).to_owned(),
+            ),
+            (
+                "3PID changes",
+                yes_no(capabilities.thirdparty_id_changes.enabled).to_owned(),
+            ),
+        ];
+
+        for (name, value) in &fields {
+            self.print(&format!("{:>24}: {}", name, value));
+        }
+    }
+
+    /// Print the round-trip time of a request to the homeserver.
+    pub async fn ping(&self) {
+        let connection = if let Some(c) = self.connection() {
+            c
+        } else {
+            self.print_error("You must be connected to execute this command");
+            return;
+        };
+
+        match connection.ping().await {
+            Ok(latency) => self.print_network(&format!(
+                "Round-trip time to server {}{}{}: {} ms",
+                Weechat::color("chat_server"),
+                self.name(),
+                Weechat::color("reset"),
+                latency.as_millis()
+            )),
+            Err(e) => {
+                self.print_error(&format!("Error pinging the server {:?}", e))
+            }
+        }
+    }
+
     pub fn autoconnect(&self) -> bool {
         self.settings.borrow().autoconnect
     }
@@ -1189,6 +2315,10 @@ impl InnerServer {
             connection.take();
         }
 
+        *self.reconnecting.borrow_mut() = false;
+        Weechat::bar_item_update("buffer_modes");
+        Weechat::bar_item_update("matrix_modes");
+
         self.print_network(&format!(
             "Disconnected from {}{}{}",
             Weechat::color("chat_server"),
@@ -1231,6 +2361,48 @@ impl InnerServer {
             settings.username,
             indent = 8
         ));
+
+        if self.connected() {
+            let user_id = self
+                .login_state
+                .borrow()
+                .as_ref()
+                .map_or_else(|| "?".to_owned(), |l| l.user_id.to_string());
+            let device_id = self
+                .client
+                .borrow()
+                .as_ref()
+                .and_then(|c| c.device_id().map(|d| d.to_string()))
+                .unwrap_or_else(|| "?".to_owned());
+            let last_sync = self.last_sync.borrow().map_or_else(
+                || "never".to_owned(),
+                |t| {
+                    let local: DateTime<Local> = t.into();
+                    format!(
+                        "{} ({} seconds ago)",
+                        local.format("%Y/%m/%d %H:%M:%S"),
+                        (Utc::now() - t).num_seconds().max(0)
+                    )
+                },
+            );
+
+            s.push_str(&format!(
+                "{:indent$}user id: {}\n\
+                 {:indent$}device id: {}\n\
+                 {:indent$}joined rooms: {}\n\
+                 {:indent$}last sync: {}\n",
+                "",
+                user_id,
+                "",
+                device_id,
+                "",
+                self.rooms.borrow().len(),
+                "",
+                last_sync,
+                indent = 8
+            ));
+        }
+
         s
     }
 }
@@ -1,12 +1,17 @@
-use matrix_sdk::ruma::{
-    events::{
-        room::message::{
-            Relation, RoomMessageEventContent,
-            RoomMessageEventContentWithoutRelation,
+use std::{collections::HashMap, io, path::Path};
+
+use matrix_sdk::{
+    mime::{self, Mime},
+    ruma::{
+        events::{
+            room::message::{
+                Relation, RoomMessageEventContent,
+                RoomMessageEventContentWithoutRelation,
+            },
+            AnyMessageLikeEvent, AnySyncMessageLikeEvent,
         },
-        AnyMessageLikeEvent, AnySyncMessageLikeEvent,
+        EventId, OwnedServerName, ServerName, UserId,
     },
-    EventId, UserId,
 };
 
 pub trait ToTag {
@@ -91,3 +96,172 @@ impl Edit for AnyMessageLikeEvent {
         }
     }
 }
+
+/// Calculate the size of all the files in the given directory, including the
+/// files in subdirectories.
+pub fn directory_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(size)
+}
+
+/// Format a size in bytes into a human readable string.
+pub fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+
+    let mut size = size as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", size, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Format the age of something, given in seconds, into a short human readable
+/// string, e.g. "3h ago".
+pub fn format_age(seconds: u64) -> String {
+    const UNITS: &[(u64, &str)] = &[(86400, "d"), (3600, "h"), (60, "m")];
+
+    UNITS
+        .iter()
+        .find(|(length, _)| seconds >= *length)
+        .map(|(length, unit)| format!("{}{} ago", seconds / length, unit))
+        .unwrap_or_else(|| "just now".to_owned())
+}
+
+/// Guess the mime type of a file from its extension, falling back to a
+/// generic binary type.
+pub fn guess_mime_type(path: &Path) -> Mime {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+
+    let mime = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        "m4a" => "audio/mp4",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "mov" => "video/quicktime",
+        "txt" | "log" => "text/plain",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        _ => return mime::APPLICATION_OCTET_STREAM,
+    };
+
+    mime.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+/// Compute the servers that should be used as `via` servers for a room.
+///
+/// The server of the member with the highest power level comes first, as long
+/// as that member is at least a moderator, the rest are the servers with the
+/// most members. Servers that are IP literals are skipped since they can't be
+/// relied on to stay around.
+pub fn via_servers(
+    members: &[(&UserId, i64)],
+    limit: usize,
+) -> Vec<OwnedServerName> {
+    let members: Vec<_> = members
+        .iter()
+        .filter(|(user_id, _)| !user_id.server_name().is_ip_literal())
+        .collect();
+
+    let mut counts: HashMap<&ServerName, usize> = HashMap::new();
+
+    for (user_id, _) in &members {
+        *counts.entry(user_id.server_name()).or_default() += 1;
+    }
+
+    let mut servers: Vec<&ServerName> = counts.keys().copied().collect();
+    servers.sort_by(|a, b| counts[b].cmp(&counts[a]).then(a.cmp(b)));
+
+    let powerful_server = members
+        .iter()
+        .filter(|(_, level)| *level >= 50)
+        .max_by(|(a, a_level), (b, b_level)| {
+            a_level
+                .cmp(b_level)
+                .then(b.server_name().cmp(a.server_name()))
+        })
+        .map(|(user_id, _)| user_id.server_name());
+
+    powerful_server
+        .into_iter()
+        .chain(servers.into_iter().filter(|s| Some(*s) != powerful_server))
+        .take(limit)
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::user_id;
+
+    use super::*;
+
+    #[test]
+    fn test_via_servers() {
+        let members = [
+            (user_id!("@admin:small.org"), 100),
+            (user_id!("@mod:medium.org"), 50),
+            (user_id!("@alice:big.org"), 0),
+            (user_id!("@bob:big.org"), 0),
+            (user_id!("@carol:big.org"), 0),
+            (user_id!("@dan:medium.org"), 0),
+            (user_id!("@eve:tiny.org"), 0),
+            (user_id!("@mallory:127.0.0.1"), 0),
+            (user_id!("@trent:127.0.0.1"), 0),
+        ];
+
+        let servers = via_servers(&members, 3);
+        let servers: Vec<&str> = servers.iter().map(|s| s.as_str()).collect();
+
+        assert_eq!(servers, ["small.org", "big.org", "medium.org"]);
+
+        let servers = via_servers(&members[2..], 3);
+        let servers: Vec<&str> = servers.iter().map(|s| s.as_str()).collect();
+
+        assert_eq!(servers, ["big.org", "medium.org", "tiny.org"]);
+
+        assert!(via_servers(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(0), "just now");
+        assert_eq!(format_age(59), "just now");
+        assert_eq!(format_age(60), "1m ago");
+        assert_eq!(format_age(3 * 3600 + 1800), "3h ago");
+        assert_eq!(format_age(86400), "1d ago");
+        assert_eq!(format_age(40 * 86400), "40d ago");
+    }
+}
@@ -0,0 +1,355 @@
+//! Incoming verification requests get their own buffer. The request is
+//! answered by typing `accept`, `confirm` or `cancel` into the buffer, the
+//! emojis of the short authentication string get printed there as well.
+
+use std::{cell::RefCell, rc::Rc};
+
+use futures_util::StreamExt;
+use matrix_sdk::{
+    async_trait,
+    encryption::verification::{SasVerification, VerificationRequest},
+    ruma::events::key::verification::VerificationMethod,
+};
+
+use weechat::{
+    buffer::{
+        Buffer, BufferBuilderAsync, BufferHandle, BufferInputCallbackAsync,
+    },
+    Prefix, Task, Weechat,
+};
+
+use crate::connection::Connection;
+
+#[derive(Clone)]
+pub struct VerificationBuffer {
+    connection: Connection,
+    request: VerificationRequest,
+    sas_started: Rc<RefCell<bool>>,
+    buffer: Rc<RefCell<Option<BufferHandle>>>,
+    /// The task following the state of the verification, dropping it stops
+    /// following the verification.
+    follow_task: Rc<RefCell<Option<Task<()>>>>,
+}
+
+impl VerificationBuffer {
+    /// Open a buffer for an incoming verification request and print a prompt
+    /// explaining how the request can be answered.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_name` - The name of the server the request was received on.
+    ///
+    /// * `connection` - The connection of the server.
+    ///
+    /// * `request` - The verification request the other side sent us.
+    pub fn open(
+        server_name: &str,
+        connection: Connection,
+        request: VerificationRequest,
+    ) {
+        let verification = Self {
+            connection,
+            request,
+            sas_started: Rc::new(RefCell::new(false)),
+            buffer: Rc::new(RefCell::new(None)),
+            follow_task: Rc::new(RefCell::new(None)),
+        };
+
+        let buffer_name = format!(
+            "{}.verification.{}",
+            server_name,
+            verification.request.flow_id()
+        );
+
+        let buffer_handle = BufferBuilderAsync::new(&buffer_name)
+            .input_callback(verification.clone())
+            .close_callback({
+                let follow_task = verification.follow_task.clone();
+
+                move |_weechat: &Weechat, _buffer: &Buffer| {
+                    follow_task.borrow_mut().take();
+                    Ok(())
+                }
+            })
+            .build()
+            .expect("Can't create new verification buffer");
+
+        let buffer = buffer_handle
+            .upgrade()
+            .expect("Can't upgrade newly created buffer");
+
+        let other_user = verification.request.other_user_id();
+
+        buffer.set_short_name("verification");
+        buffer.set_title(&format!("Verification with {}", other_user));
+        buffer.set_localvar("type", "private");
+        buffer.set_localvar("server", server_name);
+
+        *verification.buffer.borrow_mut() = Some(buffer_handle);
+
+        if verification.request.is_self_verification() {
+            verification.print_network(
+                "One of your other devices wants to verify this device, \
+                 type accept and compare the emojis that will be shown \
+                 with the ones on your other device",
+            );
+        } else {
+            verification.print_network(&format!(
+                "{} wants to verify this device, type accept and compare \
+                 the emojis that will be shown with the ones on their \
+                 device",
+                other_user
+            ));
+        }
+
+        if verification
+            .request
+            .their_supported_methods()
+            .map_or(false, |m| m.contains(&VerificationMethod::QrCodeShowV1))
+        {
+            verification.print_network(
+                "The other side can verify using a QR code, QR codes can't be \
+                 shown here so the emojis will be used instead",
+            );
+        }
+
+        verification.print_network("Type cancel to refuse the request");
+    }
+
+    fn print_with_prefix(&self, prefix: &str, message: &str) {
+        if let Some(buffer) =
+            self.buffer.borrow().as_ref().and_then(|b| b.upgrade().ok())
+        {
+            buffer.print(&format!("{}{}", prefix, message));
+        }
+    }
+
+    fn print_network(&self, message: &str) {
+        self.print_with_prefix(&Weechat::prefix(Prefix::Network), message);
+    }
+
+    fn print_error(&self, message: &str) {
+        self.print_with_prefix(&Weechat::prefix(Prefix::Error), message);
+    }
+
+    /// Is the buffer of the verification still open.
+    fn is_open(&self) -> bool {
+        self.buffer
+            .borrow()
+            .as_ref()
+            .map_or(false, |b| b.upgrade().is_ok())
+    }
+
+    /// Get the SAS verification of this request, if either side started one.
+    async fn sas(&self) -> Option<SasVerification> {
+        self.connection
+            .sas_verification(
+                self.request.other_user_id().to_owned(),
+                self.request.flow_id().to_owned(),
+            )
+            .await
+    }
+
+    /// Get the SAS verification of this request, starting one ourselves once
+    /// the request is ready and the other side didn't start one.
+    async fn sas_or_start(&self) -> Option<SasVerification> {
+        let sas = self.sas().await;
+
+        if sas.is_some()
+            || !self.request.is_ready()
+            || *self.sas_started.borrow()
+        {
+            return sas;
+        }
+
+        *self.sas_started.borrow_mut() = true;
+
+        match self.connection.start_sas(self.request.clone()).await {
+            Ok(sas) => sas,
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error starting the emoji verification: {}",
+                    e
+                ));
+                None
+            }
+        }
+    }
+
+    /// Follow the verification until it's done, cancelled or the buffer
+    /// gets closed, the emojis are printed once they are known.
+    async fn follow(&self) {
+        // Subscribe before the state is checked so no change gets lost.
+        let mut request_changes = self.request.changes();
+
+        let sas = loop {
+            if !self.is_open() {
+                return;
+            }
+
+            if self.request.is_cancelled() {
+                self.print_cancelled(
+                    self.request.cancel_info().map(|i| i.reason().to_owned()),
+                );
+                return;
+            }
+
+            if let Some(sas) = self.sas_or_start().await {
+                break sas;
+            }
+
+            if request_changes.next().await.is_none() {
+                return;
+            }
+        };
+
+        let mut sas_changes = sas.changes();
+        let mut emojis_printed = false;
+
+        while self.is_open() {
+            if sas.is_done() {
+                self.print_network(&format!(
+                    "The device {} of {} has been verified",
+                    sas.other_device().device_id(),
+                    sas.other_user_id()
+                ));
+                return;
+            } else if sas.is_cancelled() {
+                self.print_cancelled(
+                    sas.cancel_info().map(|i| i.reason().to_owned()),
+                );
+                return;
+            } else if !emojis_printed && sas.can_be_presented() {
+                if let Some(emojis) = sas.emoji() {
+                    let emojis: Vec<String> = emojis
+                        .iter()
+                        .map(|e| format!("{} ({})", e.symbol, e.description))
+                        .collect();
+
+                    self.print_network(&format!(
+                        "Compare the emojis: {}",
+                        emojis.join("  ")
+                    ));
+                    self.print_network(
+                        "Type confirm if they match the ones on the other \
+                         device, cancel if they don't",
+                    );
+                    emojis_printed = true;
+                }
+            }
+
+            if sas_changes.next().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Start following the verification, see `follow()`.
+    fn spawn_follow(&self) {
+        let verification = self.clone();
+        let task = Weechat::spawn(async move { verification.follow().await });
+
+        *self.follow_task.borrow_mut() = Some(task);
+    }
+
+    fn print_cancelled(&self, reason: Option<String>) {
+        match reason {
+            Some(reason) => self.print_network(&format!(
+                "The verification has been cancelled: {}",
+                reason
+            )),
+            None => self.print_network("The verification has been cancelled"),
+        }
+    }
+
+    async fn accept(&self) {
+        if self.request.is_done() || self.request.is_cancelled() {
+            self.print_error("The verification request is already finished");
+            return;
+        }
+
+        match self
+            .connection
+            .accept_verification(self.request.clone())
+            .await
+        {
+            Ok(_) => {
+                self.print_network(
+                    "Accepted the verification request, waiting for the \
+                     emojis",
+                );
+
+                self.spawn_follow();
+            }
+            Err(e) => self.print_error(&format!(
+                "Error accepting the verification request: {}",
+                e
+            )),
+        }
+    }
+
+    async fn confirm(&self) {
+        let sas = match self.sas().await {
+            Some(sas) if sas.can_be_presented() => sas,
+            _ => {
+                self.print_error("There are no emojis to confirm yet");
+                return;
+            }
+        };
+
+        match self.connection.confirm_sas(sas).await {
+            Ok(_) => self.print_network(
+                "Confirmed the emojis, waiting for the other side to confirm \
+                 them as well",
+            ),
+            Err(e) => self.print_error(&format!(
+                "Error confirming the verification: {}",
+                e
+            )),
+        }
+    }
+
+    async fn cancel(&self) {
+        // Stop following the verification, we print the cancellation
+        // ourselves.
+        let following = self.follow_task.borrow_mut().take().is_some();
+
+        let result = match self.sas().await {
+            Some(sas) => self.connection.cancel_sas(sas).await,
+            None => {
+                self.connection
+                    .cancel_verification(self.request.clone())
+                    .await
+            }
+        };
+
+        match result {
+            Ok(_) => self.print_cancelled(None),
+            Err(e) => {
+                self.print_error(&format!(
+                    "Error cancelling the verification: {}",
+                    e
+                ));
+
+                if following {
+                    self.spawn_follow();
+                }
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BufferInputCallbackAsync for VerificationBuffer {
+    async fn callback(&mut self, _: BufferHandle, input: String) {
+        match input.trim() {
+            "accept" => self.accept().await,
+            "confirm" => self.confirm().await,
+            "cancel" => self.cancel().await,
+            _ => self.print_error(
+                "Unknown command, type accept, confirm or cancel to answer \
+                 the verification",
+            ),
+        }
+    }
+}